@@ -0,0 +1,357 @@
+use crate::complex::Complex;
+/**
+ * Additive synthesis rendered in the frequency domain. Rather than summing one sine per partial for
+ * every sample, each block places every partial directly into a spectrum as the transform of a
+ * hann windowed sinusoid, inverse transforms it with do_fft and overlap-adds the result. Hann
+ * windows at half a frame apart sum to exactly one so the blocks join without seams.
+ */
+use crate::envelope::Envelope;
+use crate::fft::do_fft;
+use std::f64::consts::PI;
+
+/// The number of samples in each synthesized frame. Must be a power of two for do_fft.
+const FRAME_SIZE: usize = 1024;
+
+/// The distance between the start of each frame.
+const HOP_SIZE: usize = FRAME_SIZE / 2;
+
+/// The number of bins either side of a partial's peak that are filled in. The side lobes of the
+/// hann window have fallen by more than 80db this far from the peak.
+const KERNEL_BINS: isize = 8;
+
+/// One sine partial of an additive voice.
+#[derive(Debug)]
+pub struct Partial {
+    // The partial frequency as a multiple of the voice frequency.
+    pub harmonic: f32,
+
+    // The peak amplitude of the partial.
+    pub amplitude: f32,
+
+    // The starting phase of the partial, in cycles.
+    pub phase: f32,
+
+    // An optional contour applied to the amplitude of this partial alone.
+    pub envelope: Option<Envelope>,
+}
+
+impl Partial {
+    pub fn new(harmonic: f32, amplitude: f32, phase: f32) -> Self {
+        Partial {
+            harmonic,
+            amplitude,
+            phase,
+            envelope: None,
+        }
+    }
+
+    pub fn with_envelope(self, envelope: Envelope) -> Self {
+        Partial {
+            envelope: Some(envelope),
+            ..self
+        }
+    }
+}
+
+/// The sum of e^(i 2pi u n / N) over a frame. This is the transform of a complex exponential that
+/// sits `u` bins away from bin zero.
+fn dirichlet(u: f64) -> Complex<f64> {
+    let size = FRAME_SIZE as f64;
+    let denominator = (PI * u / size).sin();
+
+    // At whole multiples of the frame size every term is one.
+    if denominator.abs() < 1e-12 {
+        return Complex::real(size);
+    }
+
+    let magnitude = (PI * u).sin() / denominator;
+    let angle = PI * u * (size - 1.) / size;
+    Complex::complex(magnitude * angle.cos(), magnitude * angle.sin())
+}
+
+/// The transform, at bin `k`, of a complex exponential at fractional bin `bin` multiplied by a
+/// hann window.
+fn hann_kernel(bin: f64, k: f64) -> Complex<f64> {
+    let half = Complex::real(0.5);
+    let quarter = Complex::real(0.25);
+    half * dirichlet(bin + k)
+        - quarter * dirichlet(bin + k + 1.)
+        - quarter * dirichlet(bin + k - 1.)
+}
+
+/// An additive voice defined by a list of partials, rendered a frame at a time through an inverse
+/// fft.
+#[derive(Debug)]
+pub struct Additive {
+    partials: Vec<Partial>,
+
+    // The phase (in cycles) of each partial at the start of the next frame.
+    phases: Vec<f64>,
+
+    rate: f32,
+    frequency: f32,
+
+    // Scratch space the next frame is built in.
+    spectrum: Vec<Complex<f64>>,
+
+    // The overlap-added samples ready to be played and the second half of the last frame, which is
+    // added to the first half of the next.
+    ready: Vec<f32>,
+    tail: Vec<f32>,
+    position: usize,
+
+    // Whether the first frame has been rendered.
+    started: bool,
+}
+
+impl Additive {
+    pub fn new(partials: Vec<Partial>, rate: f32, frequency: f32) -> Self {
+        let phases = partials.iter().map(|x| x.phase as f64).collect();
+        Additive {
+            partials,
+            phases,
+            rate,
+            frequency,
+            spectrum: vec![Complex::real(0.); FRAME_SIZE],
+            ready: vec![0.; HOP_SIZE],
+            tail: vec![0.; HOP_SIZE],
+            position: HOP_SIZE,
+            started: false,
+        }
+    }
+
+    /// A voice of harmonics with the given amplitudes, starting with the fundamental.
+    pub fn from_amplitudes(amplitudes: &[f32], rate: f32, frequency: f32) -> Self {
+        let partials = amplitudes
+            .iter()
+            .enumerate()
+            .map(|(h, amplitude)| Partial::new((h + 1) as f32, *amplitude, 0.))
+            .collect();
+        Self::new(partials, rate, frequency)
+    }
+
+    /// Add another partial to the voice.
+    pub fn with_partial(mut self, partial: Partial) -> Self {
+        self.phases.push(partial.phase as f64);
+        self.partials.push(partial);
+        self
+    }
+
+    pub fn partials(&self) -> &[Partial] {
+        &self.partials
+    }
+
+    pub fn frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    /// Change the voice frequency. The change takes effect from the next frame.
+    pub fn set_frequency(&mut self, frequency: f32) {
+        self.frequency = frequency;
+    }
+
+    /// Move each partial's phase back by one hop.
+    fn rewind_phases(&mut self) {
+        for (partial, phase) in self.partials.iter().zip(self.phases.iter_mut()) {
+            let frequency = (self.frequency * partial.harmonic) as f64;
+            *phase -= frequency * HOP_SIZE as f64 / self.rate as f64;
+        }
+    }
+
+    /// Synthesize the next frame and overlap-add it with the tail of the last one.
+    fn render_frame(&mut self) {
+        for bin in self.spectrum.iter_mut() {
+            *bin = Complex::real(0.);
+        }
+
+        let nyquist = self.rate / 2.;
+
+        for (partial, phase) in self.partials.iter_mut().zip(self.phases.iter_mut()) {
+            let frequency = self.frequency * partial.harmonic;
+
+            // Each frame takes the level of the envelope at its centre, where its window peaks, and
+            // then moves the envelope on to the centre of the next frame.
+            let mut amplitude = partial.amplitude as f64;
+            if let Some(envelope) = &mut partial.envelope {
+                amplitude *= envelope.next() as f64;
+                for _ in 1..HOP_SIZE {
+                    envelope.next();
+                }
+            }
+
+            if frequency > 0. && frequency < nyquist && amplitude != 0. {
+                let bin = frequency as f64 * FRAME_SIZE as f64 / self.rate as f64;
+
+                // A sine is the sum of two complex exponentials at +bin and -bin. The angle starts a
+                // quarter cycle back so a phase of zero is a sine rather than a cosine.
+                let angle = 2. * PI * *phase - PI / 2.;
+                let positive =
+                    Complex::complex(angle.cos(), angle.sin()) * Complex::real(amplitude / 2.);
+                let negative =
+                    Complex::complex(angle.cos(), -angle.sin()) * Complex::real(amplitude / 2.);
+
+                for (bin, weight) in [(bin, positive), (-bin, negative)] {
+                    // The exponential at `bin` lands at bin -`bin` of the forward transform.
+                    let centre = -bin.round() as isize;
+                    for k in centre - KERNEL_BINS..=centre + KERNEL_BINS {
+                        let index = k.rem_euclid(FRAME_SIZE as isize) as usize;
+                        self.spectrum[index] =
+                            self.spectrum[index] + weight * hann_kernel(bin, k as f64);
+                    }
+                }
+            }
+
+            *phase = (*phase + frequency as f64 * HOP_SIZE as f64 / self.rate as f64).fract();
+        }
+
+        do_fft(&mut self.spectrum, true).expect("FRAME_SIZE is a power of two");
+
+        for i in 0..HOP_SIZE {
+            self.ready[i] = self.tail[i] + self.spectrum[i].real as f32;
+            self.tail[i] = self.spectrum[i + HOP_SIZE].real as f32;
+        }
+
+        self.position = 0;
+    }
+
+    pub fn next(&mut self) -> f32 {
+        // The voice starts with a frame centred on its first sample, so it starts at full level
+        // rather than fading in over the first window. It is rendered here rather than when the
+        // voice is made so nothing moves until the voice is played.
+        if !self.started {
+            self.started = true;
+            self.rewind_phases();
+            self.render_frame();
+            self.position = HOP_SIZE;
+        }
+
+        if self.position == HOP_SIZE {
+            self.render_frame();
+        }
+
+        let value = self.ready[self.position];
+        self.position += 1;
+        value
+    }
+
+    /// Release the envelope of every partial that has one.
+    pub fn release(&mut self) {
+        for partial in self.partials.iter_mut() {
+            if let Some(envelope) = &mut partial.envelope {
+                envelope.release();
+            }
+        }
+    }
+
+    /// The voice is finished when every partial has an envelope and all of them have finished.
+    pub fn finished(&self) -> bool {
+        self.partials.iter().all(|partial| match &partial.envelope {
+            Some(envelope) => envelope.finished(),
+            None => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod additive_tests {
+    use super::{Additive, Partial};
+    use crate::envelope::Envelope;
+    use crate::fft::RealFft;
+    use crate::sample::Sample;
+
+    const RATE: f32 = 44100.;
+
+    #[test]
+    fn matches_direct_sum() {
+        let mut additive = Additive::new(
+            vec![Partial::new(1., 0.5, 0.), Partial::new(2.5, 0.25, 0.25)],
+            RATE,
+            440.,
+        );
+
+        for i in 0..10000 {
+            let time = i as f32 / RATE;
+            let expected = 0.5 * (2. * std::f32::consts::PI * 440. * time).sin()
+                + 0.25 * (2. * std::f32::consts::PI * (1100. * time + 0.25)).sin();
+            let value = additive.next();
+            assert!(
+                (value - expected).abs() < 1e-3,
+                "{}: {} {}",
+                i,
+                value,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn spectrum_shows_configured_partials() {
+        let mut additive = Additive::from_amplitudes(&[0.5, 0., 0.25], RATE, 1000.);
+        let data: Vec<f64> = (0..8192).map(|_| additive.next() as f64).collect();
+
+        let mut fft = RealFft::new(16384, RATE as f64).unwrap();
+        let spectrum = fft.run(&data).unwrap();
+
+        let amplitude_at = |frequency: f64| {
+            spectrum
+                .iter()
+                .filter(|(f, _)| (f - frequency).abs() < 10.)
+                .map(|(_, a)| *a)
+                .fold(0., f64::max)
+        };
+
+        let fundamental = amplitude_at(1000.);
+        assert!((amplitude_at(3000.) / fundamental - 0.5).abs() < 0.05);
+        assert!(amplitude_at(2000.) / fundamental < 0.01);
+        assert!(amplitude_at(4000.) / fundamental < 0.01);
+    }
+
+    #[test]
+    fn partial_envelopes_finish() {
+        let mut additive = Additive::new(
+            vec![Partial::new(1., 1., 0.)
+                .with_envelope(Envelope::new(RATE, 0.01, 1., 0.01, 0.01, 0.5, 0.01))],
+            RATE,
+            440.,
+        );
+
+        assert!(!additive.finished());
+        for _ in 0..RATE as usize {
+            additive.next();
+        }
+        assert!(additive.finished());
+        assert!(additive.next().abs() < 1e-6);
+    }
+
+    #[test]
+    fn partial_envelopes_start_with_the_voice() {
+        // The partial fades in over a tenth of a second, so it is still close to silent a little
+        // way into the first frame.
+        let mut additive = Additive::from_amplitudes(&[], RATE, 440.).with_partial(
+            Partial::new(1., 1., 0.).with_envelope(Envelope::new(RATE, 0.1, 1., 0., 10., 1., 0.1)),
+        );
+        let start = (0..64).fold(0f32, |peak, _| peak.max(additive.next().abs()));
+        assert!(start < 0.02, "{}", start);
+    }
+
+    #[test]
+    fn plays_as_a_sample() {
+        let mut additive = Additive::from_amplitudes(&[0.5, 0.25], RATE, 440.);
+        let mut sample = Sample::Additive(Additive::from_amplitudes(&[0.5, 0.25], RATE, 440.));
+        for _ in 0..1500 {
+            assert_eq!(sample.next(), additive.next());
+        }
+
+        assert_eq!(sample.frequency(), 440.);
+        assert_eq!(sample.frequency(), additive.frequency());
+        assert_eq!(additive.partials().len(), 2);
+    }
+
+    #[test]
+    fn partials_above_nyquist_are_dropped() {
+        let mut additive = Additive::from_amplitudes(&[0., 0., 0., 0., 1.], RATE, 5000.);
+        for _ in 0..4096 {
+            assert!(additive.next().abs() < 1e-6);
+        }
+    }
+}
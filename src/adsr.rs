@@ -1,59 +1,21 @@
+use crate::envelope::{Curve, Envelope};
 use crate::sample::Sample;
+use crate::source::{Parameter, Source};
 
-/// This enum keeps track of what stage in the Adsr state machine a given adsr envelope is currently
-/// in.
+/// An Adsr envelope for synthesized sounds. This pairs an `Envelope` with the source it shapes, multiplying
+/// each sample by the level of the envelope. Any source can be shaped, including a whole mixer.
 #[derive(Debug)]
-enum AdsrState {
-    Attack,
-    Decay,
-    Sustain,
-    Release,
-    Finished,
-}
-
-use AdsrState::*;
-
-/// An Adsr envelope for synthesized sounds. This structure models linear ramp up of a sound to it's peak, then
-/// a linear decrease to a sustain level. The envelope will hold the sample amplitude at the sustain level for a
-/// fixed amount of time. Once the sustain period has elapsed the sound will linearly decrease from the sustain level
-/// to zero amplitude.
-#[derive(Debug)]
-pub struct Adsr {
-    // The current state of the adsr envelope
-    current_state: AdsrState,
-
-    // The time spent in the current state
-    time_in_state: f32,
-
-    // How quickly (in seconds) should the sound hit it's peak.
-    attack: f32,
-
-    // What should the amplitude of the sample be multiplied by at the peak.
-    peak_scalar: f32,
-
-    // How quickly (in seconds) should the sound go from it's peak to it's sustain level.
-    decay: f32,
-
-    // How long (in seconds) should the sound stay at it's sustain level
-    sustain: f32,
-
-    // What should the amplitude of the sample be multiplied by at it's sustain level
-    sustain_scalar: f32,
-
-    // How long (in seconds) should the sound take to go from it's sustain amplitude to zero
-    // amplitude.
-    release: f32,
+pub struct Adsr<S: Source = Sample> {
+    // The amplitude contour applied to the sample
+    envelope: Envelope,
 
     // The sample to modify
-    sample: Sample,
-
-    // The sample rate of the output stream
-    sample_rate: f32,
+    sample: S,
 }
 
-impl Adsr {
+impl<S: Source> Adsr<S> {
     pub fn new(
-        sample: Sample,
+        sample: S,
         sample_rate: f32,
         attack: f32,
         peak_scalar: f32,
@@ -63,76 +25,101 @@ impl Adsr {
         release: f32,
     ) -> Self {
         Adsr {
-            current_state: AdsrState::Attack,
-            time_in_state: 0.,
+            envelope: Envelope::new(
+                sample_rate,
+                attack,
+                peak_scalar,
+                decay,
+                sustain,
+                sustain_scalar,
+                release,
+            ),
             sample,
-            sample_rate,
-            attack,
-            peak_scalar,
-            decay,
-            sustain,
-            sustain_scalar,
-            release,
         }
     }
 
-    /// Step the adsr envelope forward by one sample, update the state and return the amplitude
-    /// of the envelope at the adjusted time.
-    fn step_state(
-        &mut self,
-        clock: f32,
-        start_scalar: f32,
-        end_scalar: f32,
-        max_time: f32,
-        next_state: AdsrState,
-    ) -> f32 {
-        let sampled = self.sample.next(clock);
-        self.time_in_state += 1. / self.sample_rate;
-        if self.time_in_state > max_time {
-            self.current_state = next_state;
-            sampled * end_scalar
-        } else {
-            let low_sample = start_scalar * sampled;
-            let high_sample = end_scalar * sampled;
-
-            low_sample + ((high_sample - low_sample) * (self.time_in_state / max_time))
+    /// An envelope that holds its sample at the sustain level until it is released.
+    pub fn gated(
+        sample: S,
+        sample_rate: f32,
+        attack: f32,
+        peak_scalar: f32,
+        decay: f32,
+        sustain_scalar: f32,
+        release: f32,
+    ) -> Self {
+        Adsr {
+            envelope: Envelope::gated(
+                sample_rate,
+                attack,
+                peak_scalar,
+                decay,
+                sustain_scalar,
+                release,
+            ),
+            sample,
         }
     }
 
-    /// Return the amplitude of the next sample for this adsr envelope.
-    pub fn next(&mut self, clock: f32) -> f32 {
-        match self.current_state {
-            Attack => self.step_state(clock, 0., self.peak_scalar, self.attack, AdsrState::Decay),
-            Decay => self.step_state(
-                clock,
-                self.peak_scalar,
-                self.sustain_scalar,
-                self.decay,
-                AdsrState::Sustain,
-            ),
-            Sustain => self.step_state(
-                clock,
-                self.sustain_scalar,
-                self.sustain_scalar,
-                self.sustain,
-                AdsrState::Release,
-            ),
-            Release => self.step_state(
-                clock,
-                self.sustain_scalar,
-                0.,
-                self.release,
-                AdsrState::Finished,
-            ),
-            Finished => 0.,
+    /// Shape a sample with any envelope, such as one built from breakpoints.
+    pub fn with_envelope(sample: S, envelope: Envelope) -> Self {
+        Adsr { envelope, sample }
+    }
+
+    /// Set the shape of the attack, decay and release segments, which are linear by default.
+    pub fn with_curves(self, attack: Curve, decay: Curve, release: Curve) -> Self {
+        Adsr {
+            envelope: self.envelope.with_curves(attack, decay, release),
+            ..self
         }
     }
 
-    /// Returns true when this envelope is finished, at which point next will return zero forever.
+    /// Return the amplitude of the next sample for this adsr envelope.
+    pub fn next(&mut self) -> f32 {
+        let sampled = self.sample.next();
+        sampled * self.envelope.next()
+    }
+
+    /// Fill a buffer with the next samples of the source shaped by the envelope.
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        self.sample.process(buffer);
+        self.envelope.apply(buffer);
+    }
+
+    /// Returns true when this envelope is finished, or the sample it shapes has nothing left to play,
+    /// at which point next will return zero forever.
     pub fn finished(&self) -> bool {
-        match self.current_state {
-            Attack | Decay | Sustain | Release => false,
-            Finished => true,
-        }
+        self.envelope.finished() || self.sample.finished()
+    }
+}
+
+impl<S: Source> Source for Adsr<S> {
+    fn next(&mut self) -> f32 {
+        Adsr::next(self)
+    }
+
+    fn finished(&self) -> bool {
+        Adsr::finished(self)
+    }
+
+    fn process(&mut self, buffer: &mut [f32]) {
+        Adsr::process(self, buffer)
+    }
+
+    fn release(&mut self) {
+        self.envelope.release();
+        self.sample.release();
+    }
+
+    fn parameter(&self, parameter: Parameter) -> Option<f32> {
+        self.sample.parameter(parameter)
+    }
+
+    fn set_parameter(&mut self, parameter: Parameter, value: f32) {
+        self.sample.set_parameter(parameter, value)
+    }
+
+    fn pan(&self) -> f32 {
+        self.sample.pan()
     }
 }
@@ -0,0 +1,587 @@
+/**
+ * Second order filters designed with the formulas from Robert Bristow-Johnson's audio eq cookbook.
+ * Stages can be cascaded for steeper slopes, with low and high passes cascaded as Butterworth
+ * filters so they stay flat up to the cutoff.
+ *
+ * Changing a parameter moves the coefficients towards their new values over a few milliseconds
+ * rather than all at once, so modulating a filter doesn't click. Every step of the way is a blend
+ * of stable coefficients, and blends of stable coefficients are stable too.
+ */
+use crate::filter::Filter;
+use std::error::Error;
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+use std::str::FromStr;
+
+/// The Q of a single stage low or high pass with no resonance.
+pub const BUTTERWORTH_Q: f32 = FRAC_1_SQRT_2;
+
+/// The highest order a cascade can have.
+pub const MAX_ORDER: usize = 8;
+
+/// How long the coefficients take to get most of the way to new settings by default, in seconds.
+const SMOOTHING: f32 = 0.005;
+
+/// How close the coefficients have to get to their targets before they snap to them. They also
+/// snap once they are too close for a step to change them.
+const SETTLED: f32 = 1e-6;
+
+/// The shape of a filter's frequency response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Response {
+    LowPass,
+    HighPass,
+
+    // Passes a band around the frequency at unity gain, narrower as the Q rises.
+    BandPass,
+
+    // Removes a band around the frequency.
+    Notch,
+
+    // Passes every frequency at unity gain, shifting the phase around the frequency.
+    AllPass,
+
+    // Boosts or cuts a band around the frequency by the gain.
+    Peak,
+
+    // Boosts or cuts everything below or above the frequency by the gain.
+    LowShelf,
+    HighShelf,
+}
+
+impl FromStr for Response {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "low-pass" => Ok(Response::LowPass),
+            "high-pass" => Ok(Response::HighPass),
+            "band-pass" => Ok(Response::BandPass),
+            "notch" => Ok(Response::Notch),
+            "all-pass" => Ok(Response::AllPass),
+            "peak" => Ok(Response::Peak),
+            "low-shelf" => Ok(Response::LowShelf),
+            "high-shelf" => Ok(Response::HighShelf),
+            _ => Err(format!(
+                "unknown filter response {}, expected low-pass, high-pass, band-pass, notch, \
+                 all-pass, peak, low-shelf or high-shelf",
+                name
+            )),
+        }
+    }
+}
+
+/// The coefficients of a biquad, normalised so the first feedback coefficient is one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Coefficients {
+    /// Design a stage with a response at a frequency in hz, with a Q and a gain in decibels for
+    /// the responses that use one.
+    pub fn new(response: Response, sample_rate: f32, frequency: f32, q: f32, gain: f32) -> Self {
+        let w0 = 2. * PI * frequency / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2. * q);
+        let a = 10f32.powf(gain / 40.);
+        let shelf = 2. * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match response {
+            Response::LowPass => (
+                (1. - cos) / 2.,
+                1. - cos,
+                (1. - cos) / 2.,
+                1. + alpha,
+                -2. * cos,
+                1. - alpha,
+            ),
+            Response::HighPass => (
+                (1. + cos) / 2.,
+                -(1. + cos),
+                (1. + cos) / 2.,
+                1. + alpha,
+                -2. * cos,
+                1. - alpha,
+            ),
+            Response::BandPass => (alpha, 0., -alpha, 1. + alpha, -2. * cos, 1. - alpha),
+            Response::Notch => (1., -2. * cos, 1., 1. + alpha, -2. * cos, 1. - alpha),
+            Response::AllPass => (
+                1. - alpha,
+                -2. * cos,
+                1. + alpha,
+                1. + alpha,
+                -2. * cos,
+                1. - alpha,
+            ),
+            Response::Peak => (
+                1. + alpha * a,
+                -2. * cos,
+                1. - alpha * a,
+                1. + alpha / a,
+                -2. * cos,
+                1. - alpha / a,
+            ),
+            Response::LowShelf => (
+                a * ((a + 1.) - (a - 1.) * cos + shelf),
+                2. * a * ((a - 1.) - (a + 1.) * cos),
+                a * ((a + 1.) - (a - 1.) * cos - shelf),
+                (a + 1.) + (a - 1.) * cos + shelf,
+                -2. * ((a - 1.) + (a + 1.) * cos),
+                (a + 1.) + (a - 1.) * cos - shelf,
+            ),
+            Response::HighShelf => (
+                a * ((a + 1.) + (a - 1.) * cos + shelf),
+                -2. * a * ((a - 1.) + (a + 1.) * cos),
+                a * ((a + 1.) + (a - 1.) * cos - shelf),
+                (a + 1.) - (a - 1.) * cos + shelf,
+                2. * ((a - 1.) - (a + 1.) * cos),
+                (a + 1.) - (a - 1.) * cos - shelf,
+            ),
+        };
+
+        Coefficients {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    /// Move a fraction of the way towards a target, snapping to it once close enough. Returns
+    /// true once the target is reached.
+    fn approach(&mut self, target: &Coefficients, amount: f32) -> bool {
+        let mut settled = true;
+        for (value, target) in [
+            (&mut self.b0, target.b0),
+            (&mut self.b1, target.b1),
+            (&mut self.b2, target.b2),
+            (&mut self.a1, target.a1),
+            (&mut self.a2, target.a2),
+        ] {
+            let next = *value + (target - *value) * amount;
+            if next == *value || (target - next).abs() < SETTLED {
+                *value = target;
+            } else {
+                *value = next;
+                settled = false;
+            }
+        }
+        settled
+    }
+}
+
+/// One biquad in a cascade, in transposed direct form II.
+#[derive(Debug)]
+struct Stage {
+    // The Q of this stage as a multiple of the Q of the filter.
+    q_scale: f32,
+
+    target: Coefficients,
+    current: Coefficients,
+    state: [f32; 2],
+}
+
+impl Stage {
+    fn next(&mut self, input: f32) -> f32 {
+        let c = &self.current;
+        let output = c.b0 * input + self.state[0];
+        self.state[0] = c.b1 * input - c.a1 * output + self.state[1];
+        self.state[1] = c.b2 * input - c.a2 * output;
+        output
+    }
+}
+
+/// A cascade of identical biquads, or of a Butterworth filter's stages for low and high passes.
+///
+/// Parameters: 0 is the frequency in hz, 1 the Q and 2 the gain in decibels of the peak and shelf
+/// responses.
+#[derive(Debug)]
+pub struct Biquad {
+    response: Response,
+    sample_rate: f32,
+    frequency: f32,
+    q: f32,
+    gain: f32,
+    stages: Vec<Stage>,
+
+    // The fraction of the way the coefficients move towards their targets each sample, and
+    // whether they are there yet.
+    smoothing: f32,
+    settled: bool,
+}
+
+impl Biquad {
+    /// A single stage filter. Low and high passes with a Q of `BUTTERWORTH_Q` have no resonance.
+    pub fn new(
+        response: Response,
+        sample_rate: f32,
+        frequency: f32,
+        q: f32,
+    ) -> Result<Self, Box<dyn Error>> {
+        if !(frequency > 0. && frequency < sample_rate / 2.) {
+            return Err("a filter frequency must be between zero and half the sample rate".into());
+        }
+        if q.is_nan() || q <= 0. {
+            return Err("a filter Q must be positive".into());
+        }
+
+        let mut biquad = Biquad {
+            response,
+            sample_rate,
+            frequency,
+            q,
+            gain: 0.,
+            stages: Vec::new(),
+            smoothing: 1.,
+            settled: true,
+        }
+        .with_stages(vec![1.]);
+        biquad.set_smoothing(SMOOTHING);
+        Ok(biquad)
+    }
+
+    /// Set the gain in decibels of the peak and shelf responses, which is zero by default.
+    pub fn with_gain(mut self, gain: f32) -> Self {
+        self.gain = gain;
+        self.update();
+        self.snap();
+        self
+    }
+
+    /// Cascade stages to make a filter of an even order, falling off 6db per octave for each
+    /// order. Low and high passes become Butterworth filters, with the extra Q over
+    /// `BUTTERWORTH_Q` adding resonance, and other responses repeat the same stage.
+    pub fn with_order(self, order: usize) -> Result<Self, Box<dyn Error>> {
+        if order == 0 || !order.is_multiple_of(2) || order > MAX_ORDER {
+            return Err(format!("a filter order must be even and at most {}", MAX_ORDER).into());
+        }
+
+        let stages = order / 2;
+        let q_scales = (0..stages)
+            .map(|stage| match self.response {
+                Response::LowPass | Response::HighPass => {
+                    let angle = (2 * stage + 1) as f32 * PI / (2 * order) as f32;
+                    1. / (2. * angle.sin()) / BUTTERWORTH_Q
+                }
+                _ => 1.,
+            })
+            .collect();
+        Ok(self.with_stages(q_scales))
+    }
+
+    /// How long changes take to get about two thirds of the way to their new coefficients, in
+    /// seconds. Zero applies changes straight away.
+    pub fn with_smoothing(mut self, time: f32) -> Self {
+        self.set_smoothing(time);
+        self
+    }
+
+    fn set_smoothing(&mut self, time: f32) {
+        self.smoothing = if time > 0. {
+            1. - (-1. / (time * self.sample_rate)).exp()
+        } else {
+            1.
+        };
+    }
+
+    fn with_stages(mut self, q_scales: Vec<f32>) -> Self {
+        self.stages = q_scales
+            .into_iter()
+            .map(|q_scale| {
+                let coefficients = Coefficients::new(
+                    self.response,
+                    self.sample_rate,
+                    self.frequency,
+                    self.q * q_scale,
+                    self.gain,
+                );
+                Stage {
+                    q_scale,
+                    target: coefficients,
+                    current: coefficients,
+                    state: [0.; 2],
+                }
+            })
+            .collect();
+        self
+    }
+
+    /// Redesign the stages for the current settings.
+    fn update(&mut self) {
+        for stage in self.stages.iter_mut() {
+            stage.target = Coefficients::new(
+                self.response,
+                self.sample_rate,
+                self.frequency,
+                self.q * stage.q_scale,
+                self.gain,
+            );
+        }
+        self.settled = false;
+    }
+
+    /// Jump straight to the target coefficients.
+    fn snap(&mut self) {
+        for stage in self.stages.iter_mut() {
+            stage.current = stage.target;
+        }
+        self.settled = true;
+    }
+}
+
+impl Filter for Biquad {
+    fn next(&mut self, input: f32) -> f32 {
+        if !self.settled {
+            let mut settled = true;
+            for stage in self.stages.iter_mut() {
+                settled &= stage.current.approach(&stage.target, self.smoothing);
+            }
+            self.settled = settled;
+        }
+
+        self.stages
+            .iter_mut()
+            .fold(input, |value, stage| stage.next(value))
+    }
+
+    fn parameter(&self, index: usize) -> Option<f32> {
+        match index {
+            0 => Some(self.frequency),
+            1 => Some(self.q),
+            2 => Some(self.gain),
+            _ => None,
+        }
+    }
+
+    /// Settings out of range, as modulation can ask for, are clamped to the nearest that work.
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match index {
+            0 => self.frequency = value.max(1.).min(0.49 * self.sample_rate),
+            1 => self.q = value.max(0.01),
+            2 => self.gain = value,
+            _ => return,
+        }
+        self.update();
+    }
+}
+
+#[cfg(test)]
+mod biquad_tests {
+    use super::{Biquad, Coefficients, Response, BUTTERWORTH_Q};
+    use crate::fft::RealFft;
+    use crate::filter::Filter;
+    use std::f32::consts::PI;
+
+    const RATE: f32 = 44100.;
+
+    /// The gain of a filter at a frequency in decibels, measured from the spectra of a sine and a
+    /// cosine wave once the filter has settled. The spectrum adds the positive and negative
+    /// frequencies of each wave, which depends on its phase, so the two are combined to measure
+    /// the amplitude whatever the phase shift of the filter.
+    fn measured(filter: impl Fn() -> Biquad, frequency: f32) -> f32 {
+        let mut fft = RealFft::new(16384, RATE as f64).unwrap();
+        let mut spectrum = |data: &[f64]| -> Vec<(f64, f64)> { fft.run(data).unwrap().to_vec() };
+
+        let (mut dry, mut wet) = (Vec::new(), Vec::new());
+        for phase in [0., std::f64::consts::FRAC_PI_2] {
+            let wave = |i: usize| {
+                let time = i as f64 / RATE as f64;
+                (2. * std::f64::consts::PI * frequency as f64 * time + phase).sin()
+            };
+            let mut filter = filter();
+            for i in 0..4096 {
+                filter.next(wave(i) as f32);
+            }
+            let input: Vec<f64> = (4096..4096 + 8192).map(wave).collect();
+            let output: Vec<f64> = input
+                .iter()
+                .map(|x| filter.next(*x as f32) as f64)
+                .collect();
+            dry.push(spectrum(&input));
+            wet.push(spectrum(&output));
+        }
+
+        let peak = |spectra: &[Vec<(f64, f64)>]| {
+            spectra[0]
+                .iter()
+                .zip(spectra[1].iter())
+                .filter(|((f, _), _)| (f - frequency as f64).abs() < 20.)
+                .map(|((_, sine), (_, cosine))| sine.hypot(*cosine))
+                .fold(0., f64::max)
+        };
+        (20. * (peak(&wet) / peak(&dry)).log10()) as f32
+    }
+
+    /// The gain of a stage at a frequency in hz, as a multiple of the input amplitude.
+    fn stage_magnitude(coefficients: &Coefficients, frequency: f32) -> f32 {
+        // Evaluate the transfer function at z = e^jw, with each polynomial in z^-1 summed as a
+        // complex number.
+        let w = 2. * std::f64::consts::PI * frequency as f64 / RATE as f64;
+        let polynomial = |c0: f32, c1: f32, c2: f32| {
+            let re = c0 as f64 + c1 as f64 * w.cos() + c2 as f64 * (2. * w).cos();
+            let im = -c1 as f64 * w.sin() - c2 as f64 * (2. * w).sin();
+            (re * re + im * im).sqrt()
+        };
+
+        let c = coefficients;
+        (polynomial(c.b0, c.b1, c.b2) / polynomial(1., c.a1, c.a2)) as f32
+    }
+
+    /// The gain of a whole cascade at a frequency in hz, once its coefficients have settled.
+    fn magnitude(filter: &Biquad, frequency: f32) -> f32 {
+        filter
+            .stages
+            .iter()
+            .map(|stage| stage_magnitude(&stage.target, frequency))
+            .product()
+    }
+
+    fn decibels(magnitude: f32) -> f32 {
+        20. * magnitude.log10()
+    }
+
+    #[test]
+    fn responses_match_their_designs() {
+        // Each response, the gain it has at a few frequencies for a filter at 1khz and the
+        // greatest it may have anywhere.
+        let designs = [
+            (Response::LowPass, vec![(100., 0.), (1000., -3.01)], 0.),
+            (Response::HighPass, vec![(10000., 0.), (1000., -3.01)], 0.),
+            (Response::BandPass, vec![(1000., 0.)], 0.),
+            (Response::Notch, vec![(100., 0.), (10000., 0.)], 0.),
+            (Response::AllPass, vec![(100., 0.), (1000., 0.)], 0.),
+            (Response::Peak, vec![(1000., 6.), (50., 0.)], 6.),
+            (Response::LowShelf, vec![(50., 6.), (15000., 0.)], 6.),
+            (Response::HighShelf, vec![(50., 0.), (15000., 6.)], 6.),
+        ];
+
+        for (response, points, most) in designs {
+            let make = || {
+                Biquad::new(response, RATE, 1000., BUTTERWORTH_Q)
+                    .unwrap()
+                    .with_gain(6.)
+            };
+            let filter = make();
+            for (frequency, gain) in points {
+                let designed = decibels(magnitude(&filter, frequency));
+                assert!(
+                    (designed - gain).abs() < 0.1,
+                    "{:?} {}",
+                    response,
+                    frequency
+                );
+            }
+
+            for frequency in [60., 250., 700., 1000., 1500., 4000., 12000.] {
+                let designed = decibels(magnitude(&filter, frequency));
+                assert!(designed < most + 0.01, "{:?} {}", response, frequency);
+                if designed > -40. {
+                    let gain = measured(make, frequency);
+                    assert!(
+                        (gain - designed).abs() < 0.2,
+                        "{:?} at {}hz measured {} designed {}",
+                        response,
+                        frequency,
+                        gain,
+                        designed
+                    );
+                }
+            }
+        }
+
+        let notch = Biquad::new(Response::Notch, RATE, 1000., 2.).unwrap();
+        assert!(decibels(magnitude(&notch, 1000.)) < -60.);
+        let low_pass = Biquad::new(Response::LowPass, RATE, 1000., BUTTERWORTH_Q).unwrap();
+        assert!(decibels(magnitude(&low_pass, 10000.)) < -35.);
+    }
+
+    #[test]
+    fn butterworth_cascades() {
+        let make = || {
+            Biquad::new(Response::LowPass, RATE, 1000., BUTTERWORTH_Q)
+                .unwrap()
+                .with_order(4)
+                .unwrap()
+        };
+        let filter = make();
+        assert_eq!(filter.stages.len(), 2);
+        assert!((decibels(magnitude(&filter, 1000.)) + 3.01).abs() < 0.05);
+        assert!(decibels(magnitude(&filter, 500.)) > -0.1);
+
+        // Four poles fall off 24db an octave.
+        let gain = measured(make, 2000.);
+        assert!((gain - decibels(magnitude(&filter, 2000.))).abs() < 0.2);
+        assert!(gain < -23. && gain > -26., "{}", gain);
+
+        let make = || {
+            Biquad::new(Response::HighPass, RATE, 1000., BUTTERWORTH_Q)
+                .unwrap()
+                .with_order(8)
+                .unwrap()
+        };
+        assert!((decibels(magnitude(&make(), 1000.)) + 3.01).abs() < 0.05);
+        assert!(measured(make, 500.) < -45.);
+    }
+
+    #[test]
+    fn changes_are_smoothed() {
+        let mut filter = Biquad::new(Response::LowPass, RATE, 500., BUTTERWORTH_Q).unwrap();
+        let start = filter.stages[0].current;
+        filter.set_parameter(0, 5000.);
+        filter.next(0.);
+        assert!(filter.stages[0].current != filter.stages[0].target);
+        assert!(filter.stages[0].current != start);
+
+        for _ in 0..RATE as usize / 10 {
+            filter.next(0.);
+        }
+        assert!(filter.settled);
+        assert_eq!(filter.parameter(0), Some(5000.));
+
+        let mut filter = filter.with_smoothing(0.);
+        filter.set_parameter(0, 100.);
+        filter.next(0.);
+        assert!(filter.settled);
+    }
+
+    #[test]
+    fn stable_under_audio_rate_modulation() {
+        let mut filter = Biquad::new(Response::LowPass, RATE, 1000., 20.)
+            .unwrap()
+            .with_order(4)
+            .unwrap()
+            .with_smoothing(0.001);
+        let mut loudest = 0f32;
+        for i in 0..RATE as usize {
+            let phase = 2. * PI * i as f32 / RATE;
+            filter.set_parameter(0, 2000. + 1900. * (300. * phase).sin());
+            let output = filter.next((110. * phase).sin());
+            loudest = loudest.max(output.abs());
+        }
+        assert!(loudest.is_finite() && loudest < 100., "{}", loudest);
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(Biquad::new(Response::LowPass, RATE, 0., 1.).is_err());
+        assert!(Biquad::new(Response::LowPass, RATE, 30000., 1.).is_err());
+        assert!(Biquad::new(Response::LowPass, RATE, 1000., 0.).is_err());
+
+        let filter = || Biquad::new(Response::LowPass, RATE, 1000., 1.).unwrap();
+        assert!(filter().with_order(3).is_err());
+        assert!(filter().with_order(0).is_err());
+        assert!(filter().with_order(10).is_err());
+    }
+
+    #[test]
+    fn responses_by_name() {
+        assert_eq!("notch".parse::<Response>(), Ok(Response::Notch));
+        assert_eq!("all-pass".parse::<Response>(), Ok(Response::AllPass));
+        assert!("comb".parse::<Response>().is_err());
+    }
+}
@@ -0,0 +1,12 @@
+//! Small signal processing routines shared by the parts of the mixer that need them, such as the
+//! interpolation used both to play recordings between their frames and to find peaks between
+//! samples.
+
+/// Four point cubic hermite interpolation a fraction `t` of the way from `y1` to `y2`.
+pub fn hermite(y0: f32, y1: f32, y2: f32, y3: f32, t: f32) -> f32 {
+    let c1 = 0.5 * (y2 - y0);
+    let c2 = y0 - 2.5 * y1 + 2. * y2 - 0.5 * y3;
+    let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+
+    ((c3 * t + c2) * t + c1) * t + y1
+}
@@ -0,0 +1,282 @@
+/**
+ * Effects that process the interleaved frames of a mixer bus in place, for the insert slots on
+ * each bus and on the master. An effect allocates everything it needs when it is made so it can
+ * run on the audio thread.
+ */
+use std::error::Error;
+
+/// Processes blocks of interleaved frames in place.
+pub trait Effect {
+    /// Process a buffer of interleaved frames of `channels` channels. Effects made for fewer
+    /// channels pass the rest through untouched.
+    fn process(&mut self, buffer: &mut [f32], channels: usize);
+
+    /// Change a parameter, numbered as documented by the effect. Unknown parameters are ignored.
+    fn set_parameter(&mut self, _parameter: usize, _value: f32) {}
+}
+
+/// Mix a processed value with the value that went in.
+fn blend(dry: f32, wet: f32, mix: f32) -> f32 {
+    dry + (wet - dry) * mix
+}
+
+/// An echo that repeats each channel after a delay, each repeat quieter by the feedback.
+///
+/// Parameters: 0 is the delay time in seconds up to the longest given when it was made, 1 the
+/// feedback and 2 the mix of echoes with the input.
+#[derive(Debug)]
+pub struct Delay {
+    sample_rate: f32,
+    lines: Vec<Vec<f32>>,
+    position: usize,
+    delay: usize,
+    feedback: f32,
+    mix: f32,
+}
+
+impl Delay {
+    pub fn new(
+        sample_rate: f32,
+        channels: usize,
+        longest: f32,
+        time: f32,
+        feedback: f32,
+        mix: f32,
+    ) -> Result<Self, Box<dyn Error>> {
+        if longest.is_nan() || time.is_nan() || time * sample_rate < 1. || time > longest {
+            return Err(
+                "a delay time must be at least a sample and no longer than the longest".into(),
+            );
+        }
+        if feedback.is_nan() || feedback.abs() >= 1. {
+            return Err("delay feedback must be between -1 and 1".into());
+        }
+
+        let length = (longest * sample_rate) as usize + 1;
+        Ok(Delay {
+            sample_rate,
+            lines: vec![vec![0.; length]; channels],
+            position: 0,
+            delay: ((time * sample_rate) as usize).max(1),
+            feedback,
+            mix,
+        })
+    }
+}
+
+impl Effect for Delay {
+    fn process(&mut self, buffer: &mut [f32], channels: usize) {
+        let length = self.lines.first().map_or(1, Vec::len);
+        for frame in buffer.chunks_mut(channels.max(1)) {
+            let read = (self.position + length - self.delay) % length;
+            for (line, value) in self.lines.iter_mut().zip(frame.iter_mut()) {
+                let echo = line[read];
+                line[self.position] = *value + echo * self.feedback;
+                *value = blend(*value, echo, self.mix);
+            }
+            self.position = (self.position + 1) % length;
+        }
+    }
+
+    fn set_parameter(&mut self, parameter: usize, value: f32) {
+        let length = self.lines.first().map_or(1, Vec::len);
+        match parameter {
+            0 => self.delay = ((value * self.sample_rate) as usize).clamp(1, length - 1),
+            1 => self.feedback = value.clamp(-0.99, 0.99),
+            2 => self.mix = value.clamp(0., 1.),
+            _ => {}
+        }
+    }
+}
+
+/// The comb and allpass lengths of the reverb at 44.1khz, from Freeverb.
+const COMBS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASSES: [usize; 4] = [556, 441, 341, 225];
+
+/// How much longer each channel's delays are than the last, which decorrelates the channels.
+const SPREAD: usize = 23;
+
+/// The level the input is scaled to before it enters the combs, which would otherwise sum to far
+/// beyond full scale.
+const REVERB_INPUT: f32 = 0.015;
+
+/// A feedback comb filter with a low-pass in the loop.
+#[derive(Debug)]
+struct Comb {
+    buffer: Vec<f32>,
+    position: usize,
+    store: f32,
+}
+
+impl Comb {
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.position];
+        self.store = output * (1. - damping) + self.store * damping;
+        self.buffer[self.position] = input + self.store * feedback;
+        self.position = (self.position + 1) % self.buffer.len();
+        output
+    }
+}
+
+/// A Schroeder allpass, which smears the echoes of the combs without colouring them.
+#[derive(Debug)]
+struct Allpass {
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.buffer[self.position];
+        self.buffer[self.position] = input + delayed * 0.5;
+        self.position = (self.position + 1) % self.buffer.len();
+        delayed - input
+    }
+}
+
+/// A room reverb after Freeverb: eight damped combs in parallel followed by four allpasses in
+/// series, for each channel.
+///
+/// Parameters: 0 is the room size from 0 to 1, 1 the damping of high frequencies from 0 to 1 and 2
+/// the mix of reverb with the input. A reverb on a send bus usually has a mix of 1.
+#[derive(Debug)]
+pub struct Reverb {
+    combs: Vec<Vec<Comb>>,
+    allpasses: Vec<Vec<Allpass>>,
+    feedback: f32,
+    damping: f32,
+    mix: f32,
+}
+
+impl Reverb {
+    pub fn new(sample_rate: f32, channels: usize, room: f32, damping: f32, mix: f32) -> Self {
+        let scale = |length: usize, channel: usize| {
+            ((length + channel * SPREAD) as f32 * sample_rate / 44100.).max(1.) as usize
+        };
+
+        let mut reverb = Reverb {
+            combs: (0..channels)
+                .map(|channel| {
+                    COMBS
+                        .iter()
+                        .map(|length| Comb {
+                            buffer: vec![0.; scale(*length, channel)],
+                            position: 0,
+                            store: 0.,
+                        })
+                        .collect()
+                })
+                .collect(),
+            allpasses: (0..channels)
+                .map(|channel| {
+                    ALLPASSES
+                        .iter()
+                        .map(|length| Allpass {
+                            buffer: vec![0.; scale(*length, channel)],
+                            position: 0,
+                        })
+                        .collect()
+                })
+                .collect(),
+            feedback: 0.,
+            damping: 0.,
+            mix: 0.,
+        };
+        reverb.set_parameter(0, room);
+        reverb.set_parameter(1, damping);
+        reverb.set_parameter(2, mix);
+        reverb
+    }
+}
+
+impl Effect for Reverb {
+    fn process(&mut self, buffer: &mut [f32], channels: usize) {
+        let (feedback, damping, mix) = (self.feedback, self.damping, self.mix);
+        for frame in buffer.chunks_mut(channels.max(1)) {
+            for ((combs, allpasses), value) in self
+                .combs
+                .iter_mut()
+                .zip(self.allpasses.iter_mut())
+                .zip(frame.iter_mut())
+            {
+                let input = *value * REVERB_INPUT;
+                let mut wet = combs
+                    .iter_mut()
+                    .map(|comb| comb.process(input, feedback, damping))
+                    .sum::<f32>();
+                for allpass in allpasses.iter_mut() {
+                    wet = allpass.process(wet);
+                }
+                *value = blend(*value, wet, mix);
+            }
+        }
+    }
+
+    fn set_parameter(&mut self, parameter: usize, value: f32) {
+        let value = value.clamp(0., 1.);
+        match parameter {
+            // Freeverb maps the room size onto comb feedback from 0.7 to 0.98.
+            0 => self.feedback = 0.7 + 0.28 * value,
+            1 => self.damping = 0.4 * value,
+            2 => self.mix = value,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod effect_tests {
+    use super::{Delay, Effect, Reverb};
+
+    #[test]
+    fn delay_repeats_input() {
+        let mut delay = Delay::new(100., 2, 1., 0.1, 0.5, 1.).unwrap();
+        let mut buffer = vec![0.; 2 * 40];
+        buffer[0] = 1.;
+        delay.process(&mut buffer, 2);
+
+        let left: Vec<f32> = buffer.chunks(2).map(|frame| frame[0]).collect();
+        assert_eq!(left[10], 1.);
+        assert_eq!(left[20], 0.5);
+        assert_eq!(left[30], 0.25);
+        assert_eq!(left.iter().sum::<f32>(), 1.75);
+        assert!(buffer.chunks(2).all(|frame| frame[1] == 0.));
+
+        // The time can be changed while it runs.
+        delay.set_parameter(0, 0.05);
+        let mut buffer = vec![0.; 2 * 10];
+        buffer[0] = 1.;
+        delay.process(&mut buffer, 2);
+        assert_eq!(buffer[10], 1.);
+    }
+
+    #[test]
+    fn delay_rejects_bad_settings() {
+        assert!(Delay::new(100., 1, 1., 2., 0.5, 1.).is_err());
+        assert!(Delay::new(100., 1, 1., 0.5, 1., 1.).is_err());
+        assert!(Delay::new(100., 1, 0., 0., 0.5, 1.).is_err());
+        assert!(Delay::new(100., 1, 1., 0.001, 0.5, 1.).is_err());
+    }
+
+    #[test]
+    fn reverb_tail_decays() {
+        let energy = |buffer: &[f32]| buffer.iter().map(|x| x * x).sum::<f32>();
+        let mut reverb = Reverb::new(44100., 2, 0.5, 0.5, 1.);
+
+        let mut block = vec![0.; 2 * 4410];
+        block[0] = 1.;
+        block[1] = 1.;
+        reverb.process(&mut block, 2);
+        let early = energy(&block);
+        assert!(early > 0.);
+
+        // The channels are decorrelated.
+        assert!(block.chunks(2).any(|frame| frame[0] != frame[1]));
+
+        for _ in 0..20 {
+            block.iter_mut().for_each(|x| *x = 0.);
+            reverb.process(&mut block, 2);
+        }
+        assert!(energy(&block) < early * 1e-3);
+    }
+}
@@ -0,0 +1,442 @@
+/**
+ * The part of the program that runs on the audio thread. Everything it needs is allocated before
+ * the stream starts: voices are built on the main thread and arrive ready to play through a
+ * lock-free queue, the mixer has room for every voice it can hold, finished voices are sent back
+ * to be dropped and the visualisation samples go out through another queue. Rendering a block
+ * never allocates, frees, locks or blocks.
+ *
+ * Every event is stamped with the frame it should happen on and the mixer carries it out on exactly
+ * that frame, so the same events always render the same way however the output is split into
+ * blocks.
+ */
+use crate::master::Master;
+use crate::meter::{Meter, Reading};
+use crate::mixer::{Action, BusId, MixAction, Mixer, Voice, VoiceId};
+use crate::ring::{Consumer, Producer};
+use crate::sampler::note_frequency;
+use crate::source::Parameter;
+use crate::ui::Note;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The most voices whose meters are published.
+pub const METERED_VOICES: usize = 16;
+
+/// The meter readings published after each block.
+#[derive(Debug, Clone, Copy)]
+pub struct Levels {
+    pub mix: Reading,
+
+    // The readings of the first voices playing, in the order they started, and how many there are.
+    pub voices: [Reading; METERED_VOICES],
+    pub voice_count: usize,
+}
+
+/// A request for the audio thread, carrying any voice it needs already built.
+pub enum Event {
+    // Play a voice at a pan position for a note until the note is stopped, along with the midi
+    // note it plays. A voice already held for the note is released.
+    Start(Note, Voice, f32, u8),
+
+    // Release the voice held for a note.
+    Stop(Note),
+
+    // Play a voice until it finishes by itself, along with the midi note it plays if it plays one
+    // and its priority when voices are stolen.
+    Play(Voice, Option<u8>, u8),
+
+    // Set the gain of the held notes, including those started later.
+    Level(f32),
+
+    // Bend the held notes, including those started later, by a number of semitones.
+    Bend(f32),
+
+    // Change a bus or an insert of the mixer.
+    Mix(MixAction),
+}
+
+/// The priority of held notes, which are kept over every other voice when voices are stolen.
+const HELD_PRIORITY: u8 = u8::MAX;
+
+pub struct Engine {
+    mixer: Mixer,
+    master: Master,
+    channels: usize,
+
+    // The voice playing each held note so it can be released when the note is stopped, and the
+    // midi note it plays.
+    held: [Option<(VoiceId, u8)>; Note::VARIANT_COUNT],
+
+    // The gain of the held notes and how far they are bent, in semitones.
+    level: f32,
+    bend: f32,
+
+    // Events along with the frame they happen on.
+    events: Consumer<(u64, Event)>,
+
+    // The number of frames rendered, published for the main thread to schedule against.
+    clock: Arc<AtomicU64>,
+
+    // The average of the channels of each frame, for the visualisation.
+    scope: Producer<f32>,
+
+    // Where meter readings are published and the meter of the output, if the engine meters.
+    levels: Option<(Producer<Levels>, Meter)>,
+
+    // The bus held notes are sent to and at what level.
+    note_send: Option<(BusId, f32)>,
+}
+
+impl Engine {
+    /// An engine that renders frames of `channels` channels. The mixer should already have its
+    /// voice limit, block size, event capacity and retirement queue set so that it never
+    /// allocates.
+    pub fn new(
+        mixer: Mixer,
+        master: Master,
+        channels: usize,
+        events: Consumer<(u64, Event)>,
+        scope: Producer<f32>,
+    ) -> Self {
+        Engine {
+            mixer,
+            master,
+            channels: channels.max(1),
+            held: [None; Note::VARIANT_COUNT],
+            level: 1.,
+            bend: 0.,
+            events,
+            clock: Arc::new(AtomicU64::new(0)),
+            scope,
+            levels: None,
+            note_send: None,
+        }
+    }
+
+    /// Meter the output after the master section and publish its reading with those of the
+    /// mixer's voice meters after every block. Readings are dropped while the queue is full.
+    pub fn with_levels(self, levels: Producer<Levels>, sample_rate: f32) -> Self {
+        Engine {
+            levels: Some((levels, Meter::new(sample_rate, self.channels))),
+            ..self
+        }
+    }
+
+    /// Send the voices of held notes to a bus of the mixer, while voices that finish by
+    /// themselves stay dry.
+    pub fn with_note_send(self, bus: BusId, level: f32) -> Self {
+        Engine {
+            note_send: Some((bus, level)),
+            ..self
+        }
+    }
+
+    /// The number of frames rendered so far, which can be read from another thread. Events for
+    /// frames that have already been rendered happen at the start of the next block.
+    pub fn clock(&self) -> Arc<AtomicU64> {
+        self.clock.clone()
+    }
+
+    /// Schedule an event with the mixer. Notes are held from the frame they start, so a note
+    /// stopped or restarted later releases the voice started for it most recently.
+    fn schedule(&mut self, frame: u64, event: Event) {
+        match event {
+            Event::Start(note, voice, pan, key) => {
+                if let Some((id, _)) = self.held[note as usize].take() {
+                    self.mixer.schedule(frame, id, Action::Release);
+                }
+                let id = self
+                    .mixer
+                    .schedule_voice(frame, voice, Some(key), HELD_PRIORITY);
+                self.mixer.schedule(frame, id, Action::Pan(pan));
+                self.mixer.schedule(frame, id, Action::Gain(self.level));
+                if self.bend != 0. {
+                    self.mixer.schedule(frame, id, self.bent(key));
+                }
+                if let Some((bus, level)) = self.note_send {
+                    self.mixer.schedule(frame, id, Action::Send(bus, level));
+                }
+                self.held[note as usize] = Some((id, key));
+            }
+            Event::Stop(note) => {
+                if let Some((id, _)) = self.held[note as usize].take() {
+                    self.mixer.schedule(frame, id, Action::Release);
+                }
+            }
+            Event::Play(voice, key, priority) => {
+                self.mixer.schedule_voice(frame, voice, key, priority);
+            }
+            Event::Level(level) => {
+                self.level = level;
+                for (id, _) in self.held.into_iter().flatten() {
+                    self.mixer.schedule(frame, id, Action::Gain(level));
+                }
+            }
+            Event::Bend(bend) => {
+                self.bend = bend;
+                for (id, key) in self.held.into_iter().flatten() {
+                    self.mixer.schedule(frame, id, self.bent(key));
+                }
+            }
+            Event::Mix(action) => self.mixer.schedule_mix(frame, action),
+        }
+    }
+
+    /// The change that retunes a held note playing a midi note to the current bend.
+    fn bent(&self, key: u8) -> Action {
+        let frequency = note_frequency(key) * 2f32.powf(self.bend / 12.);
+        Action::Parameter(Parameter::Frequency, frequency)
+    }
+
+    /// Fill a buffer of interleaved frames, scheduling the events that arrived since the last
+    /// block first. Visualisation samples are dropped while the interface is too far behind to
+    /// take them.
+    pub fn render(&mut self, buffer: &mut [f32]) {
+        while let Some((frame, event)) = self.events.pop() {
+            self.schedule(frame, event);
+        }
+
+        self.mixer.process_frames(buffer, self.channels);
+        self.clock.store(self.mixer.frame(), Ordering::Relaxed);
+        self.master
+            .process_block(buffer, self.channels, self.mixer.voices());
+
+        for frame in buffer.chunks(self.channels) {
+            let _ = self
+                .scope
+                .push(frame.iter().sum::<f32>() / self.channels as f32);
+        }
+
+        if let Some((levels, meter)) = &mut self.levels {
+            meter.process(buffer);
+            let mut voices = [Reading::default(); METERED_VOICES];
+            let mut voice_count = 0;
+            for (slot, (_, reading)) in voices.iter_mut().zip(self.mixer.voice_readings()) {
+                *slot = reading;
+                voice_count += 1;
+            }
+            let _ = levels.push(Levels {
+                mix: meter.reading(),
+                voices,
+                voice_count,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod engine_tests {
+    use super::{Engine, Event};
+    use crate::adsr::Adsr;
+    use crate::effect::{Delay, Reverb};
+    use crate::lfo::{Lfo, Rate, Shape};
+    use crate::master::{Limiter, Master};
+    use crate::mixer::{Mixer, StealPolicy, Target, Voice};
+    use crate::modulation::{Destination, Modulated, Modulator};
+    use crate::ring::ring;
+    use crate::sample::Sample;
+    use crate::ui::Note;
+    use rand::{rngs::SmallRng, SeedableRng};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// The system allocator, counting the allocations and frees made by any thread that has turned
+    /// counting on.
+    struct Counting;
+
+    static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+    thread_local! {
+        static COUNTING: Cell<bool> = const { Cell::new(false) };
+    }
+
+    fn count() {
+        if COUNTING
+            .try_with(|counting| counting.get())
+            .unwrap_or(false)
+        {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count();
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            count();
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count();
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    /// The allocations and frees made by `f` on this thread.
+    fn allocations(f: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        COUNTING.with(|counting| counting.set(true));
+        f();
+        COUNTING.with(|counting| counting.set(false));
+        ALLOCATIONS.load(Ordering::Relaxed) - before
+    }
+
+    const RATE: f32 = 44100.;
+    const BLOCK: usize = 256;
+
+    #[test]
+    fn counts_allocations() {
+        assert!(allocations(|| drop(vec![0u8; 16])) >= 2);
+    }
+
+    #[test]
+    fn rendering_never_allocates() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let (retired_tx, retired_rx) = ring(128);
+        let (mut events, events_rx) = ring(64);
+        let (scope, scope_rx) = ring(1 << 17);
+
+        let mut mixer = Mixer::new()
+            .with_voice_limit(4, StealPolicy::Quietest, 64)
+            .with_block_size(BLOCK, 2)
+            .with_event_capacity(64)
+            .with_metering(RATE)
+            .with_retirement(retired_tx)
+            .unwrap();
+        let bus = mixer.add_bus("reverb").unwrap();
+        mixer.add_insert(Target::Bus(bus), Reverb::new(RATE, 2, 0.5, 0.5, 1.));
+        mixer.add_insert(
+            Target::Master,
+            Delay::new(RATE, 2, 0.5, 0.25, 0.3, 0.2).unwrap(),
+        );
+        let master = Master::new(RATE)
+            .with_normalisation(true)
+            .with_limiter(Limiter::new(RATE, 2, 0.9, 0.005, 0.1).unwrap());
+        let (levels, levels_rx) = ring(512);
+        let mut engine = Engine::new(mixer, master, 2, events_rx, scope)
+            .with_levels(levels, RATE)
+            .with_note_send(bus, 0.5);
+
+        // More voices than the limit, some held and some that finish by themselves.
+        let note = |rng: &mut SmallRng| -> Voice {
+            let lfo = Lfo::new(rng, RATE, Shape::Sine, Rate::Hertz(5.));
+            let voice = Modulated::new(Sample::middle_a(RATE))
+                .with_modulator(Modulator::Lfo(lfo))
+                .with_route(0, Destination::Frequency, 0.01)
+                .unwrap();
+            Box::new(Adsr::gated(voice, RATE, 0.01, 0.8, 0.1, 0.5, 0.05))
+        };
+        for (i, note_name) in [Note::A, Note::B, Note::C, Note::D, Note::A]
+            .into_iter()
+            .enumerate()
+        {
+            let event = Event::Start(note_name, note(&mut rng), -0.5, 69);
+            assert!(events.push((i as u64 * 100, event)).is_ok());
+        }
+        for i in 0..3 {
+            let pluck = Sample::pluck(&mut rng, RATE, 220., 0.004, 0.5).unwrap();
+            let voice = Adsr::new(pluck, RATE, 0., 0.8, 0., 0.05, 0.8, 0.01);
+            assert!(events
+                .push((i * 1000, Event::Play(Box::new(voice), None, 0)))
+                .is_ok());
+        }
+
+        let mut buffer = [0.; 2 * BLOCK];
+        let count = allocations(|| {
+            for block in 0..400 {
+                if block == 100 {
+                    for note_name in [Note::A, Note::B, Note::C, Note::D] {
+                        let _ = events.push((100 * BLOCK as u64 + 17, Event::Stop(note_name)));
+                    }
+                }
+                engine.render(&mut buffer);
+            }
+        });
+
+        assert_eq!(count, 0);
+        assert_eq!(engine.mixer.voices(), 0);
+        assert_eq!(retired_rx.count(), 8);
+        assert_eq!(scope_rx.count(), 400 * BLOCK);
+
+        let published: Vec<_> = levels_rx.collect();
+        assert_eq!(published.len(), 400);
+        assert!(published[50].voice_count > 0);
+        assert!(published[50].mix.peak > 0.);
+
+        // The mix is metered after the limiter.
+        assert!(published.iter().all(|x| x.mix.peak <= 0.9));
+    }
+
+    #[test]
+    fn renders_the_same_whatever_the_block_size() {
+        let render = |block: usize| {
+            let mut rng = SmallRng::seed_from_u64(2);
+            let (mut events, events_rx) = ring(16);
+            let (scope, _scope_rx) = ring(16);
+            let mixer = Mixer::new().with_voice_limit(4, StealPolicy::Oldest, 16);
+            let mut engine = Engine::new(mixer, Master::new(RATE), 1, events_rx, scope);
+
+            let note = Adsr::gated(Sample::middle_a(RATE), RATE, 0.01, 0.8, 0.01, 0.5, 0.01);
+            let pluck = Sample::pluck(&mut rng, RATE, 220., 0.004, 0.5).unwrap();
+            assert!(events
+                .push((100, Event::Start(Note::A, Box::new(note), 0., 69)))
+                .is_ok());
+            assert!(events
+                .push((700, Event::Play(Box::new(pluck), None, 0)))
+                .is_ok());
+            assert!(events.push((1234, Event::Stop(Note::A))).is_ok());
+
+            let mut output = Vec::new();
+            let mut buffer = vec![0.; block];
+            while output.len() < 4096 {
+                engine.render(&mut buffer);
+                output.extend_from_slice(&buffer);
+            }
+            output.truncate(4096);
+            output
+        };
+
+        let reference = render(1);
+        assert_eq!(reference[99], 0.);
+        assert!(reference[100..700].iter().any(|x| *x != 0.));
+        for block in [64, 100, 512] {
+            assert_eq!(render(block), reference);
+        }
+    }
+
+    #[test]
+    fn held_notes_follow_level_and_bend() {
+        let (mut events, events_rx) = ring(16);
+        let (scope, _scope_rx) = ring(16);
+        let mut engine = Engine::new(Mixer::new(), Master::new(RATE), 1, events_rx, scope);
+
+        // A note held at 440hz, bent up an octave after a second and silenced after two.
+        let note = Adsr::gated(Sample::middle_a(RATE), RATE, 0., 0.5, 0., 0.5, 0.01);
+        let second = RATE as u64;
+        assert!(events
+            .push((0, Event::Start(Note::A, Box::new(note), 0., 69)))
+            .is_ok());
+        assert!(events.push((second, Event::Bend(12.))).is_ok());
+        assert!(events.push((2 * second, Event::Level(0.))).is_ok());
+
+        let mut output = vec![0.; 3 * second as usize];
+        for block in output.chunks_mut(1000) {
+            engine.render(block);
+        }
+
+        let crossings = |values: &[f32]| values.windows(2).filter(|x| x[0] * x[1] < 0.).count();
+        let (first, rest) = output.split_at(second as usize);
+        let (bent, silenced) = rest.split_at(second as usize);
+        assert!((crossings(first) as i32 - 880).abs() <= 2);
+        assert!((crossings(bent) as i32 - 1760).abs() <= 2);
+        assert!(silenced.iter().all(|x| *x == 0.));
+    }
+}
@@ -0,0 +1,625 @@
+/**
+ * Envelopes built from a list of breakpoints. Each breakpoint is a level to move to, how long to take
+ * getting there and the shape of the way there. An envelope can hold at a sustain point until it is
+ * released and can loop between two breakpoints to make rhythmic envelopes. The classic adsr shape
+ * is one preset of this.
+ */
+use std::error::Error;
+
+/// The curvature used by the exponential and logarithmic presets.
+const CURVATURE: f32 = 5.;
+
+/// The steepest curvature a segment can have. Beyond this `exp` overflows and the level is NaN.
+const MAX_CURVATURE: f32 = 80.;
+
+/// The shape of a segment of an envelope as it moves from one level to another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+    // A straight line between the two levels.
+    Linear,
+
+    // Changes slowly at the low end and quickly at the high end, so rises start gently and falls
+    // drop away quickly before tailing off.
+    Exponential,
+
+    // The opposite of exponential: rises jump up quickly and falls linger near the top.
+    Logarithmic,
+
+    // A curve of a given curvature. Positive values bend the curve the way of exponential,
+    // negative values the way of logarithmic and zero is linear. Curvatures steeper than 80 either
+    // way are clamped to it.
+    Curvature(f32),
+}
+
+impl Curve {
+    /// The level a fraction `t` (0 to 1) of the way through a segment from `start` to `end`.
+    pub fn level(&self, start: f32, end: f32, t: f32) -> f32 {
+        let curvature = match self {
+            Curve::Linear => 0.,
+            Curve::Exponential => CURVATURE,
+            Curve::Logarithmic => -CURVATURE,
+            Curve::Curvature(curvature) => curvature.clamp(-MAX_CURVATURE, MAX_CURVATURE),
+        };
+
+        // Bend towards the low end whichever way the segment is heading.
+        let curvature = if end < start { -curvature } else { curvature };
+
+        let progress = if curvature.abs() < 1e-4 {
+            t
+        } else {
+            ((curvature * t).exp() - 1.) / (curvature.exp() - 1.)
+        };
+
+        start + (end - start) * progress
+    }
+}
+
+/// One stage of an envelope: move from the level of the previous breakpoint (or zero) to `level`
+/// over `time` seconds following `curve`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Breakpoint {
+    pub time: f32,
+    pub level: f32,
+    pub curve: Curve,
+}
+
+impl Breakpoint {
+    pub fn new(time: f32, level: f32, curve: Curve) -> Self {
+        Breakpoint { time, level, curve }
+    }
+
+    /// A breakpoint reached by a straight line.
+    pub fn linear(time: f32, level: f32) -> Self {
+        Self::new(time, level, Curve::Linear)
+    }
+}
+
+/// The part a breakpoint plays in one of the preset shapes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Delay,
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// An amplitude contour made of any number of breakpoints, starting from zero.
+#[derive(Debug)]
+pub struct Envelope {
+    breakpoints: Vec<Breakpoint>,
+
+    // The stage each breakpoint plays for envelopes built from a preset. Envelopes built from bare
+    // breakpoints have none.
+    stages: Vec<Stage>,
+
+    // The breakpoint to hold at until the envelope is released.
+    sustain: Option<usize>,
+
+    // While the envelope has not been released, reaching the breakpoint at the end of the loop
+    // starts again from the breakpoint at the start of the loop.
+    loop_points: Option<(usize, usize)>,
+
+    // The breakpoint currently being moved towards. Once this is past the last breakpoint the
+    // envelope is finished.
+    stage: usize,
+
+    // The time spent in the current stage
+    time_in_stage: f32,
+
+    // The level the current stage started from
+    start_level: f32,
+
+    // True when the envelope has reached its sustain point and is waiting to be released.
+    holding: bool,
+    released: bool,
+
+    // The most recent level returned by the envelope
+    level: f32,
+
+    // The sample rate of the output stream
+    sample_rate: f32,
+}
+
+impl Envelope {
+    /// An envelope moving through `breakpoints` in order. Without a sustain point it runs through
+    /// every breakpoint and finishes on its own.
+    pub fn from_breakpoints(
+        sample_rate: f32,
+        breakpoints: Vec<Breakpoint>,
+    ) -> Result<Self, Box<dyn Error>> {
+        if breakpoints.is_empty() {
+            return Err("an envelope needs at least one breakpoint".into());
+        }
+        if !breakpoints.iter().all(|x| x.time >= 0.) {
+            return Err("breakpoint times must not be negative".into());
+        }
+
+        Ok(Envelope {
+            breakpoints,
+            stages: Vec::new(),
+            sustain: None,
+            loop_points: None,
+            stage: 0,
+            time_in_stage: 0.,
+            start_level: 0.,
+            holding: false,
+            released: false,
+            level: 0.,
+            sample_rate,
+        })
+    }
+
+    /// Hold at the breakpoint with the given index until the envelope is released.
+    pub fn with_sustain(self, index: usize) -> Result<Self, Box<dyn Error>> {
+        if index >= self.breakpoints.len() {
+            return Err("the sustain point must be one of the breakpoints".into());
+        }
+
+        Ok(Envelope {
+            sustain: Some(index),
+            ..self
+        })
+    }
+
+    /// Loop from the breakpoint at `end` back to the breakpoint at `start` until the envelope is
+    /// released. The first stage of the loop moves from the level at `end` to the level at `start`.
+    pub fn with_loop(self, start: usize, end: usize) -> Result<Self, Box<dyn Error>> {
+        if start > end || end >= self.breakpoints.len() {
+            return Err("the loop must run forwards between two breakpoints".into());
+        }
+
+        Ok(Envelope {
+            loop_points: Some((start, end)),
+            ..self
+        })
+    }
+
+    /// Name the stage each breakpoint plays, one for every breakpoint.
+    fn with_stages(self, stages: Vec<Stage>) -> Self {
+        debug_assert_eq!(stages.len(), self.breakpoints.len());
+        Envelope { stages, ..self }
+    }
+
+    /// The adsr preset. This models a ramp up to a peak, then a decrease to a sustain level. The
+    /// envelope will hold at the sustain level for a fixed amount of time then decrease from
+    /// wherever it is to zero.
+    pub fn new(
+        sample_rate: f32,
+        attack: f32,
+        peak_scalar: f32,
+        decay: f32,
+        sustain: f32,
+        sustain_scalar: f32,
+        release: f32,
+    ) -> Self {
+        Self::from_breakpoints(
+            sample_rate,
+            vec![
+                Breakpoint::linear(attack, peak_scalar),
+                Breakpoint::linear(decay, sustain_scalar),
+                Breakpoint::linear(sustain, sustain_scalar),
+                Breakpoint::linear(release, 0.),
+            ],
+        )
+        .expect("adsr times must not be negative")
+        .with_stages(vec![
+            Stage::Attack,
+            Stage::Decay,
+            Stage::Sustain,
+            Stage::Release,
+        ])
+    }
+
+    /// An adsr envelope that holds at its sustain level until `release` is called.
+    pub fn gated(
+        sample_rate: f32,
+        attack: f32,
+        peak_scalar: f32,
+        decay: f32,
+        sustain_scalar: f32,
+        release: f32,
+    ) -> Self {
+        Self::from_breakpoints(
+            sample_rate,
+            vec![
+                Breakpoint::linear(attack, peak_scalar),
+                Breakpoint::linear(decay, sustain_scalar),
+                Breakpoint::linear(release, 0.),
+            ],
+        )
+        .and_then(|envelope| envelope.with_sustain(1))
+        .expect("adsr times must not be negative")
+        .with_stages(vec![Stage::Attack, Stage::Decay, Stage::Release])
+    }
+
+    /// A gated envelope that waits for `delay` seconds, rises to full level and holds there for
+    /// `hold` seconds before decaying to the sustain level.
+    pub fn dahdsr(
+        sample_rate: f32,
+        delay: f32,
+        attack: f32,
+        hold: f32,
+        decay: f32,
+        sustain_scalar: f32,
+        release: f32,
+    ) -> Self {
+        Self::from_breakpoints(
+            sample_rate,
+            vec![
+                Breakpoint::linear(delay, 0.),
+                Breakpoint::linear(attack, 1.),
+                Breakpoint::linear(hold, 1.),
+                Breakpoint::linear(decay, sustain_scalar),
+                Breakpoint::linear(release, 0.),
+            ],
+        )
+        .and_then(|envelope| envelope.with_sustain(3))
+        .expect("dahdsr times must not be negative")
+        .with_stages(vec![
+            Stage::Delay,
+            Stage::Attack,
+            Stage::Hold,
+            Stage::Decay,
+            Stage::Release,
+        ])
+    }
+
+    /// Set the shape of the attack, decay and release stages of a preset. They are linear by
+    /// default. Envelopes built from bare breakpoints have no named stages and are left as they
+    /// are.
+    pub fn with_curves(mut self, attack: Curve, decay: Curve, release: Curve) -> Self {
+        for (breakpoint, stage) in self.breakpoints.iter_mut().zip(&self.stages) {
+            match stage {
+                Stage::Attack => breakpoint.curve = attack,
+                Stage::Decay => breakpoint.curve = decay,
+                Stage::Release => breakpoint.curve = release,
+                Stage::Delay | Stage::Hold | Stage::Sustain => {}
+            }
+        }
+        self
+    }
+
+    /// Move on from the breakpoint just reached, holding at the sustain point and going round the
+    /// loop until released.
+    fn next_stage(&mut self) {
+        if !self.released {
+            if self.sustain == Some(self.stage) {
+                self.holding = true;
+                return;
+            }
+
+            if let Some((start, end)) = self.loop_points {
+                if self.stage == end {
+                    self.stage = start;
+                    return;
+                }
+            }
+        }
+
+        self.stage += 1;
+    }
+
+    /// The stage a release jumps to: the one after the sustain point, or after the loop, or the
+    /// last stage when the envelope has neither.
+    fn release_stage(&self) -> usize {
+        match (self.sustain, self.loop_points) {
+            (Some(sustain), _) => sustain + 1,
+            (None, Some((_, end))) => end + 1,
+            (None, None) => self.breakpoints.len() - 1,
+        }
+    }
+
+    /// Return the amplitude of the envelope for the next sample.
+    pub fn next(&mut self) -> f32 {
+        if self.holding || self.finished() {
+            return self.level;
+        }
+
+        let breakpoint = self.breakpoints[self.stage];
+        self.time_in_stage += 1. / self.sample_rate;
+        self.level = if self.time_in_stage > breakpoint.time {
+            self.next_stage();
+            self.time_in_stage = 0.;
+            self.start_level = breakpoint.level;
+            breakpoint.level
+        } else {
+            breakpoint.curve.level(
+                self.start_level,
+                breakpoint.level,
+                self.time_in_stage / breakpoint.time,
+            )
+        };
+        self.level
+    }
+
+    /// The level the envelope has reached, which is zero before it has started.
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+
+    /// Multiply a buffer by the next levels of the envelope. While the envelope is holding at its
+    /// sustain point or has finished the level is constant, so the whole buffer is scaled at once.
+    pub fn apply(&mut self, buffer: &mut [f32]) {
+        if self.holding || self.finished() {
+            for value in buffer.iter_mut() {
+                *value *= self.level;
+            }
+        } else {
+            for value in buffer.iter_mut() {
+                *value *= self.next();
+            }
+        }
+    }
+
+    /// Move straight to the release stage, fading out from the current level. This is the note off
+    /// for gated envelopes and cuts the sustain short for timed ones.
+    pub fn release(&mut self) {
+        if self.released {
+            return;
+        }
+        self.released = true;
+
+        let stage = self.release_stage();
+        if self.stage < stage {
+            self.stage = stage;
+            self.holding = false;
+            self.time_in_stage = 0.;
+            self.start_level = self.level;
+        }
+    }
+
+    /// Returns true when this envelope has passed its last breakpoint, at which point next will
+    /// return the level of that breakpoint forever.
+    pub fn finished(&self) -> bool {
+        self.stage >= self.breakpoints.len()
+    }
+}
+
+#[cfg(test)]
+mod envelope_tests {
+    use super::{Breakpoint, Curve, Envelope};
+
+    #[test]
+    fn gated_envelope_holds_until_released() {
+        let mut envelope = Envelope::gated(100., 0.1, 1., 0.1, 0.5, 0.1);
+        for _ in 0..100000 {
+            envelope.next();
+        }
+        assert_eq!(envelope.next(), 0.5);
+        assert!(!envelope.finished());
+
+        envelope.release();
+        for _ in 0..10 {
+            envelope.next();
+        }
+        assert_eq!(envelope.next(), 0.);
+        assert!(envelope.finished());
+    }
+
+    #[test]
+    fn release_during_attack_starts_from_current_level() {
+        let mut envelope = Envelope::gated(100., 1., 1., 0.1, 0.8, 0.1);
+        let mut level = 0.;
+        for _ in 0..20 {
+            level = envelope.next();
+        }
+        assert!((level - 0.2).abs() < 1e-4);
+
+        envelope.release();
+
+        // The release fades out from 0.2 over ten samples rather than jumping to the sustain level.
+        let next = envelope.next();
+        assert!(next < level && level - next < 0.03, "{} -> {}", level, next);
+    }
+
+    #[test]
+    fn timed_envelope_releases_from_sustain() {
+        let mut envelope = Envelope::new(100., 0.01, 1., 0.01, 0.1, 0.5, 0.1);
+        let levels: Vec<f32> = (0..30).map(|_| envelope.next()).collect();
+        assert_eq!(levels[10], 0.5);
+
+        // Without a release the envelope fades out on its own once the sustain time has passed.
+        let fading: Vec<&f32> = levels.iter().filter(|x| **x > 0. && **x < 0.5).collect();
+        assert!(fading.len() >= 8 && fading.windows(2).all(|x| x[1] < x[0]));
+        assert!(envelope.finished());
+    }
+
+    /// Sample the decay and release of an envelope with the given curve and compare each sample to
+    /// the analytic curve. The envelope has no attack so its decay starts on the second sample.
+    fn assert_follows(curve: Curve, expected: impl Fn(f32, f32, f32) -> f32) {
+        let rate = 1000.;
+        let mut envelope =
+            Envelope::gated(rate, 0., 1., 0.1, 0.25, 0.2).with_curves(Curve::Linear, curve, curve);
+        assert_eq!(envelope.next(), 1.);
+
+        for i in 1..100 {
+            let t = i as f32 / 100.;
+            let level = envelope.next();
+            assert!(
+                (level - expected(1., 0.25, t)).abs() < 1e-4,
+                "{:?} decay at {}: {}",
+                curve,
+                t,
+                level
+            );
+        }
+
+        for _ in 0..10 {
+            envelope.next();
+        }
+        envelope.release();
+
+        for i in 1..200 {
+            let t = i as f32 / 200.;
+            let level = envelope.next();
+            assert!(
+                (level - expected(0.25, 0., t)).abs() < 1e-4,
+                "{:?} release at {}: {}",
+                curve,
+                t,
+                level
+            );
+        }
+    }
+
+    #[test]
+    fn linear_segments() {
+        assert_follows(Curve::Linear, |start, end, t| start + (end - start) * t);
+        assert_follows(Curve::Curvature(0.), |start, end, t| {
+            start + (end - start) * t
+        });
+    }
+
+    #[test]
+    fn exponential_segments() {
+        // A falling exponential segment drops quickly then tails off towards its end level.
+        let expected = |start: f32, end: f32, t: f32| {
+            start + (end - start) * ((-5. * t).exp() - 1.) / ((-5f32).exp() - 1.)
+        };
+        assert_follows(Curve::Exponential, expected);
+        assert_follows(Curve::Curvature(5.), expected);
+    }
+
+    #[test]
+    fn logarithmic_segments() {
+        let expected = |start: f32, end: f32, t: f32| {
+            start + (end - start) * ((5. * t).exp() - 1.) / (5f32.exp() - 1.)
+        };
+        assert_follows(Curve::Logarithmic, expected);
+    }
+
+    #[test]
+    fn steep_curvatures_are_clamped() {
+        for curvature in [100., -100., 1000., f32::INFINITY] {
+            for i in 0..=10 {
+                let level = Curve::Curvature(curvature).level(0., 1., i as f32 / 10.);
+                assert!((0. ..=1.).contains(&level), "{}: {}", curvature, level);
+            }
+        }
+        assert_eq!(
+            Curve::Curvature(100.).level(1., 0., 0.5),
+            Curve::Curvature(80.).level(1., 0., 0.5)
+        );
+    }
+
+    #[test]
+    fn curved_attack_rises_slowly_then_quickly() {
+        let mut envelope = Envelope::gated(1000., 0.1, 1., 0.1, 0.5, 0.1).with_curves(
+            Curve::Curvature(3.),
+            Curve::Linear,
+            Curve::Linear,
+        );
+        let attack: Vec<f32> = (0..100).map(|_| envelope.next()).collect();
+
+        for (i, level) in attack.iter().enumerate().take(99) {
+            let t = (i + 1) as f32 / 100.;
+            let expected = ((3. * t).exp() - 1.) / (3f32.exp() - 1.);
+            assert!((level - expected).abs() < 1e-4, "{}: {}", t, level);
+        }
+        assert!(attack[49] < 0.5 * attack[98]);
+    }
+
+    #[test]
+    fn dahdsr_waits_then_holds_at_full_level() {
+        let mut envelope = Envelope::dahdsr(100., 0.1, 0.1, 0.1, 0.1, 0.5, 0.1);
+        let levels: Vec<f32> = (0..60).map(|_| envelope.next()).collect();
+
+        assert!(levels[..10].iter().all(|x| *x == 0.));
+        assert!((levels[15] - 0.5).abs() < 0.11);
+        assert!(levels[21..30].iter().all(|x| *x == 1.));
+        assert_eq!(levels[59], 0.5);
+        assert!(!envelope.finished());
+
+        envelope.release();
+        for _ in 0..11 {
+            envelope.next();
+        }
+        assert!(envelope.finished());
+    }
+
+    #[test]
+    fn dahdsr_curves_follow_stage_names() {
+        let mut envelope = Envelope::dahdsr(1000., 0.1, 0.1, 0.1, 0.1, 0.5, 0.1).with_curves(
+            Curve::Linear,
+            Curve::Exponential,
+            Curve::Linear,
+        );
+        let levels: Vec<f32> = (0..400).map(|_| envelope.next()).collect();
+
+        // The attack stays straight while the decay falls away quickly, where it would be at 0.75
+        // half way down a straight line.
+        assert!((levels[150] - 0.5).abs() < 0.02, "{}", levels[150]);
+        assert!(levels[352] < 0.6, "{}", levels[352]);
+        assert!(levels[230..290].iter().all(|x| *x == 1.));
+    }
+
+    #[test]
+    fn loops_until_released() {
+        // Rise to full level then pulse between 0.2 and 1 until released.
+        let mut envelope = Envelope::from_breakpoints(
+            100.,
+            vec![
+                Breakpoint::linear(0.05, 1.),
+                Breakpoint::linear(0.05, 0.2),
+                Breakpoint::linear(0.05, 1.),
+                Breakpoint::linear(0.1, 0.),
+            ],
+        )
+        .unwrap()
+        .with_loop(1, 2)
+        .unwrap();
+
+        let levels: Vec<f32> = (0..200).map(|_| envelope.next()).collect();
+        let peaks = levels.iter().filter(|x| **x == 1.).count();
+        let troughs = levels.iter().filter(|x| **x == 0.2).count();
+        assert!(peaks >= 15 && troughs >= 15, "{} {}", peaks, troughs);
+        assert!(!envelope.finished());
+
+        // Releasing leaves the loop for the final stage.
+        envelope.release();
+        for _ in 0..11 {
+            envelope.next();
+        }
+        assert!(envelope.finished());
+        assert_eq!(envelope.next(), 0.);
+    }
+
+    #[test]
+    fn adsr_preset_matches_breakpoints() {
+        let mut preset = Envelope::new(100., 0.1, 1., 0.2, 0.3, 0.6, 0.4);
+        let mut breakpoints = Envelope::from_breakpoints(
+            100.,
+            vec![
+                Breakpoint::linear(0.1, 1.),
+                Breakpoint::linear(0.2, 0.6),
+                Breakpoint::linear(0.3, 0.6),
+                Breakpoint::linear(0.4, 0.),
+            ],
+        )
+        .unwrap();
+
+        for _ in 0..120 {
+            assert_eq!(preset.next(), breakpoints.next());
+        }
+        assert!(preset.finished() && breakpoints.finished());
+    }
+
+    #[test]
+    fn invalid_breakpoints() {
+        assert!(Envelope::from_breakpoints(100., vec![]).is_err());
+        assert!(Envelope::from_breakpoints(100., vec![Breakpoint::linear(-1., 1.)]).is_err());
+
+        let envelope = || {
+            Envelope::from_breakpoints(
+                100.,
+                vec![Breakpoint::linear(0.1, 1.), Breakpoint::linear(0.1, 0.)],
+            )
+            .unwrap()
+        };
+        assert!(envelope().with_sustain(2).is_err());
+        assert!(envelope().with_loop(1, 0).is_err());
+        assert!(envelope().with_loop(0, 2).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+/**
+ * Filters that shape the sound of a voice. A filter is placed inside a voice with `Filtered`,
+ * usually between the `Sample` and its `Adsr`, and its parameters become effect parameters of the
+ * voice so they can be modulated like any other.
+ *
+ * The resonant filters share a `Cutoff`, which can follow the keyboard and an envelope of its own
+ * in octaves, the way the cutoff of an analog synth's filter does.
+ */
+use crate::biquad::Biquad;
+use crate::envelope::Envelope;
+use crate::sample::Sample;
+use crate::source::{Parameter, Source};
+use std::f32::consts::PI;
+
+/// The most resonance the resonant filters take. They start to ring on their own just past one.
+pub const MAX_RESONANCE: f32 = 1.2;
+
+/// The lowest a cutoff can go, in hz, and the highest as a fraction of the sample rate.
+const LOWEST_CUTOFF: f32 = 10.;
+const HIGHEST_CUTOFF: f32 = 0.45;
+
+/// Processes a signal a sample at a time.
+pub trait Filter {
+    /// Filter the next sample.
+    fn next(&mut self, input: f32) -> f32;
+
+    /// Filter a buffer in place. Filters that can process a block more efficiently than one
+    /// sample at a time should override this.
+    fn process(&mut self, buffer: &mut [f32]) {
+        for value in buffer.iter_mut() {
+            *value = self.next(*value);
+        }
+    }
+
+    /// The current value of a parameter, numbered as documented by the filter, or None when the
+    /// filter does not have it.
+    fn parameter(&self, _index: usize) -> Option<f32> {
+        None
+    }
+
+    /// Change a parameter. Filters ignore parameters they do not have.
+    fn set_parameter(&mut self, _index: usize, _value: f32) {}
+
+    /// Signal a note off, which filters with envelopes pass on to them.
+    fn release(&mut self) {}
+}
+
+/// The cutoff frequency of a filter, moved from its base frequency by the key of the note and by
+/// an envelope. Both are measured in octaves, so they sound the same whatever the base.
+#[derive(Debug)]
+pub struct Cutoff {
+    sample_rate: f32,
+    frequency: f32,
+
+    // How many octaves the cutoff moves away from middle c.
+    tracking: f32,
+
+    // An envelope and how many octaves its peak moves the cutoff.
+    envelope: Option<(Envelope, f32)>,
+}
+
+impl Cutoff {
+    pub fn new(sample_rate: f32, frequency: f32) -> Self {
+        Cutoff {
+            sample_rate,
+            frequency,
+            tracking: 0.,
+            envelope: None,
+        }
+    }
+
+    /// Follow the key of a midi note by a fraction of its distance from middle c. An amount of one
+    /// keeps the cutoff in the same place relative to the pitch of every note.
+    pub fn with_key_tracking(self, note: u8, amount: f32) -> Self {
+        Cutoff {
+            tracking: amount * (note as f32 - 60.) / 12.,
+            ..self
+        }
+    }
+
+    /// Move the cutoff up by its envelope, by `octaves` at a level of one.
+    pub fn with_envelope(self, envelope: Envelope, octaves: f32) -> Self {
+        Cutoff {
+            envelope: Some((envelope, octaves)),
+            ..self
+        }
+    }
+
+    /// The base frequency in hz, before tracking and the envelope.
+    pub fn frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    pub fn set_frequency(&mut self, frequency: f32) {
+        self.frequency = frequency;
+    }
+
+    /// The cutoff in hz for the next sample, kept between 10hz and just under half the sample
+    /// rate.
+    pub fn next(&mut self) -> f32 {
+        let mut octaves = self.tracking;
+        if let Some((envelope, depth)) = &mut self.envelope {
+            octaves += envelope.next() * *depth;
+        }
+
+        (self.frequency * 2f32.powf(octaves))
+            .max(LOWEST_CUTOFF)
+            .min(HIGHEST_CUTOFF * self.sample_rate)
+    }
+
+    /// The gain of the integrators of a zero delay feedback filter at the next cutoff, prewarped
+    /// so the digital filter has its cutoff in the same place as the analog one.
+    pub fn next_gain(&mut self) -> f32 {
+        (PI * self.next() / self.sample_rate).tan()
+    }
+
+    pub fn release(&mut self) {
+        if let Some((envelope, _)) = &mut self.envelope {
+            envelope.release();
+        }
+    }
+}
+
+/// A source played through a filter. The effect parameters the filter has are its own, and
+/// every other parameter belongs to the source.
+#[derive(Debug)]
+pub struct Filtered<S: Source = Sample, F: Filter = Biquad> {
+    source: S,
+    filter: F,
+}
+
+impl<S: Source, F: Filter> Filtered<S, F> {
+    pub fn new(source: S, filter: F) -> Self {
+        Filtered { source, filter }
+    }
+}
+
+impl<S: Source, F: Filter> Source for Filtered<S, F> {
+    fn next(&mut self) -> f32 {
+        self.filter.next(self.source.next())
+    }
+
+    fn finished(&self) -> bool {
+        self.source.finished()
+    }
+
+    fn process(&mut self, buffer: &mut [f32]) {
+        self.source.process(buffer);
+        self.filter.process(buffer);
+    }
+
+    fn release(&mut self) {
+        self.filter.release();
+        self.source.release();
+    }
+
+    fn parameter(&self, parameter: Parameter) -> Option<f32> {
+        match parameter {
+            Parameter::Effect(index) => self.filter.parameter(index),
+            _ => None,
+        }
+        .or_else(|| self.source.parameter(parameter))
+    }
+
+    fn set_parameter(&mut self, parameter: Parameter, value: f32) {
+        match parameter {
+            Parameter::Effect(index) if self.filter.parameter(index).is_some() => {
+                self.filter.set_parameter(index, value)
+            }
+            _ => self.source.set_parameter(parameter, value),
+        }
+    }
+
+    fn pan(&self) -> f32 {
+        self.source.pan()
+    }
+}
+
+#[cfg(test)]
+mod filter_tests {
+    use super::{Cutoff, Filtered};
+    use crate::adsr::Adsr;
+    use crate::biquad::{Biquad, Response, BUTTERWORTH_Q};
+    use crate::envelope::Envelope;
+    use crate::lfo::{Lfo, Rate, Shape};
+    use crate::modulation::{Destination, Modulated, Modulator};
+    use crate::sample::Sample;
+    use crate::source::{Parameter, Source};
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    const RATE: f32 = 44100.;
+
+    fn low_pass(frequency: f32) -> Biquad {
+        Biquad::new(Response::LowPass, RATE, frequency, BUTTERWORTH_Q).unwrap()
+    }
+
+    #[test]
+    fn parameters_go_to_the_filter_or_the_source() {
+        let mut voice = Filtered::new(Sample::middle_a(RATE), low_pass(1000.));
+        assert_eq!(voice.parameter(Parameter::Effect(0)), Some(1000.));
+        assert_eq!(voice.parameter(Parameter::Frequency), Some(440.));
+        assert_eq!(voice.parameter(Parameter::Effect(3)), None);
+
+        voice.set_parameter(Parameter::Effect(0), 2000.);
+        voice.set_parameter(Parameter::Frequency, 220.);
+        assert_eq!(voice.parameter(Parameter::Effect(0)), Some(2000.));
+        assert_eq!(voice.parameter(Parameter::Frequency), Some(220.));
+
+        // Filters can be nested, each answering for its own parameters.
+        let nested = Filtered::new(voice, low_pass(500.).with_order(4).unwrap());
+        assert_eq!(nested.parameter(Parameter::Effect(0)), Some(500.));
+        assert_eq!(nested.parameter(Parameter::Frequency), Some(220.));
+    }
+
+    #[test]
+    fn a_filter_between_sample_and_envelope() {
+        let quietest = |cutoff: f32| {
+            let mut voice = Adsr::new(
+                Filtered::new(Sample::middle_a(RATE), low_pass(cutoff)),
+                RATE,
+                0.,
+                1.,
+                0.,
+                0.1,
+                1.,
+                0.01,
+            );
+            let mut buffer = vec![0.; RATE as usize / 10];
+            voice.process(&mut buffer);
+            buffer[buffer.len() / 2..]
+                .iter()
+                .fold(0f32, |peak, x| peak.max(x.abs()))
+        };
+
+        // 440hz is two octaves over a cutoff of 110hz, where a single stage has cut it by 24db.
+        assert!((quietest(10000.) - 1.).abs() < 0.02);
+        assert!(quietest(110.) < 0.07);
+    }
+
+    #[test]
+    fn modulating_the_cutoff() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        let lfo = Lfo::new(&mut rng, RATE, Shape::Sine, Rate::Hertz(2.));
+        let mut voice = Modulated::new(Filtered::new(Sample::middle_a(RATE), low_pass(1000.)))
+            .with_modulator(Modulator::Lfo(lfo))
+            .with_route(0, Destination::Effect(0), 800.)
+            .unwrap();
+
+        let mut cutoffs = Vec::new();
+        for _ in 0..RATE as usize / 2 {
+            voice.next();
+            cutoffs.push(voice.parameter(Parameter::Effect(0)).unwrap());
+        }
+        let lowest = cutoffs.iter().cloned().fold(f32::MAX, f32::min);
+        let highest = cutoffs.iter().cloned().fold(0., f32::max);
+        assert!(lowest < 300. && highest > 1700.);
+    }
+
+    #[test]
+    fn blocks_match_samples() {
+        let voice = || Filtered::new(Sample::middle_a(RATE), low_pass(800.));
+        let mut per_sample = voice();
+        let mut block = voice();
+        let mut buffer = [0.; 300];
+        block.process(&mut buffer);
+        for value in buffer {
+            assert_eq!(per_sample.next(), value);
+        }
+    }
+
+    #[test]
+    fn cutoff_follows_key_and_envelope() {
+        let mut cutoff = Cutoff::new(RATE, 1000.).with_key_tracking(72, 1.);
+        assert!((cutoff.next() - 2000.).abs() < 0.01);
+        let mut cutoff = Cutoff::new(RATE, 1000.).with_key_tracking(48, 0.5);
+        assert!((cutoff.next() - 1000. * 0.5f32.sqrt()).abs() < 0.01);
+
+        let envelope = Envelope::gated(RATE, 0.01, 1., 0.01, 0.5, 0.01);
+        let mut cutoff = Cutoff::new(RATE, 1000.).with_envelope(envelope, 2.);
+        let peak = (0..RATE as usize / 10)
+            .map(|_| cutoff.next())
+            .fold(0., f32::max);
+        assert!((peak - 4000.).abs() < 1.);
+        assert!((cutoff.next() - 2000.).abs() < 1.);
+
+        cutoff.release();
+        for _ in 0..RATE as usize / 10 {
+            cutoff.next();
+        }
+        assert_eq!(cutoff.next(), 1000.);
+
+        // The cutoff never reaches the nyquist frequency.
+        let mut cutoff = Cutoff::new(RATE, 20000.).with_key_tracking(96, 1.);
+        assert!(cutoff.next() < RATE / 2.);
+        assert!(cutoff.next_gain().is_finite());
+    }
+}
@@ -0,0 +1,432 @@
+/**
+ * Frequency modulation synthesis in the style of the Yamaha DX and TX series. A voice is a set of
+ * sine operators, each with its own frequency ratio, detune, output level and envelope. The
+ * algorithm decides which operators modulate the phase of which others and which are heard.
+ */
+use crate::envelope::Envelope;
+use crate::sample::{advance, sine};
+use std::error::Error;
+
+/// A single sine oscillator in an fm voice.
+#[derive(Debug)]
+pub struct Operator {
+    // The operator frequency as a multiple of the voice frequency.
+    ratio: f32,
+
+    // A fixed offset (in hz) added to the operator frequency.
+    detune: f32,
+
+    // The peak output of the operator. For modulators this is the modulation index in radians.
+    level: f32,
+
+    // The amplitude contour of the operator output.
+    envelope: Envelope,
+
+    phase: f32,
+
+    // The last two outputs of the operator, used when it feeds back into itself.
+    history: [f32; 2],
+}
+
+impl Operator {
+    pub fn new(ratio: f32, detune: f32, level: f32, envelope: Envelope) -> Self {
+        Operator {
+            ratio,
+            detune,
+            level,
+            envelope,
+            phase: 0.,
+            history: [0.; 2],
+        }
+    }
+
+    /// Advance the operator by one sample with the given phase modulation (in radians).
+    fn next(&mut self, frequency: f32, rate: f32, modulation: f32) -> f32 {
+        let phase = advance(&mut self.phase, frequency * self.ratio + self.detune, rate);
+        let modulation = modulation / (2. * std::f32::consts::PI);
+        let output = sine(phase + modulation) * self.level * self.envelope.next();
+        self.history = [output, self.history[0]];
+        output
+    }
+}
+
+/// Describes how the operators of a voice are connected. Operators are numbered from zero and an
+/// operator may only be modulated by operators with a higher number, so every sample can be
+/// computed in a single pass from the last operator to the first.
+#[derive(Debug, Clone)]
+pub struct Algorithm {
+    // Pairs of (modulator, modulated) operator indices.
+    connections: Vec<(usize, usize)>,
+
+    // The operators whose output is heard.
+    carriers: Vec<usize>,
+
+    // The operator that modulates itself with its own previous output, if any.
+    feedback: Option<usize>,
+}
+
+impl Algorithm {
+    pub fn new(
+        connections: Vec<(usize, usize)>,
+        carriers: Vec<usize>,
+        feedback: Option<usize>,
+    ) -> Result<Self, Box<dyn Error>> {
+        if carriers.is_empty() {
+            return Err("an algorithm needs at least one carrier".into());
+        }
+
+        if connections.iter().any(|(from, to)| from <= to) {
+            return Err("operators may only be modulated by higher numbered operators".into());
+        }
+
+        Ok(Algorithm {
+            connections,
+            carriers,
+            feedback,
+        })
+    }
+
+    /// One of the eight four operator algorithms. Operator 3 always has feedback.
+    ///
+    /// 1: 3 -> 2 -> 1 -> 0
+    /// 2: (2 + 3) -> 1 -> 0
+    /// 3: (1 + (3 -> 2)) -> 0
+    /// 4: ((3 -> 1) + 2) -> 0
+    /// 5: (1 -> 0) + (3 -> 2)
+    /// 6: 3 -> (0 + 1 + 2)
+    /// 7: (3 -> 2) + 1 + 0
+    /// 8: 0 + 1 + 2 + 3
+    pub fn four_operator(number: usize) -> Result<Self, Box<dyn Error>> {
+        let (connections, carriers) = match number {
+            1 => (vec![(3, 2), (2, 1), (1, 0)], vec![0]),
+            2 => (vec![(3, 1), (2, 1), (1, 0)], vec![0]),
+            3 => (vec![(3, 2), (2, 0), (1, 0)], vec![0]),
+            4 => (vec![(3, 1), (1, 0), (2, 0)], vec![0]),
+            5 => (vec![(1, 0), (3, 2)], vec![0, 2]),
+            6 => (vec![(3, 0), (3, 1), (3, 2)], vec![0, 1, 2]),
+            7 => (vec![(3, 2)], vec![0, 1, 2]),
+            8 => (vec![], vec![0, 1, 2, 3]),
+            n => return Err(format!("there is no four operator algorithm {}", n).into()),
+        };
+        Self::new(connections, carriers, Some(3))
+    }
+
+    /// The number of operators a voice needs to play this algorithm.
+    fn operators(&self) -> usize {
+        self.connections
+            .iter()
+            .map(|(from, _)| *from)
+            .chain(self.carriers.iter().cloned())
+            .chain(self.feedback.iter().cloned())
+            .max()
+            .map(|x| x + 1)
+            .unwrap_or(0)
+    }
+}
+
+/// A complete fm voice: a set of operators wired together by an algorithm.
+#[derive(Debug)]
+pub struct FmVoice {
+    operators: Vec<Operator>,
+    algorithm: Algorithm,
+
+    // How strongly the feedback operator modulates itself (in radians).
+    feedback: f32,
+
+    // The output of each operator in the current sample.
+    outputs: Vec<f32>,
+
+    rate: f32,
+    frequency: f32,
+}
+
+impl FmVoice {
+    pub fn new(
+        operators: Vec<Operator>,
+        algorithm: Algorithm,
+        feedback: f32,
+        rate: f32,
+        frequency: f32,
+    ) -> Result<Self, Box<dyn Error>> {
+        if operators.len() < algorithm.operators() {
+            return Err(format!(
+                "the algorithm needs {} operators but only {} were given",
+                algorithm.operators(),
+                operators.len()
+            )
+            .into());
+        }
+
+        Ok(FmVoice {
+            outputs: vec![0.; operators.len()],
+            operators,
+            algorithm,
+            feedback,
+            rate,
+            frequency,
+        })
+    }
+
+    pub fn frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    pub fn set_frequency(&mut self, frequency: f32) {
+        self.frequency = frequency;
+    }
+
+    pub fn next(&mut self) -> f32 {
+        for index in (0..self.operators.len()).rev() {
+            let mut modulation = 0.;
+
+            for (from, to) in &self.algorithm.connections {
+                if *to == index {
+                    modulation += self.outputs[*from];
+                }
+            }
+
+            // Averaging the last two outputs keeps heavy feedback from breaking into oscillation
+            // at nyquist.
+            if self.algorithm.feedback == Some(index) {
+                let history = self.operators[index].history;
+                modulation += self.feedback * (history[0] + history[1]) / 2.;
+            }
+
+            self.outputs[index] = self.operators[index].next(self.frequency, self.rate, modulation);
+        }
+
+        let total: f32 = self
+            .algorithm
+            .carriers
+            .iter()
+            .map(|carrier| self.outputs[*carrier])
+            .sum();
+        total / self.algorithm.carriers.len() as f32
+    }
+
+    /// Release the envelope of every operator.
+    pub fn release(&mut self) {
+        for operator in self.operators.iter_mut() {
+            operator.envelope.release();
+        }
+    }
+
+    /// The voice is finished once every carrier has gone silent.
+    pub fn finished(&self) -> bool {
+        self.algorithm
+            .carriers
+            .iter()
+            .all(|carrier| self.operators[*carrier].envelope.finished())
+    }
+
+    /// A struck metallic bell built from inharmonic modulator ratios and a long decay.
+    pub fn bell(rate: f32, frequency: f32) -> Self {
+        let operators = vec![
+            Operator::new(1., 0., 1., Envelope::new(rate, 0.001, 1., 4., 0., 0., 0.1)),
+            Operator::new(
+                3.5,
+                0.,
+                2.5,
+                Envelope::new(rate, 0.001, 1., 2., 0., 0., 0.1),
+            ),
+            Operator::new(1., 1.5, 1., Envelope::new(rate, 0.001, 1., 3., 0., 0., 0.1)),
+            Operator::new(
+                5.19,
+                0.,
+                1.5,
+                Envelope::new(rate, 0.001, 1., 1., 0., 0., 0.1),
+            ),
+        ];
+        Self::new(
+            operators,
+            Algorithm::four_operator(5).unwrap(),
+            0.,
+            rate,
+            frequency,
+        )
+        .unwrap()
+    }
+
+    /// A tine electric piano: a soft fundamental pair plus a bright, quickly decaying attack pair.
+    pub fn electric_piano(rate: f32, frequency: f32) -> Self {
+        let operators = vec![
+            Operator::new(
+                1.,
+                0.,
+                1.,
+                Envelope::new(rate, 0.002, 1., 1.5, 0.5, 0.4, 0.4),
+            ),
+            Operator::new(
+                1.,
+                0.,
+                1.2,
+                Envelope::new(rate, 0.002, 1., 1., 0.5, 0.3, 0.4),
+            ),
+            Operator::new(
+                1.,
+                2.,
+                0.4,
+                Envelope::new(rate, 0.002, 1., 0.4, 0.2, 0.1, 0.2),
+            ),
+            Operator::new(
+                14.,
+                0.,
+                0.8,
+                Envelope::new(rate, 0.001, 1., 0.15, 0., 0., 0.1),
+            ),
+        ];
+        Self::new(
+            operators,
+            Algorithm::four_operator(5).unwrap(),
+            0.,
+            rate,
+            frequency,
+        )
+        .unwrap()
+    }
+
+    /// A punchy bass from a stack with feedback on the top operator.
+    pub fn bass(rate: f32, frequency: f32) -> Self {
+        let operators = vec![
+            Operator::new(
+                1.,
+                0.,
+                1.,
+                Envelope::new(rate, 0.002, 1., 0.3, 0.5, 0.7, 0.1),
+            ),
+            Operator::new(
+                1.,
+                0.,
+                1.5,
+                Envelope::new(rate, 0.002, 1., 0.2, 0.5, 0.4, 0.1),
+            ),
+            Operator::new(
+                2.,
+                0.,
+                0.8,
+                Envelope::new(rate, 0.002, 1., 0.1, 0.5, 0.2, 0.1),
+            ),
+            Operator::new(
+                1.,
+                0.,
+                0.6,
+                Envelope::new(rate, 0.002, 1., 0.1, 0.5, 0.3, 0.1),
+            ),
+        ];
+        Self::new(
+            operators,
+            Algorithm::four_operator(1).unwrap(),
+            0.8,
+            rate,
+            frequency,
+        )
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod fm_tests {
+    use super::{Algorithm, FmVoice, Operator};
+    use crate::envelope::Envelope;
+    use crate::fft::RealFft;
+    use crate::sample::Sample;
+
+    const RATE: f32 = 44100.;
+
+    /// An envelope that jumps straight to full level and holds there.
+    fn held() -> Envelope {
+        Envelope::new(RATE, 0., 1., 0., 100., 1., 0.)
+    }
+
+    #[test]
+    fn single_carrier_is_a_sine() {
+        let mut voice = FmVoice::new(
+            vec![Operator::new(1., 0., 1., held())],
+            Algorithm::new(vec![], vec![0], None).unwrap(),
+            0.,
+            RATE,
+            440.,
+        )
+        .unwrap();
+
+        for i in 0..1000 {
+            let expected = (2. * std::f32::consts::PI * 440. * i as f32 / RATE).sin();
+            assert!((voice.next() - expected).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn modulation_adds_sidebands() {
+        // A 1000hz carrier modulated at 3000hz has sidebands at 4000hz and (folded) 2000hz.
+        let mut voice = FmVoice::new(
+            vec![
+                Operator::new(1., 0., 1., held()),
+                Operator::new(3., 0., 1., held()),
+            ],
+            Algorithm::new(vec![(1, 0)], vec![0], None).unwrap(),
+            0.,
+            RATE,
+            1000.,
+        )
+        .unwrap();
+
+        let data: Vec<f64> = (0..8192).map(|_| voice.next() as f64).collect();
+        let mut fft = RealFft::new(16384, RATE as f64).unwrap();
+        let spectrum = fft.run(&data).unwrap();
+
+        let amplitude_at = |frequency: f64| {
+            spectrum
+                .iter()
+                .filter(|(f, _)| (f - frequency).abs() < 20.)
+                .map(|(_, a)| *a)
+                .fold(0., f64::max)
+        };
+
+        assert!(amplitude_at(4000.) > 0.1);
+        assert!(amplitude_at(2000.) > 0.1);
+        assert!(amplitude_at(3000.) < 0.01);
+    }
+
+    #[test]
+    fn feedback_is_bounded() {
+        let mut voice = FmVoice::bass(RATE, 55.);
+        for _ in 0..RATE as usize {
+            assert!(voice.next().abs() <= 1.);
+        }
+    }
+
+    #[test]
+    fn invalid_algorithms() {
+        assert!(Algorithm::new(vec![(0, 1)], vec![0], None).is_err());
+        assert!(Algorithm::new(vec![], vec![], None).is_err());
+        assert!(Algorithm::four_operator(9).is_err());
+        assert!(FmVoice::new(
+            vec![Operator::new(1., 0., 1., held())],
+            Algorithm::four_operator(1).unwrap(),
+            0.,
+            RATE,
+            440.
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn presets_finish() {
+        let mut bell = FmVoice::bell(RATE, 880.);
+        for _ in 0..5 * RATE as usize {
+            bell.next();
+        }
+        assert!(bell.finished());
+    }
+
+    #[test]
+    fn plays_as_a_sample() {
+        let mut sample = Sample::Fm(FmVoice::electric_piano(RATE, 440.));
+        assert_eq!(sample.frequency(), 440.);
+        sample.set_frequency(220.);
+        assert_eq!(sample.frequency(), 220.);
+
+        let peak = (0..RATE as usize / 10).fold(0f32, |peak, _| peak.max(sample.next().abs()));
+        assert!(peak > 0.1);
+    }
+}
@@ -0,0 +1,286 @@
+/**
+ * A Karplus-Strong plucked string. A delay line one period long is filled with a burst of noise and
+ * fed back into itself through a gentle lowpass, so the high harmonics die away first as they do on
+ * a real string. A first order allpass in the loop supplies the fractional part of the period so
+ * the string is in tune at any frequency.
+ */
+use rand::{distributions::uniform::Uniform, rngs::SmallRng, Rng};
+use std::error::Error;
+
+/// The level (relative to full scale) below which a string is considered to have died out.
+const SILENCE: f32 = 0.001;
+
+/// The smallest fractional delay asked of the allpass. Below this the allpass coefficient
+/// approaches one and the tuning becomes sensitive to rounding.
+const MIN_FRACTION: f32 = 0.1;
+
+#[derive(Debug)]
+pub struct KarplusStrong {
+    // A circular buffer holding the travelling wave. It is longer than one period so the string can
+    // be retuned down by up to an octave without reallocating.
+    delay: Vec<f32>,
+    write: usize,
+
+    // The whole number of samples read back from the delay line each period.
+    length: usize,
+
+    // The allpass coefficient and state that supply the fractional part of the period.
+    allpass: f32,
+    allpass_input: f32,
+    allpass_output: f32,
+
+    // The weight given to the previous sample by the loop lowpass. 0.5 is the classic two point
+    // average and 0 leaves the loop unfiltered.
+    smoothing: f32,
+    previous: f32,
+
+    // The fraction of the level lost each time the wave travels around the loop.
+    damping: f32,
+
+    rate: f32,
+    frequency: f32,
+
+    // The loudest sample seen since the start of the current period and the count of samples
+    // until the period ends.
+    period_peak: f32,
+    period_remaining: usize,
+    finished: bool,
+}
+
+impl KarplusStrong {
+    /// Pluck a string. `damping` is the fraction of level lost each period (0 rings forever) and
+    /// `brightness` (0 to 1) sets how slowly the high harmonics fade relative to the fundamental.
+    /// The frequency must be between zero and half the sample rate.
+    pub fn new(
+        rng: &mut SmallRng,
+        rate: f32,
+        frequency: f32,
+        damping: f32,
+        brightness: f32,
+    ) -> Result<Self, Box<dyn Error>> {
+        if !(frequency > 0. && frequency < rate / 2.) {
+            return Err("a string must be tuned between zero and half the sample rate".into());
+        }
+
+        let capacity = (2. * rate / frequency).ceil() as usize + 4;
+        let noise = Uniform::new(-1., 1.);
+        let mut delay: Vec<f32> = (0..capacity).map(|_| rng.sample(noise)).collect();
+
+        // Remove any offset from the burst so the string settles around zero.
+        let mean = delay.iter().sum::<f32>() / capacity as f32;
+        for value in delay.iter_mut() {
+            *value -= mean;
+        }
+
+        let mut string = KarplusStrong {
+            delay,
+            write: 0,
+            length: 0,
+            allpass: 0.,
+            allpass_input: 0.,
+            allpass_output: 0.,
+            smoothing: 0.5 * (1. - brightness.clamp(0., 1.)),
+            previous: 0.,
+            damping: damping.clamp(0., 1.),
+            rate,
+            frequency,
+            period_peak: 0.,
+            period_remaining: 0,
+            finished: false,
+        };
+        string.tune();
+        string.period_remaining = string.length;
+        Ok(string)
+    }
+
+    /// Split the period into a whole number of delay samples and a fractional allpass delay.
+    fn tune(&mut self) {
+        // The lowpass delays the loop by `smoothing` samples at low frequencies.
+        let period = self.rate / self.frequency - self.smoothing;
+        let max_length = self.delay.len() - 1;
+        let length = ((period - MIN_FRACTION).floor() as usize)
+            .max(1)
+            .min(max_length);
+        let fraction = period - length as f32;
+
+        self.length = length;
+        self.allpass = (1. - fraction) / (1. + fraction);
+    }
+
+    pub fn frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    /// Retune the string. It can be bent down by up to an octave below the plucked frequency and
+    /// up to just below half the sample rate; frequencies outside that range are clamped.
+    pub fn set_frequency(&mut self, frequency: f32) {
+        let lowest = self.rate / (self.delay.len() - 1) as f32;
+        self.frequency = frequency.clamp(lowest, self.rate / 2. - 1.);
+        self.tune();
+    }
+
+    pub fn next(&mut self) -> f32 {
+        let capacity = self.delay.len();
+        let read = (self.write + capacity - self.length) % capacity;
+        let output = self.delay[read];
+
+        let filtered = (1. - self.smoothing) * output + self.smoothing * self.previous;
+        self.previous = output;
+
+        let damped = filtered * (1. - self.damping);
+
+        let tuned = self.allpass * damped + self.allpass_input - self.allpass * self.allpass_output;
+        self.allpass_input = damped;
+        self.allpass_output = tuned;
+
+        self.delay[self.write] = tuned;
+        self.write = (self.write + 1) % capacity;
+
+        // Check once per period whether the string has died out.
+        self.period_peak = self.period_peak.max(output.abs());
+        self.period_remaining = self.period_remaining.saturating_sub(1);
+        if self.period_remaining == 0 {
+            self.finished = self.period_peak < SILENCE;
+            self.period_peak = 0.;
+            self.period_remaining = self.length;
+        }
+
+        output
+    }
+
+    /// Returns true once the string has decayed below audibility.
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+}
+
+#[cfg(test)]
+mod karplus_tests {
+    use super::KarplusStrong;
+    use crate::sample::Sample;
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    const RATE: f32 = 44100.;
+
+    /// Estimate the period of a signal from the peak of its autocorrelation, refined by fitting a
+    /// parabola through the peak and its neighbours.
+    fn period(signal: &[f32], min_lag: usize, max_lag: usize) -> f32 {
+        let correlation = |lag: usize| -> f32 {
+            (0..signal.len() - max_lag - 1)
+                .map(|i| signal[i] * signal[i + lag])
+                .sum()
+        };
+
+        let best = (min_lag..max_lag)
+            .max_by(|a, b| correlation(*a).partial_cmp(&correlation(*b)).unwrap())
+            .unwrap();
+
+        let (left, centre, right) = (
+            correlation(best - 1),
+            correlation(best),
+            correlation(best + 1),
+        );
+        best as f32 + 0.5 * (left - right) / (left - 2. * centre + right)
+    }
+
+    #[test]
+    fn tuned_to_fractional_periods() {
+        for frequency in [440., 523.25, 97.] {
+            let mut string =
+                KarplusStrong::new(&mut SmallRng::seed_from_u64(1), RATE, frequency, 0.01, 0.5)
+                    .unwrap();
+
+            // Let the burst settle into the string's modes before measuring.
+            for _ in 0..4410 {
+                string.next();
+            }
+
+            let signal: Vec<f32> = (0..8192).map(|_| string.next()).collect();
+            let expected = RATE / frequency;
+            let measured = period(&signal, expected as usize - 5, expected as usize + 5);
+            assert!(
+                (measured - expected).abs() < 0.1,
+                "{}hz: measured {} expected {}",
+                frequency,
+                measured,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn dies_out_and_finishes() {
+        let mut string =
+            KarplusStrong::new(&mut SmallRng::seed_from_u64(2), RATE, 220., 0.02, 0.5).unwrap();
+
+        let mut samples = 0;
+        while !string.finished() {
+            string.next();
+            samples += 1;
+            assert!(samples < 10 * RATE as usize, "string never finished");
+        }
+
+        for _ in 0..1000 {
+            assert!(string.next().abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn damping_shortens_the_note() {
+        let ring_time = |damping: f32| {
+            let mut string =
+                KarplusStrong::new(&mut SmallRng::seed_from_u64(3), RATE, 220., damping, 0.5)
+                    .unwrap();
+            let mut samples = 0;
+            while !string.finished() {
+                string.next();
+                samples += 1;
+            }
+            samples
+        };
+
+        assert!(ring_time(0.05) < ring_time(0.01));
+    }
+
+    #[test]
+    fn plucks_are_reproducible() {
+        let pluck = || {
+            let mut string =
+                KarplusStrong::new(&mut SmallRng::seed_from_u64(4), RATE, 330., 0.01, 0.8).unwrap();
+            (0..1000).map(|_| string.next()).collect::<Vec<f32>>()
+        };
+        assert_eq!(pluck(), pluck());
+    }
+
+    #[test]
+    fn rejects_untunable_frequencies() {
+        let mut rng = SmallRng::seed_from_u64(5);
+        assert!(KarplusStrong::new(&mut rng, RATE, 0., 0.01, 0.5).is_err());
+        assert!(KarplusStrong::new(&mut rng, RATE, -220., 0.01, 0.5).is_err());
+        assert!(KarplusStrong::new(&mut rng, RATE, RATE / 2., 0.01, 0.5).is_err());
+        assert!(Sample::pluck(&mut rng, RATE, 30000., 0.01, 0.5).is_err());
+    }
+
+    #[test]
+    fn plays_as_a_sample() {
+        let mut sample =
+            Sample::pluck(&mut SmallRng::seed_from_u64(6), RATE, 220., 0.05, 0.5).unwrap();
+        assert_eq!(sample.frequency(), 220.);
+
+        let buffer: Vec<f32> = (0..RATE as usize).map(|_| sample.next()).collect();
+        assert!(buffer[..100].iter().any(|x| x.abs() > 0.1));
+        assert!(sample.finished());
+    }
+
+    #[test]
+    fn retuning_stays_below_nyquist() {
+        let mut string =
+            KarplusStrong::new(&mut SmallRng::seed_from_u64(7), RATE, 220., 0.01, 0.5).unwrap();
+
+        string.set_frequency(RATE);
+        assert!(string.frequency() < RATE / 2.);
+        string.set_frequency(-100.);
+        assert!(string.frequency() > 0.);
+        assert!((0..1000).map(|_| string.next()).all(f32::is_finite));
+    }
+}
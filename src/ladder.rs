@@ -0,0 +1,201 @@
+/**
+ * A model of the four pole transistor ladder filter of Moog synthesizers. Each pole is a
+ * trapezoidal integrator and the feedback around the ladder is solved for each sample rather than
+ * delayed by one, following Zavalishin's "The Art of VA Filter Design", so the cutoff keeps its
+ * place and the filter stays stable however fast the cutoff and resonance change.
+ *
+ * The input to the ladder is saturated like the transistors of the original. As the resonance
+ * passes one the filter rings on its own, and the saturation holds the ringing at a steady level.
+ */
+use crate::envelope::Envelope;
+use crate::filter::{Cutoff, Filter, MAX_RESONANCE};
+use std::error::Error;
+
+/// A resonant low pass falling off 24db per octave.
+///
+/// Parameters: 0 is the cutoff in hz before key tracking and the envelope, 1 the resonance from 0
+/// to `MAX_RESONANCE` and 2 the drive.
+#[derive(Debug)]
+pub struct Ladder {
+    cutoff: Cutoff,
+    resonance: f32,
+
+    // How hard the input is pushed into the saturation. Like the original, the passband gets
+    // quieter as the resonance rises.
+    drive: f32,
+
+    // The state of the integrator of each pole.
+    state: [f32; 4],
+}
+
+impl Ladder {
+    pub fn new(sample_rate: f32, frequency: f32, resonance: f32) -> Result<Self, Box<dyn Error>> {
+        if !(frequency > 0. && frequency < sample_rate / 2.) {
+            return Err("a filter cutoff must be between zero and half the sample rate".into());
+        }
+        if !(0. ..=MAX_RESONANCE).contains(&resonance) {
+            return Err(format!("a resonance must be between 0 and {}", MAX_RESONANCE).into());
+        }
+
+        Ok(Ladder {
+            cutoff: Cutoff::new(sample_rate, frequency),
+            resonance,
+            drive: 1.,
+            state: [0.; 4],
+        })
+    }
+
+    /// Follow the key of a midi note by a fraction of its distance from middle c.
+    pub fn with_key_tracking(self, note: u8, amount: f32) -> Self {
+        Ladder {
+            cutoff: self.cutoff.with_key_tracking(note, amount),
+            ..self
+        }
+    }
+
+    /// Open the cutoff by an envelope, by `octaves` at a level of one.
+    pub fn with_envelope(self, envelope: Envelope, octaves: f32) -> Self {
+        Ladder {
+            cutoff: self.cutoff.with_envelope(envelope, octaves),
+            ..self
+        }
+    }
+
+    /// Scale the input before it is saturated. The default of one only saturates loud signals.
+    pub fn with_drive(self, drive: f32) -> Self {
+        Ladder { drive, ..self }
+    }
+}
+
+impl Filter for Ladder {
+    fn next(&mut self, input: f32) -> f32 {
+        let g = self.cutoff.next_gain();
+        let gain = g / (1. + g);
+        let feedback = 4. * self.resonance;
+
+        // Each pole outputs `gain` times its input plus a part of its state, so the whole ladder
+        // outputs gain^4 times its input plus what its states add up to.
+        let stored = self
+            .state
+            .iter()
+            .fold(0., |stored, state| stored * gain + state)
+            * (1. - gain);
+        let solved = (input * self.drive - feedback * stored) / (1. + feedback * gain.powi(4));
+
+        let mut value = solved.tanh();
+        for state in self.state.iter_mut() {
+            let step = (value - *state) * gain;
+            value = step + *state;
+            *state = value + step;
+        }
+        value
+    }
+
+    fn parameter(&self, index: usize) -> Option<f32> {
+        match index {
+            0 => Some(self.cutoff.frequency()),
+            1 => Some(self.resonance),
+            2 => Some(self.drive),
+            _ => None,
+        }
+    }
+
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match index {
+            0 => self.cutoff.set_frequency(value),
+            1 => self.resonance = value.clamp(0., MAX_RESONANCE),
+            2 => self.drive = value.max(0.),
+            _ => {}
+        }
+    }
+
+    fn release(&mut self) {
+        self.cutoff.release();
+    }
+}
+
+#[cfg(test)]
+mod ladder_tests {
+    use super::Ladder;
+    use crate::filter::Filter;
+    use std::f32::consts::PI;
+
+    const RATE: f32 = 44100.;
+
+    /// The gain of a filter at a frequency in decibels, from the levels of a quiet sine wave going
+    /// in and coming out once the filter has settled.
+    fn gain(filter: &mut impl Filter, frequency: f32) -> f32 {
+        let sine =
+            |i: usize| 0.01 * (2. * PI * frequency * (i % RATE as usize) as f32 / RATE).sin();
+        for i in 0..RATE as usize / 10 {
+            filter.next(sine(i));
+        }
+        let (mut dry, mut wet) = (0., 0.);
+        for i in RATE as usize / 10..RATE as usize / 5 {
+            dry += sine(i).powi(2);
+            wet += filter.next(sine(i)).powi(2);
+        }
+        10. * (wet / dry).log10()
+    }
+
+    /// Ring a filter with an impulse and return the second half second of what follows.
+    fn ring(filter: &mut impl Filter) -> Vec<f32> {
+        filter.next(0.5);
+        let tail: Vec<f32> = (0..RATE as usize).map(|_| filter.next(0.)).collect();
+        tail[RATE as usize / 2..].to_vec()
+    }
+
+    #[test]
+    fn falls_off_four_poles() {
+        let mut filter = Ladder::new(RATE, 1000., 0.).unwrap();
+        assert!(gain(&mut filter, 100.).abs() < 0.2);
+
+        // Four poles together are 12db down at the cutoff and fall 24db an octave well past it.
+        assert!((gain(&mut filter, 1000.) + 12.04).abs() < 0.3);
+        let slope = gain(&mut filter, 4000.) - gain(&mut filter, 8000.);
+        assert!(slope > 22. && slope < 30., "{}", slope);
+    }
+
+    #[test]
+    fn resonance_peaks_at_the_cutoff() {
+        let mut filter = Ladder::new(RATE, 1000., 0.9).unwrap();
+        let peak = gain(&mut filter, 1000.);
+        assert!(peak > gain(&mut filter, 250.) + 6.);
+        assert!(peak > gain(&mut filter, 4000.) + 12.);
+
+        // Below one the ringing dies away.
+        let tail = ring(&mut filter);
+        assert!(tail.iter().all(|x| x.abs() < 1e-3));
+    }
+
+    #[test]
+    fn self_oscillates_at_the_cutoff() {
+        let mut filter = Ladder::new(RATE, 1000., 1.1).unwrap();
+        let tail = ring(&mut filter);
+        let peak = tail.iter().fold(0f32, |peak, x| peak.max(x.abs()));
+        assert!(peak > 0.1 && peak <= 1., "{}", peak);
+
+        let crossings = tail.windows(2).filter(|x| x[0] < 0. && x[1] >= 0.).count();
+        assert!((crossings as f32 - 500.).abs() < 25., "{}", crossings);
+    }
+
+    #[test]
+    fn stable_under_audio_rate_modulation() {
+        let mut filter = Ladder::new(RATE, 1000., 1.2).unwrap().with_drive(4.);
+        for i in 0..RATE as usize {
+            let phase = 2. * PI * (i as f32 / RATE);
+            filter.set_parameter(0, 7000. + 6900. * (500. * phase).sin());
+            filter.set_parameter(1, 0.6 + 0.6 * (300. * phase).sin());
+            let output = filter.next(2. * (55. * phase).sin().signum());
+            assert!(output.is_finite() && output.abs() < 1.5, "{}", output);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(Ladder::new(RATE, 0., 0.5).is_err());
+        assert!(Ladder::new(RATE, 23000., 0.5).is_err());
+        assert!(Ladder::new(RATE, 1000., -0.1).is_err());
+        assert!(Ladder::new(RATE, 1000., 2.).is_err());
+    }
+}
@@ -0,0 +1,349 @@
+/**
+ * Low frequency oscillators for modulation. An lfo reuses the waveforms of `Sample` but runs at
+ * control rate: it is advanced by a whole block of samples at a time and its output is meant to be
+ * routed through a `Modulated` voice to pitch (vibrato), amplitude (tremolo) or pulse width (pwm)
+ * rather than listened to.
+ */
+use crate::sample::{sawtooth, sine, square, triangle};
+use rand::{distributions::uniform::Uniform, rngs::SmallRng, Rng, SeedableRng};
+use std::str::FromStr;
+
+/// The shape of one cycle of an lfo. Every shape runs between -1 and 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Sine,
+    Triangle,
+    Sawtooth,
+    Square,
+
+    // A new random value at the start of each cycle, held until the next.
+    SampleAndHold,
+
+    // A new random value each cycle, glided to smoothly over the course of the cycle.
+    SmoothRandom,
+}
+
+impl FromStr for Shape {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "sine" => Ok(Shape::Sine),
+            "triangle" => Ok(Shape::Triangle),
+            "sawtooth" => Ok(Shape::Sawtooth),
+            "square" => Ok(Shape::Square),
+            "sample-and-hold" => Ok(Shape::SampleAndHold),
+            "smooth-random" => Ok(Shape::SmoothRandom),
+            _ => Err(format!(
+                "unknown lfo shape {}, expected sine, triangle, sawtooth, square, sample-and-hold \
+                 or smooth-random",
+                name
+            )),
+        }
+    }
+}
+
+/// A note length, for lfos locked to a tempo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Division {
+    Whole,
+    Half,
+    Quarter,
+    Eighth,
+    Sixteenth,
+    DottedQuarter,
+    DottedEighth,
+    QuarterTriplet,
+    EighthTriplet,
+}
+
+impl Division {
+    /// The length of the note in beats (quarter notes).
+    pub fn beats(&self) -> f32 {
+        match self {
+            Division::Whole => 4.,
+            Division::Half => 2.,
+            Division::Quarter => 1.,
+            Division::Eighth => 0.5,
+            Division::Sixteenth => 0.25,
+            Division::DottedQuarter => 1.5,
+            Division::DottedEighth => 0.75,
+            Division::QuarterTriplet => 2. / 3.,
+            Division::EighthTriplet => 1. / 3.,
+        }
+    }
+}
+
+impl FromStr for Division {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "whole" => Ok(Division::Whole),
+            "half" => Ok(Division::Half),
+            "quarter" => Ok(Division::Quarter),
+            "eighth" => Ok(Division::Eighth),
+            "sixteenth" => Ok(Division::Sixteenth),
+            "dotted-quarter" => Ok(Division::DottedQuarter),
+            "dotted-eighth" => Ok(Division::DottedEighth),
+            "quarter-triplet" => Ok(Division::QuarterTriplet),
+            "eighth-triplet" => Ok(Division::EighthTriplet),
+            _ => Err(format!(
+                "unknown note length {}, expected whole, half, quarter, eighth, sixteenth, \
+                 dotted-quarter, dotted-eighth, quarter-triplet or eighth-triplet",
+                name
+            )),
+        }
+    }
+}
+
+/// How fast an lfo cycles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rate {
+    // Cycles per second.
+    Hertz(f32),
+
+    // One cycle per note of the division at a tempo in beats per minute.
+    Synced { tempo: f32, division: Division },
+}
+
+impl Rate {
+    pub fn frequency(&self) -> f32 {
+        match self {
+            Rate::Hertz(frequency) => *frequency,
+            Rate::Synced { tempo, division } => tempo / 60. / division.beats(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Lfo {
+    shape: Shape,
+    rate: Rate,
+    sample_rate: f32,
+
+    // Where in the cycle (from 0 to 1) the lfo starts when triggered, and where it is now.
+    phase_offset: f32,
+    phase: f32,
+
+    // The time (in seconds) the lfo takes to grow to full depth after a trigger and the time since
+    // the last trigger.
+    fade_in: f32,
+    elapsed: f32,
+
+    // Whether a trigger restarts the cycle. Without it the lfo runs freely from note to note.
+    retrigger: bool,
+
+    // The random values of the current and previous cycles for the random shapes.
+    rng: SmallRng,
+    current: f32,
+    previous: f32,
+}
+
+impl Lfo {
+    /// A key retriggered lfo starting at the beginning of its cycle with no fade in. The random
+    /// shapes draw their values from a generator seeded from `rng`.
+    pub fn new(rng: &mut SmallRng, sample_rate: f32, shape: Shape, rate: Rate) -> Self {
+        let mut rng = SmallRng::seed_from_u64(rng.gen());
+        let range = Uniform::new(-1., 1.);
+        let previous = rng.sample(range);
+        let current = rng.sample(range);
+
+        Lfo {
+            shape,
+            rate,
+            sample_rate,
+            phase_offset: 0.,
+            phase: 0.,
+            fade_in: 0.,
+            elapsed: 0.,
+            retrigger: true,
+            rng,
+            current,
+            previous,
+        }
+    }
+
+    /// Start each cycle part way through, from 0 to 1.
+    pub fn with_phase(self, offset: f32) -> Self {
+        let offset = offset - offset.floor();
+        Lfo {
+            phase_offset: offset,
+            phase: offset,
+            ..self
+        }
+    }
+
+    /// Grow from nothing to full depth over `seconds` after each trigger.
+    pub fn with_fade_in(self, seconds: f32) -> Self {
+        Lfo {
+            fade_in: seconds,
+            ..self
+        }
+    }
+
+    /// Choose whether a trigger restarts the cycle from the phase offset.
+    pub fn with_retrigger(self, retrigger: bool) -> Self {
+        Lfo { retrigger, ..self }
+    }
+
+    /// Restart the fade in, and the cycle when the lfo is key retriggered, for a new note.
+    pub fn trigger(&mut self) {
+        self.elapsed = 0.;
+        if self.retrigger {
+            self.phase = self.phase_offset;
+        }
+    }
+
+    /// The value at the current phase before the fade in is applied.
+    fn value(&self) -> f32 {
+        match self.shape {
+            Shape::Sine => sine(self.phase),
+            Shape::Triangle => triangle(self.phase),
+            Shape::Sawtooth => sawtooth(self.phase),
+            Shape::Square => square(self.phase, 0.5),
+            Shape::SampleAndHold => self.current,
+            Shape::SmoothRandom => {
+                let t = 0.5 - 0.5 * (std::f32::consts::PI * self.phase).cos();
+                self.previous + (self.current - self.previous) * t
+            }
+        }
+    }
+
+    /// Return the value of the lfo and advance it by a number of samples.
+    pub fn next(&mut self, samples: usize) -> f32 {
+        let fade = if self.elapsed < self.fade_in {
+            self.elapsed / self.fade_in
+        } else {
+            1.
+        };
+        let value = self.value() * fade;
+
+        let seconds = samples as f32 / self.sample_rate;
+        self.elapsed += seconds;
+        self.phase += self.rate.frequency() * seconds;
+
+        // Pick a new random value for each cycle started. Only the last two matter.
+        let cycles = self.phase.floor();
+        if cycles >= 1. {
+            let range = Uniform::new(-1., 1.);
+            if cycles >= 2. {
+                self.current = self.rng.sample(range);
+            }
+            self.previous = self.current;
+            self.current = self.rng.sample(range);
+        }
+        self.phase -= cycles;
+
+        value
+    }
+}
+
+#[cfg(test)]
+mod lfo_tests {
+    use super::{Division, Lfo, Rate, Shape};
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    const RATE: f32 = 1000.;
+
+    fn lfo(shape: Shape, rate: Rate) -> Lfo {
+        Lfo::new(&mut SmallRng::seed_from_u64(1), RATE, shape, rate)
+    }
+
+    #[test]
+    fn waveforms_match_samples() {
+        let mut sine = lfo(Shape::Sine, Rate::Hertz(2.));
+        for i in 0..1000 {
+            let expected = (2. * std::f32::consts::PI * 2. * i as f32 / RATE).sin();
+            assert!((sine.next(1) - expected).abs() < 1e-3, "{}", i);
+        }
+
+        let mut square = lfo(Shape::Square, Rate::Hertz(1.));
+        let values: Vec<f32> = (0..1000).map(|_| square.next(1)).collect();
+        assert_eq!(values[100], -1.);
+        assert_eq!(values[900], 1.);
+    }
+
+    #[test]
+    fn tempo_sync() {
+        let rate = Rate::Synced {
+            tempo: 120.,
+            division: Division::Eighth,
+        };
+        assert_eq!(rate.frequency(), 4.);
+
+        // A quarter note triplet at 90 bpm lasts 4/9 of a second.
+        let triplet = Rate::Synced {
+            tempo: 90.,
+            division: Division::QuarterTriplet,
+        };
+        assert!((triplet.frequency() - 2.25).abs() < 1e-5);
+    }
+
+    #[test]
+    fn control_rate_matches_audio_rate() {
+        let mut audio = lfo(Shape::Triangle, Rate::Hertz(3.)).with_phase(0.25);
+        let mut control = lfo(Shape::Triangle, Rate::Hertz(3.)).with_phase(0.25);
+
+        for _ in 0..100 {
+            let expected = audio.next(1);
+            for _ in 0..31 {
+                audio.next(1);
+            }
+            assert!((control.next(32) - expected).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn fades_in_after_trigger() {
+        let mut lfo = lfo(Shape::Square, Rate::Hertz(1.))
+            .with_phase(0.75)
+            .with_fade_in(0.5);
+
+        assert_eq!(lfo.next(250), 0.);
+        assert_eq!(lfo.next(250), -0.5);
+        assert_eq!(lfo.next(250), -1.);
+
+        lfo.trigger();
+        assert_eq!(lfo.next(1), 0.);
+    }
+
+    #[test]
+    fn retrigger_restarts_the_cycle() {
+        let mut retriggered = lfo(Shape::Sawtooth, Rate::Hertz(1.));
+        let mut free = lfo(Shape::Sawtooth, Rate::Hertz(1.)).with_retrigger(false);
+
+        retriggered.next(300);
+        free.next(300);
+        retriggered.trigger();
+        free.trigger();
+
+        assert_eq!(retriggered.next(1), -1.);
+        assert!((free.next(1) - -0.4).abs() < 1e-4);
+    }
+
+    #[test]
+    fn random_shapes_change_once_per_cycle() {
+        let mut held = lfo(Shape::SampleAndHold, Rate::Hertz(10.));
+        let values: Vec<f32> = (0..1000).map(|_| held.next(1)).collect();
+        let changes = values.windows(2).filter(|x| x[1] != x[0]).count();
+        assert!(changes == 9 || changes == 10, "{}", changes);
+        assert!(values.iter().all(|x| x.abs() <= 1.));
+
+        // The smooth shape glides between the held values without jumps.
+        let mut smooth = lfo(Shape::SmoothRandom, Rate::Hertz(10.));
+        let values: Vec<f32> = (0..1000).map(|_| smooth.next(1)).collect();
+        assert!(values.windows(2).all(|x| (x[1] - x[0]).abs() < 0.05));
+    }
+
+    #[test]
+    fn shapes_and_divisions_by_name() {
+        assert_eq!("smooth-random".parse::<Shape>(), Ok(Shape::SmoothRandom));
+        assert_eq!(
+            "dotted-eighth".parse::<Division>(),
+            Ok(Division::DottedEighth)
+        );
+        assert!("wobble".parse::<Shape>().is_err());
+        assert!("eighth".parse::<Shape>().is_err());
+    }
+}
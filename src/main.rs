@@ -1,50 +1,349 @@
-#![feature(drain_filter)]
 extern crate cpal;
 extern crate num;
 extern crate rand;
 extern crate variant_count;
 
+mod additive;
 mod adsr;
+mod biquad;
 mod complex;
+mod dsp;
+mod effect;
+mod engine;
+mod envelope;
 mod fft;
+mod filter;
+mod fm;
+mod karplus;
+mod ladder;
+mod lfo;
+mod master;
+mod meter;
 mod mixer;
+mod modulation;
+mod noise;
+mod ring;
 mod sample;
+mod sampler;
+mod source;
+mod svf;
 mod ui;
+mod velocity;
+mod wav;
+mod wavetable;
 
 use clap::Parser;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use mixer::Mixer;
+use mixer::{Mixer, PanLaw, StealPolicy, Target};
 use rand::{distributions::uniform::Uniform, rngs::SmallRng, Rng, SeedableRng};
-use sample::Sample;
+use sample::{Sample, Waveform};
 use std::error::Error;
 
+use crate::additive::{Additive, Partial};
 use crate::adsr::Adsr;
-use crate::ui::{Command, LoopState, Note, Ui};
-
+use crate::biquad::{Biquad, Response, BUTTERWORTH_Q};
+use crate::effect::{Delay, Reverb};
+use crate::engine::{Engine, Event};
+use crate::envelope::{Breakpoint, Curve, Envelope};
+use crate::filter::Filtered;
+use crate::fm::FmVoice;
+use crate::ladder::Ladder;
+use crate::lfo::{Division, Lfo, Rate, Shape};
+use crate::master::{Clipper, Limiter, Master};
+use crate::modulation::{Destination, Modulated, Modulator};
+use crate::ring::ring;
+use crate::sampler::{note_frequency, LoopMode, Recording, Sampler};
+use crate::svf::{Output, Svf};
+use crate::ui::{Command, LoopState, Mix, Note, Ui};
+use crate::velocity::{VelocityCurve, VelocityResponse};
+use crate::wavetable::Wavetable;
+
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
 use std::thread;
 
+/// The tempo that tempo synced lfos follow, in beats per minute.
+const TEMPO: f32 = 120.;
+
+/// The most output channels the mixer will fill.
+const MAX_CHANNELS: usize = 8;
+
+/// The level the limiter holds peaks under, just short of full scale so the soft clippers are
+/// barely touched.
+const LIMITER_CEILING: f32 = 0.9;
+
+/// The most voices that can play at once.
+const MAX_VOICES: usize = 16;
+
+/// The most frames rendered at once. Longer blocks asked for by the device are rendered in pieces.
+const MAX_BLOCK: usize = 4096;
+
+/// The most events that can be waiting to go to the audio thread.
+const VOICE_QUEUE: usize = 64;
+
+/// How important it is to keep plucks and fm notes playing when voices are stolen. They are kept
+/// over the other voices that finish by themselves, and held notes are kept over everything.
+const PLUCK_PRIORITY: u8 = 1;
+
+/// How far ahead of the audio thread events from the keyboard are scheduled, in seconds. Being
+/// further ahead than a block lasts means each event lands on a frame a fixed time after the key
+/// was read rather than at the start of whichever block happens to come next.
+const SCHEDULE_AHEAD: f32 = 0.02;
+
+/// How long a stolen voice takes to fade out, in seconds.
+const STEAL_FADE: f32 = 0.005;
+
+/// The room size and damping of the reverb held notes are sent to, both from 0 to 1.
+const REVERB_ROOM: f32 = 0.7;
+const REVERB_DAMPING: f32 = 0.5;
+
+/// The level held notes are sent to the reverb at.
+const NOTE_REVERB: f32 = 0.4;
+
+/// The feedback and mix of the echo on the master, which repeats every dotted eighth.
+const ECHO_FEEDBACK: f32 = 0.3;
+const ECHO_MIX: f32 = 0.15;
+
+/// The number of samples between evaluations of voice modulation.
+const CONTROL_INTERVAL: usize = 32;
+
+/// How the note keys respond to velocity. Soft notes are quieter and fade in more slowly. The curve
+/// can be changed on the command line.
+const NOTE_VELOCITY: VelocityResponse = VelocityResponse::new(VelocityCurve::Soft)
+    .with_level(0.7)
+    .with_attack(1.5);
+
+/// How plucks respond to velocity. Soft plucks are quieter and duller.
+const PLUCK_VELOCITY: VelocityResponse = VelocityResponse::new(VelocityCurve::Linear)
+    .with_level(0.6)
+    .with_brightness(0.8);
+
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
 #[clap(about, version, author)]
 struct Args {
     #[clap(help = "seed argument for output")]
     seed: i64,
+
+    #[clap(
+        long,
+        default_value = "sine",
+        help = "waveform of the note keys: sine, sawtooth, square or triangle"
+    )]
+    waveform: Waveform,
+
+    #[clap(long, help = "wav file to play on the note keys instead of sine waves")]
+    sample: Option<String>,
+
+    #[clap(
+        long,
+        default_value = "60",
+        help = "midi note the wav file was recorded at"
+    )]
+    root_note: u8,
+
+    #[clap(
+        long,
+        default_value = "0",
+        help = "number of frames of the wav file to skip before playing it"
+    )]
+    offset: usize,
+
+    #[clap(
+        long,
+        default_value = "off",
+        help = "how the wav file loops while a note is held: off, forward or ping-pong"
+    )]
+    loop_mode: LoopMode,
+
+    #[clap(
+        long,
+        default_value = "0",
+        help = "first frame of the loop in the wav file"
+    )]
+    loop_start: usize,
+
+    #[clap(
+        long,
+        help = "frame the loop in the wav file ends before, the end of the file if not given"
+    )]
+    loop_end: Option<usize>,
+
+    #[clap(long, help = "wav file holding one cycle to play as a wavetable")]
+    wavetable: Option<String>,
+
+    #[clap(
+        long,
+        default_value = "triangle",
+        help = "shape of the tremolo on the note keys: sine, triangle, sawtooth, square, \
+                sample-and-hold or smooth-random"
+    )]
+    tremolo_shape: Shape,
+
+    #[clap(
+        long,
+        default_value = "quarter",
+        help = "note length of one tremolo cycle: whole, half, quarter, eighth, sixteenth, \
+                dotted-quarter, dotted-eighth, quarter-triplet or eighth-triplet"
+    )]
+    tremolo_division: Division,
+
+    #[clap(
+        long,
+        default_value = "soft",
+        help = "how the note keys respond to velocity: linear, soft, hard, power:<exponent> or \
+                fixed:<response>"
+    )]
+    velocity_curve: VelocityCurve,
+
+    #[clap(
+        long,
+        default_value = "constant-power",
+        help = "how voices are panned between speakers: linear, constant-power or compromise"
+    )]
+    pan_law: PanLaw,
+
+    #[clap(
+        long,
+        default_value = "quietest",
+        help = "which voice gives way when too many play: oldest, quietest, same-note or \
+                lowest-priority"
+    )]
+    steal_policy: StealPolicy,
+
+    #[clap(
+        long,
+        default_value = "low-pass",
+        help = "filter the pulse key plays through: low-pass, high-pass, band-pass, notch, \
+                all-pass, peak, low-shelf or high-shelf"
+    )]
+    pulse_filter: Response,
+
+    #[clap(
+        long,
+        default_value = "tanh",
+        help = "how the mix is kept within full scale: hard, tanh or cubic"
+    )]
+    clip: Clipper,
+
+    #[clap(long, help = "scale the mix down as more voices play")]
+    normalise: bool,
+
+    #[clap(long, help = "turn off the look-ahead limiter")]
+    no_limiter: bool,
+}
+
+/// How the voices are played, as chosen on the command line.
+#[derive(Debug, Clone, Copy)]
+pub struct Voicing {
+    // The waveform of the note keys when no recording is played.
+    waveform: Waveform,
+
+    // The number of frames of the recording to skip before playing it.
+    offset: usize,
+
+    // The shape and length of a cycle of the tremolo on the note keys.
+    tremolo_shape: Shape,
+    tremolo_division: Division,
+
+    // How the note keys respond to velocity.
+    velocity: VelocityResponse,
+
+    // How voices are panned between the speakers.
+    pan_law: PanLaw,
+
+    // Which voice gives way when too many voices play at once.
+    steal_policy: StealPolicy,
+
+    // The response of the swept filter on the pulse key.
+    pulse_filter: Response,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
     let seed = args.seed;
+    let voicing = Voicing {
+        waveform: args.waveform,
+        offset: args.offset,
+        tremolo_shape: args.tremolo_shape,
+        tremolo_division: args.tremolo_division,
+        velocity: NOTE_VELOCITY.with_curve(args.velocity_curve),
+        pan_law: args.pan_law,
+        steal_policy: args.steal_policy,
+        pulse_filter: args.pulse_filter,
+    };
+
+    // The wavetable key plays a single cycle loaded from a file, or a bright organ-like cycle
+    // built from its harmonics when no file is given.
+    let tables = Arc::new(vec![match &args.wavetable {
+        Some(path) => Wavetable::from_wav(path)?,
+        None => Wavetable::from_harmonics(&[1., 0.5, 0.33, 0.25, 0.2, 0.17, 0.14, 0.12])?,
+    }]);
+
+    let recording = match &args.sample {
+        Some(path) => {
+            let recording = Recording::from_wav(path)?.with_root_note(args.root_note);
+            let recording = match args.loop_mode {
+                LoopMode::Off => recording,
+                mode => {
+                    let end = args.loop_end.unwrap_or(recording.len());
+                    recording.with_loop(mode, args.loop_start, end)?
+                }
+            };
+            Some(Arc::new(recording))
+        }
+        None => None,
+    };
 
     let host = cpal::default_host();
     let device = host.default_output_device().ok_or("no device found")?;
     let config = device.default_output_config().unwrap();
 
+    let sample_rate = config.sample_rate().0 as f32;
+    let channels = (config.channels() as usize).min(MAX_CHANNELS);
+    let mut master = Master::new(sample_rate)
+        .with_clipper(args.clip)
+        .with_normalisation(args.normalise);
+    if !args.no_limiter {
+        master = master.with_limiter(Limiter::new(
+            sample_rate,
+            channels,
+            LIMITER_CEILING,
+            0.005,
+            0.1,
+        )?);
+    }
+
     match config.sample_format() {
-        cpal::SampleFormat::F32 => run::<f32>(&device, &config.into(), seed),
-        cpal::SampleFormat::I16 => run::<i16>(&device, &config.into(), seed),
-        cpal::SampleFormat::U16 => run::<u16>(&device, &config.into(), seed),
+        cpal::SampleFormat::F32 => run::<f32>(
+            &device,
+            &config.into(),
+            seed,
+            voicing,
+            recording,
+            tables,
+            master,
+        ),
+        cpal::SampleFormat::I16 => run::<i16>(
+            &device,
+            &config.into(),
+            seed,
+            voicing,
+            recording,
+            tables,
+            master,
+        ),
+        cpal::SampleFormat::U16 => run::<u16>(
+            &device,
+            &config.into(),
+            seed,
+            voicing,
+            recording,
+            tables,
+            master,
+        ),
     }
 }
 
@@ -52,6 +351,10 @@ pub fn run<T>(
     device: &cpal::Device,
     config: &cpal::StreamConfig,
     seed: i64,
+    voicing: Voicing,
+    recording: Option<Arc<Recording>>,
+    tables: Arc<Vec<Wavetable>>,
+    master: Master,
 ) -> Result<(), Box<dyn Error>>
 where
     T: cpal::Sample,
@@ -60,7 +363,46 @@ where
     let sample_rate = config.sample_rate.0 as f32;
     let channels = config.channels as usize;
 
-    let mut sample = Mixer::new();
+    let mixed = channels.min(MAX_CHANNELS);
+
+    // Voices past the limit steal another voice chosen by the steal policy, which fades out over a
+    // few milliseconds. Voices the audio thread is done with are sent back here to be dropped,
+    // through a queue with room for every voice it could retire in one block.
+    let (retired_tx, mut retired_rx) = ring(2 * MAX_VOICES + 4 * VOICE_QUEUE);
+    let mut mixer = Mixer::new()
+        .with_pan_law(voicing.pan_law)
+        .with_voice_limit(
+            MAX_VOICES,
+            voicing.steal_policy,
+            (STEAL_FADE * sample_rate) as usize,
+        )
+        .with_block_size(MAX_BLOCK, mixed)
+        .with_event_capacity(4 * VOICE_QUEUE)
+        .with_metering(sample_rate)
+        .with_retirement(retired_tx)?;
+
+    // Held notes are sent to a reverb bus while plucks stay dry.
+    let reverb = mixer.add_bus("reverb")?;
+    mixer.add_insert(
+        Target::Bus(reverb),
+        Reverb::new(sample_rate, mixed, REVERB_ROOM, REVERB_DAMPING, 1.),
+    );
+
+    // Everything echoes in time with the tempo.
+    let beat = 60. / TEMPO;
+    let echo = mixer.add_insert(
+        Target::Master,
+        Delay::new(
+            sample_rate,
+            mixed,
+            beat,
+            0.75 * beat,
+            ECHO_FEEDBACK,
+            ECHO_MIX,
+        )?,
+    );
+    let clips = master.clip_counter();
+
     let mut continue_samples = 0.;
 
     let mut sample_clock = 0f32;
@@ -68,68 +410,41 @@ where
     let min_spawn: f32 = rng.sample(Uniform::new(0.0, 2.0));
     let max_spawn: f32 = min_spawn + rng.sample(Uniform::new(0.0, 2.0));
 
-    // The UI can request new sounds be created through 'Command'. These are sent over channels to
-    // the audio thread.
+    // The UI can request new sounds be created through 'Command'. The main thread builds the
+    // voices they need and sends them on to the audio thread as 'Event's through a lock-free queue.
     let (command_tx, command_rx): (Sender<Command>, Receiver<Command>) = mpsc::channel();
+    let (mut event_tx, event_rx) = ring(VOICE_QUEUE);
 
-    // The audio thread sends samples on a channel back to the main thread for visualization
-    let (sample_tx, sample_rx): (Sender<f32>, Receiver<f32>) = mpsc::channel();
+    // The audio thread sends samples through a lock-free queue back to the main thread for
+    // visualization.
+    let (scope_tx, mut scope_rx) = ring(sample_rate as usize);
 
-    // We use a channel to communicate when the audio thread should stop generating random data
-    let (finished_tx, finished_rx): (Sender<()>, Receiver<()>) = mpsc::channel();
+    // Meter readings are sent back after every block, and the interface shows the latest.
+    let (levels_tx, mut levels_rx) = ring(VOICE_QUEUE);
 
-    // This closure captures the new mixer we created and yields a function that will sample the
-    // next value from it, refilling the mixer when samples end.
-    let mut next_value = move || {
-        sample_clock = (sample_clock + 1.0) % sample_rate;
+    // Set when the audio thread should stop generating data.
+    let finished = Arc::new(AtomicBool::new(false));
 
-        match command_rx.try_recv() {
-            Ok(command) => match command {
-                Command::Start(Note::C) => sample.add_sample(Adsr::new(
-                    Sample::middle_c(sample_rate),
-                    sample_rate,
-                    0.4,
-                    0.7,
-                    0.3,
-                    0.6,
-                    0.6,
-                    0.5,
-                )),
-                Command::Start(Note::B) => sample.add_sample(Adsr::new(
-                    Sample::middle_b(sample_rate),
-                    sample_rate,
-                    0.4,
-                    0.7,
-                    0.3,
-                    0.6,
-                    0.6,
-                    0.5,
-                )),
-                Command::Start(Note::A) => sample.add_sample(Adsr::new(
-                    Sample::middle_a(sample_rate),
-                    sample_rate,
-                    0.4,
-                    0.7,
-                    0.3,
-                    0.6,
-                    0.6,
-                    0.5,
-                )),
-                Command::Start(Note::D) => sample.add_sample(Adsr::new(
-                    Sample::middle_a(sample_rate),
-                    sample_rate,
-                    0.4,
-                    0.7,
-                    0.3,
-                    0.6,
-                    0.6,
-                    0.5,
-                )),
-            },
-            Err(_) => {}
-        };
+    // The note keys play the recording when one was supplied and the chosen waveform otherwise.
+    let note_sample = move |tone: Sample| match &recording {
+        Some(recording) => Sample::Pcm(
+            Sampler::new(recording.clone(), sample_rate, tone.frequency())
+                .with_offset(voicing.offset),
+        ),
+        None => tone.with_waveform(voicing.waveform),
+    };
 
-        continue_samples = continue_samples - 1.;
+    // This closure captures the engine and yields a function that will fill the next block of
+    // interleaved frames from it.
+    let mut engine = Engine::new(mixer, master, mixed, event_rx, scope_tx)
+        .with_levels(levels_tx, sample_rate)
+        .with_note_send(reverb, NOTE_REVERB);
+    let clock = engine.clock();
+    let mut render = move |buffer: &mut [f32]| {
+        let frames = buffer.len() / mixed;
+        sample_clock = (sample_clock + frames as f32) % sample_rate;
+
+        continue_samples = continue_samples - frames as f32;
         /*
         if sample_clock == 0. && continue_samples < 0. {
             continue_samples = rng.sample(Uniform::new(
@@ -152,26 +467,20 @@ where
             );
         } */
 
-        let next = sample.next();
-
-        sample_tx.send(next).unwrap();
-
-        next
+        engine.render(buffer);
     };
 
-    let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
+    // Blocks are rendered here before being converted to the output format.
+    let mut buffer = vec![0.; MAX_BLOCK * mixed];
+    let stream_finished = finished.clone();
 
-    let mut finished = false;
+    let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
 
     let stream = device.build_output_stream(
         config,
         move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-            if let Ok(()) = finished_rx.try_recv() {
-                finished = true;
-            }
-
-            if !finished {
-                write_data(data, channels, &mut next_value)
+            if !stream_finished.load(Ordering::Relaxed) {
+                write_data(data, channels, &mut buffer, &mut render)
             }
         },
         err_fn,
@@ -179,21 +488,45 @@ where
 
     stream.play()?;
 
-    let mut ui = Ui::new(1500, 1, sample_rate as usize, command_tx).unwrap();
+    let mix = Mix {
+        reverb,
+        echo,
+        feedback: ECHO_FEEDBACK,
+    };
+    let mut ui = Ui::new(1500, 1, sample_rate as usize, command_tx, clips, mix).unwrap();
     let mut should_continue = true;
 
     while should_continue {
-        for sample in sample_rx.try_iter().take(sample_rate as usize * 4) {
+        for sample in scope_rx.by_ref().take(sample_rate as usize * 4) {
             ui.add_sample(sample);
         }
+        if let Some(levels) = levels_rx.by_ref().last() {
+            ui.set_levels(levels);
+        }
 
         should_continue = match ui.update().unwrap() {
             LoopState::Continue => true,
             LoopState::Exit => false,
         };
 
+        // An event that does not fit in the queue is dropped along with its voice.
+        let frame = clock.load(Ordering::Relaxed) + (SCHEDULE_AHEAD * sample_rate) as u64;
+        for command in command_rx.try_iter() {
+            let event = event(
+                command,
+                &mut rng,
+                sample_rate,
+                frame,
+                voicing,
+                &tables,
+                &note_sample,
+            );
+            let _ = event_tx.push((frame, event));
+        }
+        retired_rx.by_ref().for_each(drop);
+
         if !should_continue {
-            finished_tx.send(())?;
+            finished.store(true, Ordering::Relaxed);
         }
 
         ui.draw().unwrap();
@@ -203,14 +536,326 @@ where
     Ok(())
 }
 
-fn write_data<T>(output: &mut [T], channels: usize, next_sample: &mut dyn FnMut() -> f32)
-where
+/// Build the voice a command asks for, if it needs one, ready to send to the audio thread. `frame`
+/// is the number of frames played before the voice starts.
+fn event(
+    command: Command,
+    rng: &mut SmallRng,
+    sample_rate: f32,
+    frame: u64,
+    voicing: Voicing,
+    tables: &Arc<Vec<Wavetable>>,
+    note_sample: &dyn Fn(Sample) -> Sample,
+) -> Event {
+    match command {
+        // Note voices hold until their key is pressed again. Starting a note that is already held
+        // releases the old voice so it is never left sounding.
+        Command::Start(note, velocity) => {
+            // The notes are spread across the stereo field from left to right. A gently driven
+            // ladder filter follows the key of each so every note is as bright as the others.
+            let (tone, pan) = match note {
+                Note::A => (Sample::middle_a(sample_rate), -0.6),
+                Note::B => (Sample::middle_b(sample_rate), -0.2),
+                Note::C => (Sample::middle_c(sample_rate), 0.2),
+                Note::D => (Sample::middle_a(sample_rate), 0.6),
+            };
+            let key = (69. + 12. * (tone.frequency() / 440.).log2()).round() as u8;
+            let voice = Filtered::new(
+                vibrato(rng, sample_rate, note_sample(tone), frame, voicing),
+                Ladder::new(sample_rate, 2000., 0.2)
+                    .expect("the cutoff is below the nyquist frequency")
+                    .with_key_tracking(key, 1.)
+                    .with_drive(1.5),
+            );
+            let gain = voicing.velocity.gain(velocity);
+            let attack = voicing.velocity.attack(velocity, 0.4);
+            let voice = Adsr::gated(voice, sample_rate, attack, 0.7 * gain, 0.3, 0.6 * gain, 0.5)
+                .with_curves(Curve::Linear, Curve::Exponential, Curve::Exponential);
+            Event::Start(note, Box::new(voice), pan, key)
+        }
+        Command::Stop(note) => Event::Stop(note),
+        Command::Level(level) => Event::Level(level),
+        Command::Bend(bend) => Event::Bend(bend),
+        Command::Mix(action) => Event::Mix(action),
+        // The wavetable is gated in eighth notes at 120 bpm by a looping envelope, and swells in
+        // and dies away under a shape of its own. A wide band pass thins it out like a small
+        // speaker.
+        Command::Wavetable => {
+            let eighth = 0.25;
+            let gate = Envelope::from_breakpoints(
+                sample_rate,
+                vec![
+                    Breakpoint::linear(eighth / 4., 1.),
+                    Breakpoint::new(eighth * 3. / 4., 0., Curve::Exponential),
+                ],
+            )
+            .and_then(|envelope| envelope.with_loop(0, 1))
+            .expect("the gate has two stages to loop between");
+            let voice = Modulated::new(Filtered::new(
+                Sample::wavetable(tables.clone(), sample_rate, 220.)
+                    .expect("the set holds a table"),
+                Biquad::new(Response::BandPass, sample_rate, 1200., 0.5)
+                    .expect("the band is below the nyquist frequency"),
+            ))
+            .with_modulator(Modulator::Envelope(gate))
+            .with_route(0, Destination::Amplitude, 0.5)
+            .expect("every voice has an amplitude")
+            .with_interval(CONTROL_INTERVAL);
+            let shape = Envelope::from_breakpoints(
+                sample_rate,
+                vec![
+                    Breakpoint::new(0.3, 0.6, Curve::Logarithmic),
+                    Breakpoint::new(1.5, 0.4, Curve::Curvature(2.)),
+                    Breakpoint::new(1., 0., Curve::Exponential),
+                ],
+            )
+            .expect("the times are not negative");
+            Event::Play(Box::new(Adsr::with_envelope(voice, shape)), None, 0)
+        }
+        // Noise is seeded from the same rng as everything else so runs with the same seed sound
+        // the same, including which colour of noise each press plays and which output of the
+        // resonant filter it comes from. Each burst waits a moment and holds at the top before
+        // settling. The cutoff follows a key picked from the two octaves over middle c, which
+        // gives the noise a pitch, and is swept up from it by an envelope.
+        Command::Noise => {
+            let key = rng.sample(Uniform::new(60, 84));
+            let burst = Adsr::with_envelope(
+                match rng.sample(Uniform::new(0, 4)) {
+                    0 => Sample::white_noise(rng),
+                    1 => Sample::pink_noise(rng),
+                    2 => Sample::brown_noise(rng),
+                    _ => Sample::lfsr_noise(rng, sample_rate, 4000., false),
+                },
+                Envelope::dahdsr(sample_rate, 0.02, 0.01, 0.03, 0.05, 0.5, 0.2),
+            );
+            let filter = Svf::new(sample_rate, note_frequency(60), 0.8)
+                .expect("the cutoff is below the nyquist frequency")
+                .with_output(
+                    [Output::Low, Output::Band, Output::High][rng.sample(Uniform::new(0, 3))],
+                )
+                .with_key_tracking(key, 1.)
+                .with_envelope(Envelope::new(sample_rate, 0.15, 1., 0.1, 0., 0., 0.2), 2.);
+            Event::Play(
+                Box::new(Adsr::new(
+                    Filtered::new(burst, filter),
+                    sample_rate,
+                    0.01,
+                    0.8,
+                    0.1,
+                    0.05,
+                    0.3,
+                    0.2,
+                )),
+                Some(key),
+                0,
+            )
+        }
+        // An organ-like tone whose partials should show up as distinct peaks in the frequency
+        // spectrum. The fifth harmonic dies away quickly like the percussion of a drawbar organ.
+        // A ladder filter opens up over the attack and closes again to let the lower partials
+        // through.
+        Command::Additive => {
+            let decay = Envelope::new(sample_rate, 0.005, 1., 0.3, 0., 0., 0.1);
+            let organ = Additive::from_amplitudes(&[0.4, 0.2, 0.1], sample_rate, 440.)
+                .with_partial(Partial::new(5., 0.2, 0.).with_envelope(decay));
+
+            // The partials can line up to reach the sum of their amplitudes, so the envelope is
+            // scaled by it to keep the organ under full scale.
+            let amplitude: f32 = organ.partials().iter().map(|x| x.amplitude).sum();
+            Event::Play(
+                Box::new(Adsr::new(
+                    Filtered::new(
+                        Sample::Additive(organ),
+                        Ladder::new(sample_rate, 500., 0.5)
+                            .expect("the cutoff is below the nyquist frequency")
+                            .with_envelope(
+                                Envelope::new(sample_rate, 0.05, 1., 0.6, 1., 0.2, 0.5),
+                                2.,
+                            ),
+                    ),
+                    sample_rate,
+                    0.05,
+                    0.9 / amplitude,
+                    0.1,
+                    1.,
+                    0.8 / amplitude,
+                    0.5,
+                )),
+                None,
+                0,
+            )
+        }
+        // One of the fm presets, picked from the seeded rng. Their operator envelopes shape the
+        // sound, and the adsr only holds the voice open until they have run out. Each is equalised
+        // to suit it: the bell is brightened, the electric piano warmed and the rumble under the
+        // bass cut.
+        Command::Fm => {
+            let (voice, key, response, frequency, gain) = match rng.sample(Uniform::new(0, 3)) {
+                0 => (
+                    FmVoice::bell(sample_rate, 880.),
+                    81,
+                    Response::HighShelf,
+                    4000.,
+                    4.,
+                ),
+                1 => (
+                    FmVoice::electric_piano(sample_rate, 440.),
+                    69,
+                    Response::LowShelf,
+                    300.,
+                    3.,
+                ),
+                _ => (
+                    FmVoice::bass(sample_rate, 55.),
+                    33,
+                    Response::HighPass,
+                    30.,
+                    0.,
+                ),
+            };
+            let eq = Biquad::new(response, sample_rate, frequency, BUTTERWORTH_Q)
+                .expect("the eq is below the nyquist frequency")
+                .with_gain(gain);
+            Event::Play(
+                Box::new(Adsr::new(
+                    Filtered::new(Sample::Fm(voice), eq),
+                    sample_rate,
+                    0.,
+                    1.,
+                    0.,
+                    4.,
+                    1.,
+                    0.1,
+                )),
+                Some(key),
+                PLUCK_PRIORITY,
+            )
+        }
+        // A pulse wave whose width is swept slowly back and forth, through a resonant four pole
+        // filter whose frequency is swept faster, a low pass unless another response is chosen. The
+        // frequency only moves once every control interval, so the filter glides over about as long
+        // to keep the steps from clicking.
+        Command::Pulse => {
+            let pulse = Sample::BandLimitedSquare {
+                duty: 0.5,
+                rate: sample_rate,
+                frequency: 110.,
+                phase: 0.,
+            };
+            let filter = Biquad::new(voicing.pulse_filter, sample_rate, 900., 2. * BUTTERWORTH_Q)
+                .and_then(|filter| filter.with_order(4))
+                .expect("the frequency is below the nyquist frequency and the order is even")
+                .with_smoothing(CONTROL_INTERVAL as f32 / sample_rate);
+            let width = Lfo::new(rng, sample_rate, Shape::Triangle, Rate::Hertz(0.8));
+            let sweep = Lfo::new(rng, sample_rate, Shape::Sine, Rate::Hertz(2.5));
+            let voice = Modulated::new(Filtered::new(pulse, filter))
+                .with_modulator(Modulator::Lfo(width))
+                .with_modulator(Modulator::Lfo(sweep))
+                .with_route(0, Destination::Duty, 0.35)
+                .and_then(|voice| voice.with_route(1, Destination::Effect(0), 600.))
+                .expect("filtered pulse waves have a duty cycle and a cutoff")
+                .with_interval(CONTROL_INTERVAL);
+            Event::Play(
+                Box::new(Adsr::new(voice, sample_rate, 0.05, 0.6, 0.2, 2., 0.4, 0.5)),
+                None,
+                0,
+            )
+        }
+        // The envelope holds for longer than the string rings so the voice is removed when the
+        // string dies out.
+        // Harder plucks are brighter and ring slightly sharp as well as being louder, and a peak
+        // low in the range of the strings gives them a body to resonate in.
+        Command::Pluck(velocity) => {
+            // One of the open strings of a guitar, each pluck slightly out of tune. Lower strings
+            // sit further to the left.
+            let key = [40, 45, 50, 55, 59, 64][rng.sample(Uniform::new(0, 6))];
+            let gain = PLUCK_VELOCITY.gain(velocity);
+            let brightness = PLUCK_VELOCITY.brightness(velocity, 0.5);
+            let string = Sample::pluck(rng, sample_rate, note_frequency(key), 0.004, brightness)
+                .expect("the string is tuned below the nyquist frequency");
+            let body = Biquad::new(Response::Peak, sample_rate, 180., 1.5)
+                .expect("the body resonates below the nyquist frequency")
+                .with_gain(6.);
+            let voice = Modulated::new(Filtered::new(string, body))
+                .with_modulator(Modulator::Velocity(velocity))
+                .with_modulator(Modulator::Key(key))
+                .with_modulator(Modulator::random(rng))
+                .with_route(0, Destination::Frequency, 0.003)
+                .and_then(|voice| voice.with_route(1, Destination::Pan, 0.5))
+                .and_then(|voice| voice.with_route(2, Destination::Frequency, 0.005))
+                .expect("strings have a frequency")
+                .with_interval(CONTROL_INTERVAL);
+            Event::Play(
+                Box::new(Adsr::new(
+                    voice,
+                    sample_rate,
+                    0.,
+                    0.8 * gain,
+                    0.,
+                    60.,
+                    0.8 * gain,
+                    0.1,
+                )),
+                Some(key),
+                PLUCK_PRIORITY,
+            )
+        }
+    }
+}
+
+/// Add a delayed vibrato and a tremolo in time with the tempo to a voice starting at `frame`.
+fn vibrato(
+    rng: &mut SmallRng,
+    sample_rate: f32,
+    sample: Sample,
+    frame: u64,
+    voicing: Voicing,
+) -> Modulated {
+    let vibrato = Lfo::new(rng, sample_rate, Shape::Sine, Rate::Hertz(5.5)).with_fade_in(0.6);
+
+    // The tremolo keeps in time with the beat rather than restarting with each note, so it starts
+    // from wherever the beat has reached when the note does.
+    let rate = Rate::Synced {
+        tempo: TEMPO,
+        division: voicing.tremolo_division,
+    };
+    let beat = (frame as f64 / sample_rate as f64 * rate.frequency() as f64).fract();
+    let tremolo = Lfo::new(rng, sample_rate, voicing.tremolo_shape, rate)
+        .with_phase(beat as f32)
+        .with_retrigger(false);
+
+    Modulated::new(sample)
+        .with_modulator(Modulator::Lfo(vibrato))
+        .with_modulator(Modulator::Lfo(tremolo))
+        .with_route(0, Destination::Frequency, 0.01)
+        .and_then(|voice| voice.with_route(1, Destination::Amplitude, 0.15))
+        .expect("note voices have a frequency")
+        .with_interval(CONTROL_INTERVAL)
+}
+
+/// Render a block of output into a buffer of at least `MAX_BLOCK` frames of mixed channels,
+/// rendering longer blocks in pieces so the buffer never has to grow.
+fn write_data<T>(
+    output: &mut [T],
+    channels: usize,
+    buffer: &mut [f32],
+    render: &mut dyn FnMut(&mut [f32]),
+) where
     T: cpal::Sample,
 {
-    for frame in output.chunks_mut(channels) {
-        let value: T = cpal::Sample::from::<f32>(&next_sample());
-        for sample in frame.iter_mut() {
-            *sample = value;
+    // Channels past the most the mixer is asked for are left silent.
+    let mixed = channels.min(MAX_CHANNELS);
+
+    for output in output.chunks_mut(MAX_BLOCK * channels) {
+        let frames = output.len() / channels;
+        let block = &mut buffer[..frames * mixed];
+        render(block);
+
+        for (output_frame, frame) in output.chunks_mut(channels).zip(block.chunks(mixed)) {
+            for (i, sample) in output_frame.iter_mut().enumerate() {
+                let value = if i < mixed { frame[i] } else { 0. };
+                *sample = cpal::Sample::from::<f32>(&value);
+            }
         }
     }
 }
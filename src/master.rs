@@ -0,0 +1,417 @@
+/**
+ * The master stage between the mixer and the output. The mix can be normalised by the number of
+ * voices playing, held under a ceiling by a look-ahead limiter, and finally passed through a
+ * clipper that keeps it within full scale. Samples that reach the clipper outside full scale are
+ * counted so the interface can show when the mix is too hot.
+ */
+use crate::dsp::hermite;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// How long normalisation takes to follow a change in the number of voices, in seconds.
+const NORMALISE_TIME: f32 = 0.05;
+
+/// The number of points between each pair of samples checked by the limiter for inter-sample
+/// peaks.
+const OVERSAMPLING: usize = 4;
+
+/// How the final mix is kept within full scale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Clipper {
+    // Clamp to full scale, which distorts harshly.
+    Hard,
+
+    // A hyperbolic tangent curve, which bends gradually from the smallest levels.
+    Tanh,
+
+    // A cubic curve that is close to linear at low levels and flattens out to full scale.
+    Cubic,
+}
+
+impl Clipper {
+    pub fn apply(&self, value: f32) -> f32 {
+        match self {
+            Clipper::Hard => value.clamp(-1., 1.),
+            Clipper::Tanh => value.tanh(),
+            Clipper::Cubic => {
+                let value = value.clamp(-1., 1.);
+                1.5 * value - 0.5 * value * value * value
+            }
+        }
+    }
+}
+
+impl FromStr for Clipper {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "hard" => Ok(Clipper::Hard),
+            "tanh" => Ok(Clipper::Tanh),
+            "cubic" => Ok(Clipper::Cubic),
+            _ => Err(format!(
+                "unknown clipper {}, expected hard, tanh or cubic",
+                name
+            )),
+        }
+    }
+}
+
+/// A look-ahead peak limiter. The signal is delayed while the limiter looks for peaks, including
+/// peaks between samples, so the gain can be brought down smoothly before a peak arrives rather
+/// than after it has already gone over the ceiling.
+#[derive(Debug)]
+pub struct Limiter {
+    channels: usize,
+    ceiling: f32,
+
+    // The attack in samples. The limiter looks this far ahead (plus a little to find peaks between
+    // samples) and ramps the gain down over this many samples.
+    attack: usize,
+
+    // How much of the distance back to full gain is left after each sample of release.
+    release: f32,
+
+    // The last four input frames of each channel, used to find peaks between samples.
+    history: Vec<[f32; 4]>,
+
+    // The delayed signal, one frame after another, and the position of the oldest frame.
+    delay: Vec<f32>,
+    delay_position: usize,
+
+    // The gain each recent frame needs to stay under the ceiling, the lowest of which is held for
+    // the length of the attack.
+    required: Vec<f32>,
+    required_position: usize,
+
+    // The held gains being averaged to ramp the gain down, and their running total.
+    held: Vec<f32>,
+    held_position: usize,
+    held_total: f64,
+
+    gain: f32,
+}
+
+impl Limiter {
+    /// A limiter for frames of `channels` channels which keeps peaks under `ceiling`, reaching
+    /// full reduction over `attack` seconds and recovering over roughly `release` seconds.
+    pub fn new(
+        sample_rate: f32,
+        channels: usize,
+        ceiling: f32,
+        attack: f32,
+        release: f32,
+    ) -> Result<Self, Box<dyn Error>> {
+        if channels == 0 {
+            return Err("a limiter needs at least one channel".into());
+        }
+        if ceiling.is_nan() || ceiling <= 0. {
+            return Err("the limiter ceiling must be above zero".into());
+        }
+
+        let attack = ((attack * sample_rate).round() as usize).max(1);
+        let release = if release > 0. {
+            (-1. / (release * sample_rate)).exp()
+        } else {
+            0.
+        };
+
+        // The delay covers the attack plus the frame the limiter waits on to see the far side
+        // of each peak between samples.
+        Ok(Limiter {
+            channels,
+            ceiling,
+            attack,
+            release,
+            history: vec![[0.; 4]; channels],
+            delay: vec![0.; (attack + 1) * channels],
+            delay_position: 0,
+            required: vec![1.; attack + 1],
+            required_position: 0,
+            held: vec![1.; attack],
+            held_position: 0,
+            held_total: attack as f64,
+            gain: 1.,
+        })
+    }
+
+    /// The delay the limiter adds to the signal, in frames.
+    pub fn latency(&self) -> usize {
+        self.attack + 1
+    }
+
+    /// The gain the limiter applied to the last frame.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// The highest level between the two middle frames of the history, checked at the frames
+    /// themselves and at points between them.
+    fn recent_peak(&self) -> f32 {
+        let mut peak: f32 = 0.;
+        for [y0, y1, y2, y3] in self.history.iter() {
+            peak = peak.max(y1.abs()).max(y2.abs());
+            for i in 1..OVERSAMPLING {
+                let t = i as f32 / OVERSAMPLING as f32;
+                peak = peak.max(hermite(*y0, *y1, *y2, *y3, t).abs());
+            }
+        }
+        peak
+    }
+
+    /// Limit a frame in place. The frame that comes out is the one that went in `latency` frames
+    /// ago.
+    pub fn process(&mut self, frame: &mut [f32]) {
+        for (history, value) in self.history.iter_mut().zip(frame.iter()) {
+            *history = [history[1], history[2], history[3], *value];
+        }
+
+        let peak = self.recent_peak();
+        let required = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.
+        };
+        self.required[self.required_position] = required;
+        self.required_position = (self.required_position + 1) % self.required.len();
+
+        // Hold the lowest gain needed by any frame in the look-ahead window, then average the held
+        // gains over the attack so the gain ramps down and reaches each reduction as its peak
+        // leaves the delay.
+        let lowest = self.required.iter().cloned().fold(1., f32::min);
+        self.held_total += (lowest - self.held[self.held_position]) as f64;
+        self.held[self.held_position] = lowest;
+        self.held_position = (self.held_position + 1) % self.held.len();
+        let target = (self.held_total / self.held.len() as f64) as f32;
+
+        self.gain = if target < self.gain {
+            target
+        } else {
+            target + (self.gain - target) * self.release
+        };
+
+        let start = self.delay_position * self.channels;
+        for (delayed, value) in self.delay[start..start + self.channels]
+            .iter_mut()
+            .zip(frame.iter_mut())
+        {
+            let input = *value;
+            *value = *delayed * self.gain;
+            *delayed = input;
+        }
+        self.delay_position = (self.delay_position + 1) % (self.delay.len() / self.channels);
+    }
+}
+
+/// The stages applied to the mix before it is sent to the output.
+#[derive(Debug)]
+pub struct Master {
+    // Whether to scale the mix down as more voices play, and the current scale.
+    normalise: bool,
+    normal_gain: f32,
+    normal_smoothing: f32,
+
+    limiter: Option<Limiter>,
+    clipper: Clipper,
+
+    // The number of samples that reached the clipper outside full scale. This is shared so the
+    // interface can read it while the audio thread writes it.
+    clips: Arc<AtomicU64>,
+}
+
+impl Master {
+    /// A master stage that only hard clips, like a plain clamp.
+    pub fn new(sample_rate: f32) -> Self {
+        Master {
+            normalise: false,
+            normal_gain: 1.,
+            normal_smoothing: (-1. / (NORMALISE_TIME * sample_rate)).exp(),
+            limiter: None,
+            clipper: Clipper::Hard,
+            clips: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Scale the mix by one over the square root of the number of voices playing, which keeps the
+    /// level of uncorrelated voices roughly constant.
+    pub fn with_normalisation(self, normalise: bool) -> Self {
+        Master { normalise, ..self }
+    }
+
+    pub fn with_limiter(self, limiter: Limiter) -> Self {
+        Master {
+            limiter: Some(limiter),
+            ..self
+        }
+    }
+
+    pub fn with_clipper(self, clipper: Clipper) -> Self {
+        Master { clipper, ..self }
+    }
+
+    /// A counter of the samples that have been clipped, which can be read from another thread.
+    pub fn clip_counter(&self) -> Arc<AtomicU64> {
+        self.clips.clone()
+    }
+
+    /// Process a frame of the mix in place, given the number of voices that made it.
+    pub fn process(&mut self, frame: &mut [f32], voices: usize) {
+        if self.normalise {
+            let target = 1. / (voices.max(1) as f32).sqrt();
+            self.normal_gain = target + (self.normal_gain - target) * self.normal_smoothing;
+            for value in frame.iter_mut() {
+                *value *= self.normal_gain;
+            }
+        }
+
+        if let Some(limiter) = &mut self.limiter {
+            limiter.process(frame);
+        }
+
+        let mut clipped = 0;
+        for value in frame.iter_mut() {
+            if value.abs() > 1. {
+                clipped += 1;
+            }
+            *value = self.clipper.apply(*value);
+        }
+        if clipped > 0 {
+            self.clips.fetch_add(clipped, Ordering::Relaxed);
+        }
+    }
+
+    /// Process a buffer of interleaved frames of `channels` channels in place.
+    pub fn process_block(&mut self, buffer: &mut [f32], channels: usize, voices: usize) {
+        for frame in buffer.chunks_mut(channels.max(1)) {
+            self.process(frame, voices);
+        }
+    }
+}
+
+#[cfg(test)]
+mod master_tests {
+    use super::{Clipper, Limiter, Master};
+    use crate::dsp::hermite;
+    use std::sync::atomic::Ordering;
+
+    const RATE: f32 = 44100.;
+
+    #[test]
+    fn clippers_stay_within_full_scale() {
+        for clipper in [Clipper::Hard, Clipper::Tanh, Clipper::Cubic] {
+            for i in -100..=100 {
+                let value = clipper.apply(i as f32 / 10.);
+                assert!(value.abs() <= 1., "{:?}", clipper);
+            }
+            assert_eq!(clipper.apply(0.), 0.);
+        }
+
+        // The soft clippers are smooth where the hard clipper has a corner.
+        assert_eq!(Clipper::Hard.apply(1.5), 1.);
+        assert!((Clipper::Cubic.apply(0.99) - Clipper::Cubic.apply(1.)).abs() < 1e-3);
+        assert!(Clipper::Tanh.apply(0.5) < 0.5 && Clipper::Cubic.apply(0.5) > 0.5);
+        assert_eq!("cubic".parse::<Clipper>(), Ok(Clipper::Cubic));
+        assert!("soft".parse::<Clipper>().is_err());
+    }
+
+    #[test]
+    fn counts_clipped_samples() {
+        let mut master = Master::new(RATE).with_clipper(Clipper::Tanh);
+        let clips = master.clip_counter();
+
+        let mut frame = [0.5, 1.5];
+        master.process(&mut frame, 2);
+        let mut frame = [-2., 0.];
+        master.process(&mut frame, 2);
+
+        assert_eq!(clips.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn normalises_by_voice_count() {
+        let mut master = Master::new(RATE).with_normalisation(true);
+        let mut frame = [0.];
+        for _ in 0..RATE as usize {
+            frame[0] = 1.;
+            master.process(&mut frame, 4);
+        }
+        assert!((frame[0] - 0.5).abs() < 1e-4);
+    }
+
+    /// A sine burst that goes well over full scale, with peaks falling between samples.
+    fn burst(length: usize) -> Vec<f32> {
+        (0..length)
+            .map(|i| {
+                let level = if i > length / 4 && i < length / 2 {
+                    3.
+                } else {
+                    0.5
+                };
+                level * (2. * std::f32::consts::PI * 5512.5 * i as f32 / RATE + 0.3).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn limiter_holds_true_peaks_under_the_ceiling() {
+        let mut limiter = Limiter::new(RATE, 1, 0.9, 0.005, 0.01).unwrap();
+        let input = burst(8192);
+        let output: Vec<f32> = input
+            .iter()
+            .map(|x| {
+                let mut frame = [*x];
+                limiter.process(&mut frame);
+                frame[0]
+            })
+            .collect();
+
+        for window in output.windows(4) {
+            assert!(window[1].abs() <= 0.9 + 1e-5);
+            for i in 1..8 {
+                let t = i as f32 / 8.;
+                let between = hermite(window[0], window[1], window[2], window[3], t);
+                assert!(between.abs() <= 0.9 * 1.01, "{}", between);
+            }
+        }
+
+        // Quiet material well before and after the burst passes through untouched.
+        let latency = limiter.latency();
+        for i in 100..1000 {
+            assert!((output[i + latency] - input[i]).abs() < 1e-6);
+        }
+        assert!((output[8191] - input[8191 - latency]).abs() < 1e-3);
+    }
+
+    #[test]
+    fn limiter_ramps_down_before_the_peak() {
+        let mut limiter = Limiter::new(RATE, 2, 1., 0.001, 0.1).unwrap();
+        let attack = (0.001 * RATE).round() as usize;
+
+        let mut gains = Vec::new();
+        for i in 0..400 {
+            let level = if i == 200 { 4. } else { 0. };
+            let mut frame = [level, -level];
+            limiter.process(&mut frame);
+            gains.push(limiter.gain());
+            if i == 200 + limiter.latency() {
+                assert_eq!(frame, [1., -1.]);
+            }
+        }
+
+        // The gain starts falling as soon as the limiter sees the far side of the peak, an attack
+        // ahead of the peak leaving the delay, and never jumps.
+        assert_eq!(gains[200], 1.);
+        assert!(gains[201] < 1. && gains[201] > 0.9);
+        assert!(gains
+            .windows(2)
+            .all(|x| (x[1] - x[0]).abs() < 0.75 / attack as f32 + 1e-4));
+    }
+
+    #[test]
+    fn invalid_limiters() {
+        assert!(Limiter::new(RATE, 0, 1., 0.001, 0.1).is_err());
+        assert!(Limiter::new(RATE, 2, 0., 0.001, 0.1).is_err());
+    }
+}
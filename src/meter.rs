@@ -0,0 +1,415 @@
+/**
+ * Level meters. A meter follows the sample peak, RMS and true (inter-sample) peak of a signal along
+ * with its loudness as defined by ITU-R BS.1770 and EBU R128. For loudness the signal is K-weighted,
+ * a high shelf modelling the effect of the head followed by a high-pass, and the mean square of the
+ * weighted channels is measured over the last 400ms (momentary), the last 3s (short-term) and the
+ * whole signal with quiet passages gated out (integrated).
+ *
+ * Everything a meter needs is held inline, so meters can be made and run on the audio thread.
+ */
+use crate::dsp::hermite;
+
+/// The most channels a meter measures. Any further channels are ignored.
+pub const MAX_CHANNELS: usize = 8;
+
+/// The length of the steps the loudness is measured in, in seconds. The reading is updated at the
+/// end of each step.
+const STEP: f32 = 0.1;
+
+/// The number of steps in the momentary (400ms) and short-term (3s) windows.
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+
+/// Blocks quieter than this, in LUFS, are left out of the integrated loudness.
+const ABSOLUTE_GATE: f32 = -70.;
+
+/// Blocks more than this many LU below the loudness of the blocks that pass the absolute gate are
+/// left out as well.
+const RELATIVE_GATE: f32 = -10.;
+
+/// The integrated loudness is measured from a histogram of block loudness rather than a list of
+/// every block, so it needs no more memory the longer it runs. The bins are a tenth of a LU wide
+/// and cover the 100 LU above the absolute gate.
+const BINS_PER_LU: f32 = 10.;
+const BINS: usize = 1000;
+
+/// The number of points between each pair of samples checked for true peaks.
+const OVERSAMPLING: usize = 4;
+
+/// The loudness in LUFS of a weighted mean square.
+fn loudness(energy: f64) -> f32 {
+    (-0.691 + 10. * energy.log10()) as f32
+}
+
+/// The weighted mean square of a loudness in LUFS.
+fn energy(loudness: f32) -> f64 {
+    10f64.powf((loudness as f64 + 0.691) / 10.)
+}
+
+/// The level in decibels of a gain.
+pub fn decibels(gain: f32) -> f32 {
+    20. * gain.log10()
+}
+
+/// One second order section of the K-weighting filter, in transposed direct form II.
+#[derive(Debug, Clone, Copy)]
+struct Section {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Section {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The two sections of the K-weighting filter at a sample rate. BS.1770 gives the coefficients at
+/// 48khz; these are the analogue prototypes they come from, so they match at any rate.
+fn k_weighting(sample_rate: f32) -> [Section; 2] {
+    let rate = sample_rate as f64;
+
+    // A high shelf of about +4db above 1.5khz.
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1. + k / q + k * k;
+    let shelf = Section {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2. * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+        z: [0.; 2],
+    };
+
+    // A high-pass at about 38hz.
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let a0 = 1. + k / q + k * k;
+    let high_pass = Section {
+        b: [1., -2., 1.],
+        a: [2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+        z: [0.; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+/// The state kept for each channel: its weighting filter and the last four samples, which true
+/// peaks are interpolated from.
+#[derive(Debug, Clone, Copy)]
+struct Channel {
+    filter: [Section; 2],
+    history: [f32; 4],
+}
+
+/// The measurements of one step.
+#[derive(Debug, Clone, Copy, Default)]
+struct Step {
+    peak: f32,
+    true_peak: f32,
+
+    // The mean square of every channel, and the sum over the channels of the mean square of each
+    // once weighted.
+    square: f64,
+    weighted: f64,
+}
+
+/// What a meter shows. Levels are gains and loudness is in LUFS, where silence is negative
+/// infinity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    // The highest sample and the highest level reached between samples over the momentary window.
+    pub peak: f32,
+    pub true_peak: f32,
+
+    // The root mean square level of all the channels over the momentary window.
+    pub rms: f32,
+
+    pub momentary: f32,
+    pub short_term: f32,
+    pub integrated: f32,
+}
+
+impl Default for Reading {
+    fn default() -> Self {
+        Reading {
+            peak: 0.,
+            true_peak: 0.,
+            rms: 0.,
+            momentary: f32::NEG_INFINITY,
+            short_term: f32::NEG_INFINITY,
+            integrated: f32::NEG_INFINITY,
+        }
+    }
+}
+
+/// Meters a signal of up to `MAX_CHANNELS` channels.
+#[derive(Debug, Clone)]
+pub struct Meter {
+    channels: usize,
+    channel: [Channel; MAX_CHANNELS],
+
+    // The frames in a step and the frames of the current step so far.
+    step_length: usize,
+    position: usize,
+
+    // The measurements of the current step so far.
+    current: Step,
+
+    // The last steps, the newest at `step`, and the number of steps ever taken.
+    steps: [Step; SHORT_TERM_STEPS],
+    step: usize,
+    steps_taken: u64,
+
+    // The number of gating blocks whose loudness fell in each bin.
+    histogram: [u32; BINS],
+
+    reading: Reading,
+}
+
+impl Meter {
+    pub fn new(sample_rate: f32, channels: usize) -> Self {
+        let channel = Channel {
+            filter: k_weighting(sample_rate),
+            history: [0.; 4],
+        };
+
+        Meter {
+            channels: channels.clamp(1, MAX_CHANNELS),
+            channel: [channel; MAX_CHANNELS],
+            step_length: ((STEP * sample_rate) as usize).max(1),
+            position: 0,
+            current: Step::default(),
+            steps: [Step::default(); SHORT_TERM_STEPS],
+            step: 0,
+            steps_taken: 0,
+            histogram: [0; BINS],
+            reading: Reading::default(),
+        }
+    }
+
+    /// Measure a buffer of interleaved frames.
+    pub fn process(&mut self, buffer: &[f32]) {
+        for frame in buffer.chunks(self.channels) {
+            self.process_frame(frame);
+        }
+    }
+
+    fn process_frame(&mut self, frame: &[f32]) {
+        let current = &mut self.current;
+        for (channel, value) in self.channel.iter_mut().zip(frame.iter()) {
+            channel.history = [
+                channel.history[1],
+                channel.history[2],
+                channel.history[3],
+                *value,
+            ];
+            let [y0, y1, y2, y3] = channel.history;
+            current.peak = current.peak.max(value.abs());
+            current.true_peak = current.true_peak.max(y2.abs());
+            for i in 1..OVERSAMPLING {
+                let t = i as f32 / OVERSAMPLING as f32;
+                current.true_peak = current.true_peak.max(hermite(y0, y1, y2, y3, t).abs());
+            }
+
+            let weighted = channel
+                .filter
+                .iter_mut()
+                .fold(*value as f64, |x, section| section.process(x));
+            current.square += (*value as f64).powi(2);
+            current.weighted += weighted * weighted;
+        }
+
+        self.position += 1;
+        if self.position == self.step_length {
+            self.finish_step();
+        }
+    }
+
+    /// Store the step just measured and update the reading.
+    fn finish_step(&mut self) {
+        let frames = self.step_length as f64;
+        let mut step = std::mem::take(&mut self.current);
+        step.square /= frames * self.channels as f64;
+        step.weighted /= frames;
+
+        self.step = (self.step + 1) % SHORT_TERM_STEPS;
+        self.steps[self.step] = step;
+        self.steps_taken += 1;
+        self.position = 0;
+
+        let (steps, newest) = (self.steps, self.step);
+        let recent = move |count: usize| {
+            (0..count).map(move |i| steps[(newest + SHORT_TERM_STEPS - i) % SHORT_TERM_STEPS])
+        };
+        let momentary = recent(MOMENTARY_STEPS)
+            .map(|step| step.weighted)
+            .sum::<f64>()
+            / MOMENTARY_STEPS as f64;
+        let short_term = recent(SHORT_TERM_STEPS)
+            .map(|step| step.weighted)
+            .sum::<f64>()
+            / SHORT_TERM_STEPS as f64;
+        let square =
+            recent(MOMENTARY_STEPS).map(|step| step.square).sum::<f64>() / MOMENTARY_STEPS as f64;
+        let peak = recent(MOMENTARY_STEPS).fold(0., |peak, step| step.peak.max(peak));
+        let true_peak = recent(MOMENTARY_STEPS).fold(0., |peak, step| step.true_peak.max(peak));
+
+        // Each step ends a gating block of the momentary window, overlapping the last by 75%.
+        if self.steps_taken >= MOMENTARY_STEPS as u64 && loudness(momentary) > ABSOLUTE_GATE {
+            let bin = ((loudness(momentary) - ABSOLUTE_GATE) * BINS_PER_LU) as usize;
+            self.histogram[bin.min(BINS - 1)] += 1;
+        }
+
+        self.reading = Reading {
+            peak,
+            true_peak,
+            rms: square.sqrt() as f32,
+            momentary: loudness(momentary),
+            short_term: loudness(short_term),
+            integrated: self.integrated(),
+        };
+    }
+
+    /// The gated loudness of every block measured so far.
+    fn integrated(&self) -> f32 {
+        // The mean energy of the blocks in the bins from `first` up.
+        let mean = |first: usize| {
+            let (blocks, total) = self.histogram[first.min(BINS)..].iter().enumerate().fold(
+                (0, 0.),
+                |(blocks, total), (i, count)| {
+                    let centre = ABSOLUTE_GATE + (first + i) as f32 / BINS_PER_LU + 0.05;
+                    (blocks + count, total + *count as f64 * energy(centre))
+                },
+            );
+            if blocks == 0 {
+                None
+            } else {
+                Some(total / blocks as f64)
+            }
+        };
+
+        match mean(0) {
+            Some(ungated) => {
+                let gate = loudness(ungated) + RELATIVE_GATE - ABSOLUTE_GATE;
+                let first = (gate * BINS_PER_LU).max(0.) as usize;
+                mean(first).map_or(f32::NEG_INFINITY, loudness)
+            }
+            None => f32::NEG_INFINITY,
+        }
+    }
+
+    /// The measurements as of the end of the last step.
+    pub fn reading(&self) -> Reading {
+        self.reading
+    }
+}
+
+#[cfg(test)]
+mod meter_tests {
+    use super::{decibels, Meter};
+    use std::f32::consts::PI;
+
+    const RATE: f32 = 48000.;
+
+    /// Meter a sine of a frequency, peak amplitude and starting phase in every channel for a
+    /// number of seconds.
+    fn sine(meter: &mut Meter, channels: usize, frequency: f32, amplitude: f32, seconds: f32) {
+        sine_from(meter, channels, frequency, amplitude, seconds, 0.)
+    }
+
+    fn sine_from(
+        meter: &mut Meter,
+        channels: usize,
+        frequency: f32,
+        amplitude: f32,
+        seconds: f32,
+        phase: f32,
+    ) {
+        let frames = (seconds * RATE) as usize;
+        let mut buffer = Vec::with_capacity(frames * channels);
+        for i in 0..frames {
+            let value = amplitude * (2. * PI * frequency * i as f32 / RATE + phase).sin();
+            buffer.extend(std::iter::repeat_n(value, channels));
+        }
+        meter.process(&buffer);
+    }
+
+    #[test]
+    fn tenth_scale_tone_is_minus_twenty_three_lufs() {
+        // BS.1770 calibrates loudness so a full scale 997hz sine in one channel reads -3.01, and
+        // a tenth of full scale is 20db quieter.
+        let mut meter = Meter::new(RATE, 1);
+        sine(&mut meter, 1, 997., 0.1, 5.);
+
+        let reading = meter.reading();
+        for loudness in [reading.momentary, reading.short_term, reading.integrated] {
+            assert!((loudness + 23.01).abs() < 0.1, "{}", loudness);
+        }
+        assert!((reading.peak - 0.1).abs() < 1e-3);
+        assert!((reading.rms - 0.1 / 2f32.sqrt()).abs() < 1e-3);
+
+        // The same tone in two channels is twice the power.
+        let mut meter = Meter::new(RATE, 2);
+        sine(&mut meter, 2, 997., 0.1, 5.);
+        assert!((meter.reading().momentary + 20.).abs() < 0.1);
+    }
+
+    #[test]
+    fn weighting_favours_highs() {
+        let measure = |frequency| {
+            let mut meter = Meter::new(44100., 1);
+            sine(&mut meter, 1, frequency, 0.1, 1.);
+            meter.reading().momentary
+        };
+
+        let (low, mid, high) = (measure(30.), measure(1000.), measure(8000.));
+        assert!(mid - low > 1., "{} {}", low, mid);
+        assert!(high - mid > 2.5 && high - mid < 4.5, "{} {}", mid, high);
+    }
+
+    #[test]
+    fn true_peak_is_found_between_samples() {
+        // A quarter of the sample rate, sampled either side of each crest.
+        let mut meter = Meter::new(RATE, 1);
+        sine_from(&mut meter, 1, RATE / 4., 0.5, 0.5, PI / 4.);
+
+        let reading = meter.reading();
+        assert!((decibels(reading.peak) - decibels(0.5) + 3.01).abs() < 0.05);
+        assert!(reading.true_peak > reading.peak * 1.2);
+        assert!(reading.true_peak <= 0.5 * 1.01);
+    }
+
+    #[test]
+    fn integrated_loudness_is_gated() {
+        // The blocks that straddle the end of the tone pull the reading down a little.
+        let mut meter = Meter::new(RATE, 1);
+        sine(&mut meter, 1, 997., 0.1, 20.);
+        sine(&mut meter, 1, 997., 0., 10.);
+        assert!((meter.reading().integrated + 23.01).abs() < 0.1);
+        assert!(meter.reading().momentary < -70.);
+
+        // A passage 20 LU quieter falls below the relative gate.
+        let mut meter = Meter::new(RATE, 1);
+        sine(&mut meter, 1, 997., 0.1, 20.);
+        sine(&mut meter, 1, 997., 0.01, 10.);
+        assert!((meter.reading().integrated + 23.01).abs() < 0.1);
+    }
+
+    #[test]
+    fn silence_reads_nothing() {
+        let mut meter = Meter::new(RATE, 2);
+        meter.process(&vec![0.; 2 * RATE as usize]);
+        let reading = meter.reading();
+        assert_eq!(reading.peak, 0.);
+        assert_eq!(reading.integrated, f32::NEG_INFINITY);
+    }
+}
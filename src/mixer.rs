@@ -1,38 +1,1579 @@
-use crate::adsr::Adsr;
+use crate::effect::Effect;
+use crate::meter::{Meter, Reading};
+use crate::ring::Producer;
+use crate::source::{Parameter, Source};
+use std::error::Error;
+use std::f32::consts::FRAC_PI_2;
+use std::str::FromStr;
 
-/// A mixer chunk stores the sample being played and the number of times it has been
-/// sampled (it's clock).
+/// How a voice's level is split between a pair of speakers as it is panned between them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PanLaw {
+    // The gains add up to one, so a centred voice is 6db quieter in each speaker.
+    Linear,
+
+    // The powers add up to one, so a centred voice is 3db quieter in each speaker and sounds
+    // equally loud wherever it is placed.
+    ConstantPower,
+
+    // A compromise between the two, 4.5db down in the centre.
+    Compromise,
+}
+
+impl FromStr for PanLaw {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "linear" => Ok(PanLaw::Linear),
+            "constant-power" => Ok(PanLaw::ConstantPower),
+            "compromise" => Ok(PanLaw::Compromise),
+            _ => Err(format!(
+                "unknown pan law {}, expected linear, constant-power or compromise",
+                name
+            )),
+        }
+    }
+}
+
+impl PanLaw {
+    /// The left and right gains for a pan position from -1 (left) to 1 (right).
+    pub fn gains(&self, pan: f32) -> (f32, f32) {
+        let position = (pan.clamp(-1., 1.) + 1.) / 2.;
+        let linear = (1. - position, position);
+        let power = ((position * FRAC_PI_2).cos(), (position * FRAC_PI_2).sin());
+
+        match self {
+            PanLaw::Linear => linear,
+            PanLaw::ConstantPower => power,
+            PanLaw::Compromise => ((linear.0 * power.0).sqrt(), (linear.1 * power.1).sqrt()),
+        }
+    }
+}
+
+/// A source playing in a mixer.
+pub type Voice = Box<dyn Source + Send>;
+
+/// The most buses a mixer can have.
+pub const MAX_BUSES: usize = 8;
+
+/// Identifies a bus added to a mixer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BusId(usize);
+
+/// Where an insert effect goes: on the master, after the dry voices and buses are mixed, or on a
+/// bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Master,
+    Bus(BusId),
+}
+
+/// An aux bus. Voices send to it at their own levels, its inserts process what they sent and the
+/// result is mixed in with the dry voices.
+struct Bus {
+    name: String,
+
+    // The frames sent to the bus in the current block.
+    buffer: Vec<f32>,
+
+    effects: Vec<Box<dyn Effect + Send>>,
+    gain: f32,
+    mute: bool,
+    solo: bool,
+}
+
+/// Identifies a voice added to a mixer so it can be released later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VoiceId(u64);
+
+/// A mixer chunk stores the source being played and the number of times it has been
+/// sampled (it's age). The source keeps its own phase so the age is only informational.
 pub struct Chunk {
-    pub sample: Adsr,
-    pub samples: f32,
+    pub id: VoiceId,
+    pub sample: Voice,
+    pub samples: u64,
+
+    // The level and position of the voice. The pan is added to any pan the source sets itself.
+    pub gain: f32,
+    pub pan: f32,
+
+    // The note the voice is playing, if it plays one, and how important it is to keep it playing
+    // when voices have to be stolen.
+    pub note: Option<u8>,
+    pub priority: u8,
+
+    // A peak level that follows the output of the voice, used to find the quietest voice.
+    pub level: f32,
+
+    // The samples left before a stolen voice has faded out, and the gain it has faded to.
+    pub fading: Option<usize>,
+    pub fade: f32,
+
+    // Measures the voice after its gain and fade, when the mixer meters its voices.
+    pub meter: Option<Meter>,
+
+    // The level the voice sends to each bus, after its gain.
+    pub sends: [f32; MAX_BUSES],
+}
+
+/// How much of the tracked level of a voice is kept each sample.
+const LEVEL_DECAY: f32 = 0.999;
+
+/// The most samples a stolen voice keeps fading for once too many voices are fading at once.
+const SHORT_FADE: usize = 64;
+
+impl Chunk {
+    /// Fill a buffer with the next samples of the voice.
+    fn process(&mut self, buffer: &mut [f32]) {
+        self.sample.process(buffer);
+        self.samples += buffer.len() as u64;
+
+        for value in buffer.iter_mut() {
+            *value *= self.gain;
+            self.level = f32::max(value.abs(), self.level * LEVEL_DECAY);
+        }
+
+        // The fade falls in a straight line to nothing over the samples that remain, so cutting
+        // the remaining samples short makes it steeper without a jump in level.
+        if let Some(remaining) = &mut self.fading {
+            for value in buffer.iter_mut() {
+                self.fade -= self.fade / (*remaining + 1) as f32;
+                *value *= self.fade;
+                *remaining = remaining.saturating_sub(1);
+            }
+        }
+
+        if let Some(meter) = &mut self.meter {
+            meter.process(buffer);
+        }
+    }
+
+    /// A voice is done once its source has finished or it has been stolen and faded out.
+    fn finished(&self) -> bool {
+        self.sample.finished() || self.fading == Some(0)
+    }
 }
 
-/// The mixer combines a set of playing samples wrapped in adsr envelopes and mixes them together, removing samples once they are finished.
+/// A change to a playing voice that can be scheduled for a particular frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    // Send the voice a note off.
+    Release,
+    Gain(f32),
+    Pan(f32),
+
+    // Set the level the voice sends to a bus.
+    Send(BusId, f32),
+
+    // Set a parameter of the voice's source.
+    Parameter(Parameter, f32),
+}
+
+/// A change to a bus or an insert that can be scheduled for a particular frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MixAction {
+    BusGain(BusId, f32),
+    Mute(BusId, bool),
+    Solo(BusId, bool),
+
+    // Set a parameter of the insert in a slot of the master or a bus.
+    Insert(Target, usize, usize, f32),
+}
+
+/// What happens when a scheduled event comes due.
+enum Pending {
+    // Start a voice, with the note it plays and its priority.
+    Start(VoiceId, Voice, Option<u8>, u8),
+    Change(VoiceId, Action),
+    Mix(MixAction),
+}
+
+/// An event waiting in a mixer for the frame it happens on.
+struct Scheduled {
+    frame: u64,
+    pending: Pending,
+}
+
+/// Which voice gives way when a new voice would take the mixer over its voice limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StealPolicy {
+    // The voice that has been playing longest.
+    Oldest,
+
+    // The voice with the lowest recent level.
+    Quietest,
+
+    // A voice playing the same note as the new voice, which it retriggers. This applies whether or
+    // not the mixer is full, and the oldest voice is stolen when no voice shares the note.
+    SameNote,
+
+    // The voice with the lowest priority, and the oldest of those.
+    LowestPriority,
+}
+
+impl FromStr for StealPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "oldest" => Ok(StealPolicy::Oldest),
+            "quietest" => Ok(StealPolicy::Quietest),
+            "same-note" => Ok(StealPolicy::SameNote),
+            "lowest-priority" => Ok(StealPolicy::LowestPriority),
+            _ => Err(format!(
+                "unknown steal policy {}, expected oldest, quietest, same-note or lowest-priority",
+                name
+            )),
+        }
+    }
+}
+
+/// The mixer combines a set of playing sources (typically samples wrapped in adsr envelopes) and mixes them together,
+/// removing sources once they are finished. The mix is not clipped, so it should pass through a `Master` on its way
+/// to the output.
 pub struct Mixer {
     chunks: Vec<Chunk>,
+    next_id: u64,
+    pan_law: PanLaw,
+
+    // The most voices that can play at once, not counting stolen voices fading out.
+    max_voices: Option<usize>,
+    policy: StealPolicy,
+
+    // The number of samples a stolen voice takes to fade out.
+    fade_length: usize,
+
+    // Space to render each voice into when processing a block, and the most samples of a block
+    // the buses have room for.
+    scratch: Vec<f32>,
+    block_size: usize,
+
+    buses: Vec<Bus>,
+
+    // The inserts on the master.
+    effects: Vec<Box<dyn Effect + Send>>,
+
+    // Where the sources of removed voices are sent to be dropped, if anywhere.
+    retired: Option<Producer<Voice>>,
+
+    // The number of frames mixed so far, which scheduled events are timed against.
+    frame: u64,
+
+    // Events that have not come due, latest first so the next one can be popped off the end.
+    // Events for the same frame happen in the order they were scheduled.
+    scheduled: Vec<Scheduled>,
+
+    // The most events that can be waiting at once, if the mixer has room set aside for them.
+    max_events: Option<usize>,
+
+    // The sample rate voice meters run at, when the mixer meters its voices.
+    metering: Option<f32>,
+}
+
+/// Where a value panned to a position between -1 and 1 goes in a frame of `channels` channels. The
+/// channels are spread evenly from left to right and the value is split between the two nearest,
+/// so this returns the first of those channels and the gains for it and the one after it.
+fn pan_position(channels: usize, pan: f32, pan_law: PanLaw) -> (usize, f32, f32) {
+    if channels < 2 {
+        return (0, 1., 0.);
+    }
+
+    let position = (pan.clamp(-1., 1.) + 1.) / 2. * (channels - 1) as f32;
+    let left = (position.floor() as usize).min(channels - 2);
+    let (left_gain, right_gain) = pan_law.gains(2. * (position - left as f32) - 1.);
+    (left, left_gain, right_gain)
+}
+
+/// Add a panned value into a frame.
+fn pan_into(frame: &mut [f32], value: f32, (left, left_gain, right_gain): (usize, f32, f32)) {
+    match frame.len() {
+        0 => {}
+        1 => frame[0] += value,
+        _ => {
+            frame[left] += value * left_gain;
+            frame[left + 1] += value * right_gain;
+        }
+    }
 }
 
 impl Mixer {
     pub fn new() -> Self {
-        Mixer { chunks: Vec::new() }
+        Mixer {
+            chunks: Vec::new(),
+            next_id: 0,
+            pan_law: PanLaw::ConstantPower,
+            max_voices: None,
+            policy: StealPolicy::Oldest,
+            fade_length: 0,
+            scratch: Vec::new(),
+            block_size: 0,
+            buses: Vec::new(),
+            effects: Vec::new(),
+            retired: None,
+            frame: 0,
+            scheduled: Vec::new(),
+            max_events: None,
+            metering: None,
+        }
     }
 
-    pub fn add_sample(&mut self, sample: Adsr) {
+    /// Limit the number of voices playing at once. When a new voice would go over the limit one
+    /// is stolen according to `policy` and fades out over `fade_length` samples. Mixers have no
+    /// limit by default. Room for every playing and fading voice is allocated up front, so adding
+    /// a voice never allocates.
+    pub fn with_voice_limit(
+        mut self,
+        max_voices: usize,
+        policy: StealPolicy,
+        fade_length: usize,
+    ) -> Self {
+        let max_voices = max_voices.max(1);
+        self.chunks.reserve(2 * max_voices);
+        Mixer {
+            max_voices: Some(max_voices),
+            policy,
+            fade_length,
+            ..self
+        }
+    }
+
+    /// Allocate room to process blocks of up to `frames` frames of `channels` channels, so
+    /// processing them never allocates.
+    pub fn with_block_size(mut self, frames: usize, channels: usize) -> Self {
+        self.scratch.resize(frames, 0.);
+        self.block_size = frames * channels;
+        for bus in self.buses.iter_mut() {
+            bus.buffer.resize(self.block_size, 0.);
+        }
+        self
+    }
+
+    /// Allocate room for `events` scheduled events to be waiting at once, so scheduling never
+    /// allocates. Once that many are waiting the earliest is carried out straight away to make
+    /// room for the next, so events can happen early but are never lost.
+    pub fn with_event_capacity(mut self, events: usize) -> Self {
+        self.scheduled.reserve_exact(events);
+        self.max_events = Some(events);
+        self
+    }
+
+    /// Meter every voice added from now on. Meters are held inline, so metering a voice does not
+    /// allocate.
+    pub fn with_metering(self, sample_rate: f32) -> Self {
+        Mixer {
+            metering: Some(sample_rate),
+            ..self
+        }
+    }
+
+    /// Send the sources of finished and stolen voices to a queue rather than dropping them, so
+    /// a mixer on the audio thread can leave freeing them to another thread. In one block a
+    /// mixer can retire every voice it holds and every voice waiting to start, so the voice limit
+    /// and event capacity must be set first and the queue must have room for twice the limit and
+    /// all the events. Voices are still dropped in place if the queue is not emptied between
+    /// blocks and fills up.
+    pub fn with_retirement(self, retired: Producer<Voice>) -> Result<Self, Box<dyn Error>> {
+        let max_voices = self
+            .max_voices
+            .ok_or("a mixer needs a voice limit to retire voices to a queue")?;
+        let needed = 2 * max_voices + self.max_events.unwrap_or(0);
+        if retired.capacity() < needed {
+            return Err(format!("a retirement queue needs room for {} voices", needed).into());
+        }
+
+        Ok(Mixer {
+            retired: Some(retired),
+            ..self
+        })
+    }
+
+    /// Choose how voices are panned. Mixers pan at constant power by default.
+    pub fn with_pan_law(self, pan_law: PanLaw) -> Self {
+        Mixer { pan_law, ..self }
+    }
+
+    fn new_id(&mut self) -> VoiceId {
+        let id = VoiceId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn start(&mut self, id: VoiceId, sample: Voice, note: Option<u8>, priority: u8) {
+        self.make_room(note);
         self.chunks.push(Chunk {
+            id,
             sample,
-            samples: 0.,
+            samples: 0,
+            gain: 1.,
+            pan: 0.,
+            note,
+            priority,
+            level: 0.,
+            fading: None,
+            fade: 1.,
+            meter: self.metering.map(|rate| Meter::new(rate, 1)),
+            sends: [0.; MAX_BUSES],
         });
     }
 
+    /// Add an aux bus with a name that no other bus has. Voices send nothing to a new bus until
+    /// their send level for it is set.
+    pub fn add_bus(&mut self, name: &str) -> Result<BusId, Box<dyn Error>> {
+        if self.buses.len() == MAX_BUSES {
+            return Err(format!("a mixer can have at most {} buses", MAX_BUSES).into());
+        }
+        if self.bus(name).is_some() {
+            return Err(format!("there is already a bus called {}", name).into());
+        }
+
+        self.buses.push(Bus {
+            name: name.to_string(),
+            buffer: vec![0.; self.block_size],
+            effects: Vec::new(),
+            gain: 1.,
+            mute: false,
+            solo: false,
+        });
+        Ok(BusId(self.buses.len() - 1))
+    }
+
+    /// Find a bus by name.
+    pub fn bus(&self, name: &str) -> Option<BusId> {
+        self.buses
+            .iter()
+            .position(|bus| bus.name == name)
+            .map(BusId)
+    }
+
+    /// Add an effect to the end of the inserts on the master or a bus, returning its slot.
+    pub fn add_insert<E: Effect + Send + 'static>(&mut self, target: Target, effect: E) -> usize {
+        let effects = self.effects_mut(target);
+        effects.push(Box::new(effect));
+        effects.len() - 1
+    }
+
+    fn effects_mut(&mut self, target: Target) -> &mut Vec<Box<dyn Effect + Send>> {
+        match target {
+            Target::Master => &mut self.effects,
+            Target::Bus(BusId(bus)) => &mut self.buses[bus].effects,
+        }
+    }
+
+    /// Set a parameter of the insert in a slot. Slots that are empty are ignored.
+    pub fn set_insert_parameter(
+        &mut self,
+        target: Target,
+        slot: usize,
+        parameter: usize,
+        value: f32,
+    ) {
+        if let Some(effect) = self.effects_mut(target).get_mut(slot) {
+            effect.set_parameter(parameter, value);
+        }
+    }
+
+    /// Change the level a bus is mixed in at. Buses start at a gain of one.
+    pub fn set_bus_gain(&mut self, BusId(bus): BusId, gain: f32) {
+        self.buses[bus].gain = gain;
+    }
+
+    /// Silence a bus. Its inserts keep running so effect tails carry on underneath.
+    pub fn set_mute(&mut self, BusId(bus): BusId, mute: bool) {
+        self.buses[bus].mute = mute;
+    }
+
+    /// While any bus is soloed only the soloed buses are heard, and the dry voices are silenced
+    /// too.
+    pub fn set_solo(&mut self, BusId(bus): BusId, solo: bool) {
+        self.buses[bus].solo = solo;
+    }
+
+    /// The number of frames mixed so far. Events scheduled for this frame happen before the next
+    /// frame is mixed.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Start playing a source on a given frame, returning the id the voice will have so that
+    /// changes to it can be scheduled too. A voice scheduled for a frame that has already been
+    /// mixed starts before the next frame.
+    pub fn schedule_voice(
+        &mut self,
+        frame: u64,
+        sample: Voice,
+        note: Option<u8>,
+        priority: u8,
+    ) -> VoiceId {
+        let id = self.new_id();
+        self.insert(frame, Pending::Start(id, sample, note, priority));
+        id
+    }
+
+    /// Change a voice on a given frame. Changes to voices that have finished by then are ignored.
+    pub fn schedule(&mut self, frame: u64, id: VoiceId, action: Action) {
+        self.insert(frame, Pending::Change(id, action));
+    }
+
+    /// Change a bus or an insert on a given frame. Buses and inserts process a whole block at a
+    /// time, so the change holds for all of the block it falls in.
+    pub fn schedule_mix(&mut self, frame: u64, action: MixAction) {
+        self.insert(frame, Pending::Mix(action));
+    }
+
+    fn insert(&mut self, frame: u64, pending: Pending) {
+        // A full queue makes room by carrying out its earliest event, which may be the new one.
+        if self
+            .max_events
+            .is_some_and(|max| self.scheduled.len() >= max)
+        {
+            match self.scheduled.last() {
+                Some(earliest) if earliest.frame <= frame => {
+                    let earliest = self.scheduled.pop().unwrap();
+                    self.apply(earliest.pending);
+                }
+                _ => return self.apply(pending),
+            }
+        }
+
+        let index = self.scheduled.partition_point(|event| event.frame > frame);
+        self.scheduled.insert(index, Scheduled { frame, pending });
+    }
+
+    fn apply(&mut self, pending: Pending) {
+        match pending {
+            Pending::Start(id, sample, note, priority) => self.start(id, sample, note, priority),
+            Pending::Change(id, Action::Release) => self.release(id),
+            Pending::Change(id, Action::Gain(gain)) => self.set_gain(id, gain),
+            Pending::Change(id, Action::Pan(pan)) => self.set_pan(id, pan),
+            Pending::Change(id, Action::Send(bus, level)) => self.set_send(id, bus, level),
+            Pending::Change(id, Action::Parameter(parameter, value)) => {
+                self.set_parameter(id, parameter, value)
+            }
+            Pending::Mix(MixAction::BusGain(bus, gain)) => self.set_bus_gain(bus, gain),
+            Pending::Mix(MixAction::Mute(bus, mute)) => self.set_mute(bus, mute),
+            Pending::Mix(MixAction::Solo(bus, solo)) => self.set_solo(bus, solo),
+            Pending::Mix(MixAction::Insert(target, slot, parameter, value)) => {
+                self.set_insert_parameter(target, slot, parameter, value)
+            }
+        }
+    }
+
+    /// Carry out every event due by the current frame.
+    fn apply_due(&mut self) {
+        while self.scheduled.last().is_some_and(|x| x.frame <= self.frame) {
+            let Scheduled { pending, .. } = self.scheduled.pop().unwrap();
+            self.apply(pending);
+        }
+    }
+
+    /// Pick the voice to steal for a new voice playing `note`, if there is one to steal.
+    fn victim(&self, note: Option<u8>) -> Option<usize> {
+        let playing = || {
+            self.chunks
+                .iter()
+                .enumerate()
+                .filter(|(_, chunk)| chunk.fading.is_none())
+        };
+        let oldest = || playing().max_by_key(|(_, chunk)| chunk.samples);
+
+        let active = playing().count();
+        let full = self.max_voices.is_some_and(|max| active >= max);
+
+        let same_note = match (self.policy, note) {
+            (StealPolicy::SameNote, Some(note)) => {
+                playing().find(|(_, chunk)| chunk.note == Some(note))
+            }
+            _ => None,
+        };
+        if same_note.is_some() {
+            return same_note.map(|(i, _)| i);
+        }
+        if !full {
+            return None;
+        }
+
+        let victim = match self.policy {
+            StealPolicy::Oldest | StealPolicy::SameNote => oldest(),
+            StealPolicy::Quietest => playing().min_by(|(_, a), (_, b)| a.level.total_cmp(&b.level)),
+            StealPolicy::LowestPriority => playing()
+                .min_by_key(|(_, chunk)| (chunk.priority, std::cmp::Reverse(chunk.samples))),
+        };
+        victim.map(|(i, _)| i)
+    }
+
+    /// Steal a voice if the policy calls for it. When as many voices are fading as may play, the
+    /// oldest of them have their fades shortened, and only if there is still no room left for a
+    /// new voice is the oldest fading voice cut off.
+    fn make_room(&mut self, note: Option<u8>) {
+        if let Some(victim) = self.victim(note) {
+            if self.fade_length == 0 {
+                self.retire(victim);
+            } else {
+                self.chunks[victim].fading = Some(self.fade_length);
+            }
+        }
+
+        if let Some(max) = self.max_voices {
+            let slow = |chunk: &Chunk| chunk.fading.is_some_and(|x| x > SHORT_FADE);
+            while self.chunks.iter().filter(|x| slow(x)).count() >= max {
+                if let Some(oldest) = self.oldest_fading(slow) {
+                    self.chunks[oldest].fading = Some(SHORT_FADE);
+                }
+            }
+
+            if self.chunks.len() >= 2 * max {
+                if let Some(oldest) = self.oldest_fading(|chunk| chunk.fading.is_some()) {
+                    self.retire(oldest);
+                }
+            }
+        }
+    }
+
+    /// The index of the voice that has played longest of those matching `f`.
+    fn oldest_fading(&self, f: impl Fn(&Chunk) -> bool) -> Option<usize> {
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, chunk)| f(chunk))
+            .max_by_key(|(_, chunk)| chunk.samples)
+            .map(|(i, _)| i)
+    }
+
+    /// Remove a voice, passing its source on to be dropped elsewhere if the mixer has somewhere to
+    /// send it.
+    fn retire(&mut self, index: usize) {
+        let chunk = self.chunks.remove(index);
+        if let Some(retired) = &mut self.retired {
+            let _ = retired.push(chunk.sample);
+        }
+    }
+
+    /// Call `f` on every voice in turn, removing each voice that has finished afterwards.
+    fn for_each_voice(&mut self, mut f: impl FnMut(&mut Chunk)) {
+        let mut i = 0;
+        while i < self.chunks.len() {
+            f(&mut self.chunks[i]);
+            if self.chunks[i].finished() {
+                self.retire(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    fn chunk_mut(&mut self, id: VoiceId) -> Option<&mut Chunk> {
+        self.chunks.iter_mut().find(|chunk| chunk.id == id)
+    }
+
+    /// Change the level of a voice. Voices start at a gain of one.
+    pub fn set_gain(&mut self, id: VoiceId, gain: f32) {
+        if let Some(chunk) = self.chunk_mut(id) {
+            chunk.gain = gain;
+        }
+    }
+
+    /// Move a voice between the left (-1) and right (1) speakers. Voices start in the centre.
+    pub fn set_pan(&mut self, id: VoiceId, pan: f32) {
+        if let Some(chunk) = self.chunk_mut(id) {
+            chunk.pan = pan;
+        }
+    }
+
+    /// Set the level a voice sends to a bus.
+    pub fn set_send(&mut self, id: VoiceId, BusId(bus): BusId, level: f32) {
+        if let Some(chunk) = self.chunk_mut(id) {
+            chunk.sends[bus] = level;
+        }
+    }
+
+    /// Set a parameter of the source of a voice.
+    pub fn set_parameter(&mut self, id: VoiceId, parameter: Parameter, value: f32) {
+        if let Some(chunk) = self.chunk_mut(id) {
+            chunk.sample.set_parameter(parameter, value);
+        }
+    }
+
+    /// Send a note off to a single voice. Voices that have already finished are ignored.
+    pub fn release(&mut self, id: VoiceId) {
+        if let Some(chunk) = self.chunk_mut(id) {
+            chunk.sample.release();
+        }
+    }
+
+    /// Mix every voice down to a single channel, ignoring pan.
     pub fn next(&mut self) -> f32 {
-        let mut sampled = 0.;
+        let mut frame = [0.];
+        self.process_frames(&mut frame, 1);
+        frame[0]
+    }
 
-        self.chunks.drain_filter(|sample| {
-            sampled += sample.sample.next(sample.samples);
-            sample.samples += 1.;
-            sample.sample.finished()
+    /// Mix the next block of every voice into a buffer of interleaved frames of `channels`
+    /// channels. The block is split at every scheduled event so each one happens on its exact
+    /// frame. Each voice renders the pieces in between at once, and is placed according to its pan
+    /// at the start of each piece. Voices that finish during a piece are removed at the end of it.
+    ///
+    /// Once the voices are mixed each bus runs its inserts over the whole block and is added to
+    /// the dry mix, and then the master inserts run over the result.
+    pub fn process_frames(&mut self, buffer: &mut [f32], channels: usize) {
+        for value in buffer.iter_mut() {
+            *value = 0.;
+        }
+
+        let channels = channels.max(1);
+        let frames = buffer.len() / channels;
+        if self.scratch.len() < frames {
+            self.scratch.resize(frames, 0.);
+        }
+        for bus in self.buses.iter_mut() {
+            if bus.buffer.len() < frames * channels {
+                bus.buffer.resize(frames * channels, 0.);
+            }
+            for value in bus.buffer[..frames * channels].iter_mut() {
+                *value = 0.;
+            }
+        }
+
+        let mut start = 0;
+        while start < frames {
+            self.apply_due();
+            let end = match self.scheduled.last() {
+                Some(event) => {
+                    start + (event.frame - self.frame).min((frames - start) as u64) as usize
+                }
+                None => frames,
+            };
+
+            self.mix_into(buffer, channels, start, end);
+            self.frame += (end - start) as u64;
+            start = end;
+        }
+
+        let soloing = self.buses.iter().any(|bus| bus.solo);
+        for bus in self.buses.iter_mut() {
+            let block = &mut bus.buffer[..frames * channels];
+            for effect in bus.effects.iter_mut() {
+                effect.process(block, channels);
+            }
+
+            if !bus.mute && (bus.solo || !soloing) {
+                for (value, sent) in buffer.iter_mut().zip(block.iter()) {
+                    *value += sent * bus.gain;
+                }
+            }
+        }
+
+        for effect in self.effects.iter_mut() {
+            effect.process(buffer, channels);
+        }
+    }
+
+    /// Add frames `start` to `end` of every voice into a buffer of interleaved frames, and into
+    /// the buses they send to.
+    fn mix_into(&mut self, buffer: &mut [f32], channels: usize, start: usize, end: usize) {
+        let frames = end - start;
+        let samples = start * channels..end * channels;
+        let dry = !self.buses.iter().any(|bus| bus.solo);
+
+        // The scratch space and buses are moved out while the voices are processed and put back
+        // after.
+        let mut scratch = std::mem::take(&mut self.scratch);
+        let mut buses = std::mem::take(&mut self.buses);
+        let pan_law = self.pan_law;
+        self.for_each_voice(|sample| {
+            let position = pan_position(channels, sample.pan + sample.sample.pan(), pan_law);
+            sample.process(&mut scratch[..frames]);
+            if dry {
+                for (frame, value) in buffer[samples.clone()]
+                    .chunks_mut(channels)
+                    .zip(scratch.iter())
+                {
+                    pan_into(frame, *value, position);
+                }
+            }
+
+            for (bus, send) in buses.iter_mut().zip(sample.sends.iter()) {
+                if *send != 0. {
+                    for (frame, value) in bus.buffer[samples.clone()]
+                        .chunks_mut(channels)
+                        .zip(scratch.iter())
+                    {
+                        pan_into(frame, *value * send, position);
+                    }
+                }
+            }
         });
+        self.scratch = scratch;
+        self.buses = buses;
+    }
+
+    /// The readings of the meters of the voices playing, if the mixer meters.
+    pub fn voice_readings(&self) -> impl Iterator<Item = (VoiceId, Reading)> + '_ {
+        self.chunks.iter().filter_map(|chunk| {
+            chunk
+                .meter
+                .as_ref()
+                .map(|meter| (chunk.id, meter.reading()))
+        })
+    }
+
+    /// The number of voices playing, including stolen voices that are fading out.
+    pub fn voices(&self) -> usize {
+        self.chunks.len()
+    }
+}
+
+impl Source for Mixer {
+    fn next(&mut self) -> f32 {
+        Mixer::next(self)
+    }
+
+    fn process(&mut self, buffer: &mut [f32]) {
+        self.process_frames(buffer, 1)
+    }
+
+    /// Releasing a mixer releases every voice in it.
+    fn release(&mut self) {
+        for chunk in self.chunks.iter_mut() {
+            chunk.sample.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod mixer_tests {
+    use super::{Action, MixAction, Mixer, PanLaw, StealPolicy, Target, Voice, VoiceId, MAX_BUSES};
+    use crate::adsr::Adsr;
+    use crate::effect::Effect;
+    use crate::modulation::{Destination, Modulated, Modulator};
+    use crate::ring::ring;
+    use crate::sample::Sample;
+    use crate::source::{Parameter, Source};
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    /// Start a voice straight away by scheduling it for the current frame and carrying out the
+    /// events that are due.
+    fn add_voice(mixer: &mut Mixer, sample: Voice, note: Option<u8>, priority: u8) -> VoiceId {
+        let id = mixer.schedule_voice(mixer.frame(), sample, note, priority);
+        mixer.apply_due();
+        id
+    }
+
+    /// A user defined source that counts down to zero and then finishes.
+    struct Countdown(u32);
+
+    impl Source for Countdown {
+        fn next(&mut self) -> f32 {
+            self.0 = self.0.saturating_sub(1);
+            0.1
+        }
+
+        fn finished(&self) -> bool {
+            self.0 == 0
+        }
+    }
+
+    /// A source that plays a level, which can be set as its first effect parameter, until it is
+    /// released and then finishes.
+    struct Gate {
+        level: f32,
+        released: bool,
+    }
+
+    impl Gate {
+        fn new() -> Box<Self> {
+            Box::new(Gate {
+                level: 1.,
+                released: false,
+            })
+        }
+    }
+
+    impl Source for Gate {
+        fn next(&mut self) -> f32 {
+            if self.released {
+                0.
+            } else {
+                self.level
+            }
+        }
+
+        fn finished(&self) -> bool {
+            self.released
+        }
+
+        fn release(&mut self) {
+            self.released = true;
+        }
+
+        fn set_parameter(&mut self, parameter: Parameter, value: f32) {
+            if parameter == Parameter::Effect(0) {
+                self.level = value;
+            }
+        }
+    }
+
+    /// A mixer with a voice scheduled to start on frame 10 and be released on frame 30, with its
+    /// gain halved on frame 15 and its level raised on frame 20.
+    fn scheduled() -> Mixer {
+        let mut mixer = Mixer::new();
+        let id = mixer.schedule_voice(10, Gate::new(), None, 0);
+        mixer.schedule(30, id, Action::Release);
+        mixer.schedule(20, id, Action::Parameter(Parameter::Effect(0), 2.));
+        mixer.schedule(15, id, Action::Gain(0.5));
+        mixer
+    }
+
+    fn expected_schedule(frame: usize) -> f32 {
+        match frame {
+            10..=14 => 1.,
+            15..=19 => 0.5,
+            20..=29 => 1.,
+            _ => 0.,
+        }
+    }
+
+    #[test]
+    fn scheduled_events_happen_on_their_frame() {
+        let mut mixer = scheduled();
+        for frame in 0..40 {
+            assert_eq!(mixer.next(), expected_schedule(frame), "frame {}", frame);
+        }
+        assert_eq!(mixer.voices(), 0);
+        assert_eq!(mixer.frame(), 40);
+    }
+
+    #[test]
+    fn scheduled_events_split_blocks() {
+        for block in [1, 3, 7, 16, 64] {
+            let mut mixer = scheduled();
+            let mut buffer = vec![0.; 2 * block];
+            let mut frame = 0;
+            while frame < 64 {
+                mixer.process_frames(&mut buffer, 2);
+                for value in buffer.chunks(2) {
+                    let expected = expected_schedule(frame) * FRAC_1_SQRT_2;
+                    assert!((value[0] - expected).abs() < 1e-6, "{} {}", block, frame);
+                    frame += 1;
+                }
+            }
+            assert_eq!(mixer.voices(), 0);
+        }
+    }
+
+    #[test]
+    fn late_and_simultaneous_events() {
+        let mut mixer = Mixer::new();
+        for _ in 0..10 {
+            mixer.next();
+        }
+
+        // Events for frames already mixed happen straight away, and events for the same frame
+        // happen in the order they were scheduled.
+        let id = mixer.schedule_voice(5, Gate::new(), None, 0);
+        mixer.schedule(12, id, Action::Gain(0.5));
+        mixer.schedule(12, id, Action::Gain(0.25));
+        let played: Vec<f32> = (0..4).map(|_| mixer.next()).collect();
+        assert_eq!(played, vec![1., 1., 0.25, 0.25]);
+    }
+
+    #[test]
+    fn full_event_queue_never_grows_or_loses_events() {
+        let mut mixer = Mixer::new().with_event_capacity(4);
+        let capacity = mixer.scheduled.capacity();
+        let mut buffer = [0.; 3];
+
+        // Each short block schedules a gate to open and close well after the block ends, so more
+        // events are waiting than the mixer has room for.
+        for i in 0..10 {
+            let id = mixer.schedule_voice(100 + 10 * i, Gate::new(), None, 0);
+            mixer.schedule(105 + 10 * i, id, Action::Release);
+            mixer.process_frames(&mut buffer, 1);
+            assert!(mixer.scheduled.len() <= 4);
+            assert_eq!(mixer.scheduled.capacity(), capacity);
+        }
+
+        // The earliest events were carried out early to make room, but every gate still closes.
+        for _ in 0..100 {
+            mixer.process_frames(&mut buffer, 1);
+        }
+        assert_eq!(mixer.voices(), 0);
+        assert!(mixer.scheduled.is_empty());
+    }
+
+    #[test]
+    fn custom_sources_are_mixed_and_removed() {
+        let mut mixer = Mixer::new();
+        add_voice(&mut mixer, Box::new(Countdown(3)), None, 0);
+        add_voice(&mut mixer, Box::new(Countdown(5)), None, 0);
+
+        let played: Vec<f32> = (0..6).map(|_| mixer.next()).collect();
+        assert_eq!(played, vec![0.2, 0.2, 0.2, 0.1, 0.1, 0.]);
+    }
+
+    #[test]
+    fn release_targets_one_voice() {
+        let mut mixer = Mixer::new();
+        let first = add_voice(
+            &mut mixer,
+            Box::new(Adsr::gated(
+                Sample::middle_a(100.),
+                100.,
+                0.,
+                0.5,
+                0.,
+                0.5,
+                0.1,
+            )),
+            None,
+            0,
+        );
+        let _second = add_voice(
+            &mut mixer,
+            Box::new(Adsr::gated(
+                Sample::middle_c(100.),
+                100.,
+                0.,
+                0.5,
+                0.,
+                0.5,
+                0.1,
+            )),
+            None,
+            0,
+        );
+
+        mixer.release(first);
+        for _ in 0..20 {
+            mixer.next();
+        }
+
+        assert_eq!(mixer.chunks.len(), 1);
+        assert_ne!(mixer.chunks[0].id, first);
+    }
+
+    #[test]
+    fn envelope_can_wrap_a_mixer() {
+        let mixed = || {
+            let mut mixer = Mixer::new();
+            add_voice(&mut mixer, Box::new(Sample::middle_a(100.)), None, 0);
+            add_voice(&mut mixer, Box::new(Countdown(1000)), None, 0);
+            mixer
+        };
+
+        // A 0.1 second attack at 100hz reaches full level after ten samples.
+        let mut envelope = Adsr::new(mixed(), 100., 0.1, 1., 0., 10., 1., 0.);
+        let mut unshaped = mixed();
+
+        for i in 1..10 {
+            let expected = unshaped.next() * i as f32 / 10.;
+            assert!((envelope.next() - expected).abs() < 1e-6);
+        }
+
+        // The shaped mixer can itself be mixed.
+        let mut outer = Mixer::new();
+        add_voice(&mut outer, Box::new(envelope), None, 0);
+        assert!(outer.next().abs() <= 1.);
+    }
+
+    #[test]
+    fn block_render_matches_per_sample() {
+        let mut per_sample = Adsr::new(
+            Sample::middle_c(44100.),
+            44100.,
+            0.01,
+            1.,
+            0.01,
+            0.1,
+            0.5,
+            0.1,
+        );
+        let mut block = Adsr::new(
+            Sample::middle_c(44100.),
+            44100.,
+            0.01,
+            1.,
+            0.01,
+            0.1,
+            0.5,
+            0.1,
+        );
+
+        let mut buffer = [0.; 512];
+        block.process(&mut buffer);
+
+        for value in buffer.iter() {
+            assert_eq!(per_sample.next(), *value);
+        }
+    }
+
+    /// The level in decibels of a gain.
+    fn decibels(gain: f32) -> f32 {
+        20. * gain.log10()
+    }
+
+    #[test]
+    fn pan_laws_at_the_centre_and_edges() {
+        for (law, centre) in [
+            (PanLaw::Linear, -6.02),
+            (PanLaw::ConstantPower, -3.01),
+            (PanLaw::Compromise, -4.52),
+        ] {
+            let (left, right) = law.gains(0.);
+            assert!((decibels(left) - centre).abs() < 0.01, "{:?}", law);
+            assert!((left - right).abs() < 1e-6);
+
+            let (left, right) = law.gains(-1.);
+            assert!((left - 1.).abs() < 1e-6 && right.abs() < 1e-6, "{:?}", law);
+            let (left, right) = law.gains(1.);
+            assert!(left.abs() < 1e-6 && (right - 1.).abs() < 1e-6, "{:?}", law);
+        }
+
+        // Constant power keeps the total power the same wherever the voice is.
+        for pan in [-0.7, -0.2, 0.4, 0.9] {
+            let (left, right) = PanLaw::ConstantPower.gains(pan);
+            assert!((left * left + right * right - 1.).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn pan_laws_by_name() {
+        assert_eq!("compromise".parse::<PanLaw>(), Ok(PanLaw::Compromise));
+        assert!("equal".parse::<PanLaw>().is_err());
+    }
+
+    #[test]
+    fn steal_policies_by_name() {
+        assert_eq!(
+            "lowest-priority".parse::<StealPolicy>(),
+            Ok(StealPolicy::LowestPriority)
+        );
+        assert!("newest".parse::<StealPolicy>().is_err());
+    }
+
+    #[test]
+    fn stereo_frames_follow_gain_and_pan() {
+        let mut mixer = Mixer::new().with_pan_law(PanLaw::Linear);
+        let left = add_voice(&mut mixer, Box::new(Countdown(10)), None, 0);
+        let centre = add_voice(&mut mixer, Box::new(Countdown(10)), None, 0);
+        mixer.set_pan(left, -1.);
+        mixer.set_gain(centre, 2.);
+
+        let mut frame = [0.; 2];
+        mixer.process_frames(&mut frame, 2);
+        assert!((frame[0] - 0.2).abs() < 1e-6);
+        assert!((frame[1] - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn frames_spread_over_more_channels() {
+        let mut mixer = Mixer::new().with_pan_law(PanLaw::Linear);
+        let voice = add_voice(&mut mixer, Box::new(Countdown(10)), None, 0);
+
+        // With four channels the speakers sit at -1, -1/3, 1/3 and 1.
+        mixer.set_pan(voice, 1. / 3.);
+        let mut frame = [0.; 4];
+        mixer.process_frames(&mut frame, 4);
+        assert!(frame[0].abs() < 1e-6 && frame[1].abs() < 1e-6 && frame[3].abs() < 1e-6);
+        assert!((frame[2] - 0.1).abs() < 1e-6);
+
+        let mut mono = [0.; 1];
+        mixer.set_pan(voice, -1.);
+        mixer.process_frames(&mut mono, 1);
+        assert!((mono[0] - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sources_can_pan_themselves() {
+        let mut mixer = Mixer::new();
+        let voice = Modulated::new(Sample::middle_a(100.))
+            .with_modulator(Modulator::Velocity(1.))
+            .with_route(0, Destination::Pan, 1.)
+            .unwrap();
+        add_voice(&mut mixer, Box::new(voice), None, 0);
+
+        let mut frame = [0.; 2];
+        for _ in 0..10 {
+            mixer.process_frames(&mut frame, 2);
+            assert!(frame[0].abs() < 1e-6);
+        }
+    }
+
+    /// A quiet, slow sine that fades in so that adding one never causes a jump on its own.
+    fn soft_voice(frequency: f32) -> Adsr {
+        let sine = Sample::Sin {
+            rate: 44100.,
+            frequency,
+            phase: 0.,
+        };
+        Adsr::gated(sine, 44100., 0.002, 0.25, 0., 0.25, 0.01)
+    }
+
+    #[test]
+    fn voice_count_is_bounded_without_discontinuities() {
+        for policy in [
+            StealPolicy::Oldest,
+            StealPolicy::Quietest,
+            StealPolicy::SameNote,
+            StealPolicy::LowestPriority,
+        ] {
+            let mut mixer = Mixer::new().with_voice_limit(4, policy, 64);
+            let mut previous = 0.;
+            for i in 0..8000 {
+                if i % 200 == 0 {
+                    let note = (i / 200 % 3) as u8;
+                    add_voice(
+                        &mut mixer,
+                        Box::new(soft_voice(20. + note as f32)),
+                        Some(note),
+                        0,
+                    );
+                }
+                let playing = mixer.chunks.iter().filter(|x| x.fading.is_none()).count();
+                assert!(playing <= 4 && mixer.voices() <= 8, "{:?}", policy);
+
+                let value = mixer.next();
+                assert!((value - previous).abs() < 0.02, "{:?} {}", policy, i);
+                previous = value;
+            }
+        }
+    }
+
+    #[test]
+    fn fades_are_shortened_rather_than_cut() {
+        let mut mixer = Mixer::new().with_voice_limit(2, StealPolicy::Oldest, 1000);
+        let mut previous = 0.;
+        for i in 0..4000 {
+            if i % 100 == 0 {
+                add_voice(&mut mixer, Box::new(soft_voice(30.)), None, 0);
+            }
+            assert!(mixer.voices() <= 4);
+
+            let value = mixer.next();
+            assert!((value - previous).abs() < 0.02, "{}", i);
+            previous = value;
+        }
+    }
+
+    #[test]
+    fn retirement_queue_holds_the_worst_case() {
+        let mixer = || {
+            Mixer::new()
+                .with_voice_limit(4, StealPolicy::Oldest, 0)
+                .with_event_capacity(8)
+        };
+        assert!(mixer().with_retirement(ring(16).0).is_ok());
+        assert!(mixer().with_retirement(ring(8).0).is_err());
+        assert!(Mixer::new().with_retirement(ring(64).0).is_err());
+    }
+
+    #[test]
+    fn oldest_voice_is_stolen() {
+        let mut mixer = Mixer::new().with_voice_limit(2, StealPolicy::Oldest, 0);
+        let first = add_voice(&mut mixer, Box::new(Countdown(100)), None, 0);
+        mixer.next();
+        let second = add_voice(&mut mixer, Box::new(Countdown(100)), None, 0);
+        mixer.next();
+        let third = add_voice(&mut mixer, Box::new(Countdown(100)), None, 0);
+
+        let ids: Vec<_> = mixer.chunks.iter().map(|x| x.id).collect();
+        assert_eq!(ids, vec![second, third]);
+        assert!(!ids.contains(&first));
+    }
+
+    #[test]
+    fn quietest_voice_is_stolen() {
+        let mut mixer = Mixer::new().with_voice_limit(2, StealPolicy::Quietest, 0);
+        let loud = add_voice(&mut mixer, Box::new(Countdown(100)), None, 0);
+        let quiet = add_voice(&mut mixer, Box::new(Countdown(100)), None, 0);
+        mixer.set_gain(quiet, 0.1);
+        mixer.next();
+        add_voice(&mut mixer, Box::new(Countdown(100)), None, 0);
+
+        assert!(mixer.chunks.iter().any(|x| x.id == loud));
+        assert!(!mixer.chunks.iter().any(|x| x.id == quiet));
+    }
+
+    #[test]
+    fn same_note_retriggers_below_the_limit() {
+        let mut mixer = Mixer::new().with_voice_limit(8, StealPolicy::SameNote, 10);
+        let first = add_voice(&mut mixer, Box::new(Countdown(100)), Some(60), 0);
+        add_voice(&mut mixer, Box::new(Countdown(100)), Some(62), 0);
+        add_voice(&mut mixer, Box::new(Countdown(100)), Some(60), 0);
+
+        // The first voice fades out over ten samples and is then removed.
+        assert_eq!(mixer.chunks[0].fading, Some(10));
+        for _ in 0..11 {
+            mixer.next();
+        }
+        assert_eq!(mixer.voices(), 2);
+        assert!(!mixer.chunks.iter().any(|x| x.id == first));
+    }
+
+    #[test]
+    fn lowest_priority_voice_is_stolen() {
+        let mut mixer = Mixer::new().with_voice_limit(2, StealPolicy::LowestPriority, 0);
+        let important = add_voice(&mut mixer, Box::new(Countdown(100)), None, 5);
+        mixer.next();
+        let background = add_voice(&mut mixer, Box::new(Countdown(100)), None, 1);
+        add_voice(&mut mixer, Box::new(Countdown(100)), None, 3);
+
+        assert!(mixer.chunks.iter().any(|x| x.id == important));
+        assert!(!mixer.chunks.iter().any(|x| x.id == background));
+    }
+
+    #[test]
+    fn block_frames_match_per_sample_frames() {
+        let voices = || {
+            let mut mixer = Mixer::new();
+            let left = add_voice(
+                &mut mixer,
+                Box::new(Adsr::new(
+                    Sample::middle_a(44100.),
+                    44100.,
+                    0.01,
+                    1.,
+                    0.01,
+                    0.02,
+                    0.5,
+                    0.01,
+                )),
+                None,
+                0,
+            );
+            mixer.set_pan(left, -0.5);
+            add_voice(
+                &mut mixer,
+                Box::new(Adsr::gated(
+                    Sample::middle_c(44100.),
+                    44100.,
+                    0.,
+                    0.5,
+                    0.,
+                    0.5,
+                    0.,
+                )),
+                None,
+                0,
+            );
+            mixer
+        };
+
+        let mut per_sample = voices();
+        let mut block = voices();
+        let mut buffer = [0.; 2 * 256];
+
+        for _ in 0..10 {
+            block.process_frames(&mut buffer, 2);
+            for frame in buffer.chunks(2) {
+                let mut expected = [0.; 2];
+                per_sample.process_frames(&mut expected, 2);
+                assert!((frame[0] - expected[0]).abs() < 1e-6);
+                assert!((frame[1] - expected[1]).abs() < 1e-6);
+            }
+        }
+        assert_eq!(block.voices(), 1);
+    }
+
+    /// Render a second of two thousand voices a frame at a time and in blocks and report the
+    /// speedup. Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn block_rendering_benchmark() {
+        use std::time::Instant;
+
+        const RATE: f32 = 44100.;
+        let voices = || {
+            let mut mixer = Mixer::new();
+            for i in 0..2000 {
+                let sine = Sample::Sin {
+                    rate: RATE,
+                    frequency: 100. + i as f32,
+                    phase: 0.,
+                };
+                let voice = add_voice(
+                    &mut mixer,
+                    Box::new(Adsr::gated(sine, RATE, 0.01, 0.5, 0.1, 0.3, 0.1)),
+                    None,
+                    0,
+                );
+                mixer.set_pan(voice, (i % 3) as f32 - 1.);
+            }
+            mixer
+        };
+
+        let mut mixer = voices();
+        let start = Instant::now();
+        let mut frame = [0.; 2];
+        let mut total = 0.;
+        for _ in 0..RATE as usize {
+            mixer.process_frames(&mut frame, 2);
+            total += frame[0];
+        }
+        let per_sample = start.elapsed();
+
+        let mut mixer = voices();
+        let start = Instant::now();
+        let mut buffer = [0.; 2 * 512];
+        for _ in 0..RATE as usize / 512 {
+            mixer.process_frames(&mut buffer, 2);
+            total += buffer[0];
+        }
+        let block = start.elapsed();
+
+        println!(
+            "per sample {:?}, blocks of 512 {:?}, {:.1}x faster ({})",
+            per_sample,
+            block,
+            per_sample.as_secs_f64() / block.as_secs_f64(),
+            total
+        );
+        assert!(block < per_sample);
+    }
+
+    #[test]
+    fn meters_follow_voices() {
+        let sine = || Sample::Sin {
+            rate: 44100.,
+            frequency: 997.,
+            phase: 0.,
+        };
+
+        let mut mixer = Mixer::new();
+        add_voice(&mut mixer, Box::new(sine()), None, 0);
+        mixer.next();
+        assert_eq!(mixer.voice_readings().count(), 0);
+
+        let mut mixer = Mixer::new().with_metering(44100.);
+        let loud = add_voice(&mut mixer, Box::new(sine()), None, 0);
+        let quiet = add_voice(&mut mixer, Box::new(sine()), None, 0);
+        mixer.set_gain(quiet, 0.1);
+        let mut buffer = vec![0.; 2 * 44100];
+        mixer.process_frames(&mut buffer, 2);
+
+        for (id, reading) in mixer.voice_readings() {
+            let (peak, loudness) = if id == loud {
+                (1., -3.01)
+            } else {
+                (0.1, -23.01)
+            };
+            assert!((reading.peak - peak).abs() < 1e-3);
+            assert!((reading.momentary - loudness).abs() < 0.1, "{:?}", reading);
+        }
+    }
+
+    /// An insert that scales what passes through it by a gain, which is its first parameter.
+    struct Scale(f32);
+
+    impl Effect for Scale {
+        fn process(&mut self, buffer: &mut [f32], _channels: usize) {
+            for value in buffer.iter_mut() {
+                *value *= self.0;
+            }
+        }
+
+        fn set_parameter(&mut self, parameter: usize, value: f32) {
+            if parameter == 0 {
+                self.0 = value;
+            }
+        }
+    }
+
+    /// The next few frames of a mono mix, which should all be the same.
+    fn level(mixer: &mut Mixer) -> f32 {
+        let mut buffer = [0.; 4];
+        mixer.process_frames(&mut buffer, 1);
+        assert!(buffer.iter().all(|value| *value == buffer[0]));
+        buffer[0]
+    }
+
+    #[test]
+    fn sends_go_through_bus_inserts() {
+        let mut mixer = Mixer::new().with_block_size(4, 1);
+        let bus = mixer.add_bus("reverb").unwrap();
+        assert_eq!(mixer.add_insert(Target::Bus(bus), Scale(3.)), 0);
+        add_voice(&mut mixer, Gate::new(), None, 0);
+        let sent = add_voice(&mut mixer, Gate::new(), None, 0);
+        mixer.set_send(sent, bus, 0.5);
+        assert_eq!(level(&mut mixer), 2. + 0.5 * 3.);
+
+        mixer.set_insert_parameter(Target::Bus(bus), 0, 0, 1.);
+        assert_eq!(level(&mut mixer), 2.5);
+
+        // Empty slots are ignored.
+        mixer.set_insert_parameter(Target::Bus(bus), 1, 0, 0.);
+        mixer.set_bus_gain(bus, 2.);
+        assert_eq!(level(&mut mixer), 3.);
+
+        mixer.add_insert(Target::Master, Scale(0.5));
+        assert_eq!(level(&mut mixer), 1.5);
+    }
+
+    #[test]
+    fn muted_and_soloed_buses() {
+        let mut mixer = Mixer::new();
+        let first = mixer.add_bus("first").unwrap();
+        let second = mixer.add_bus("second").unwrap();
+        let id = add_voice(&mut mixer, Gate::new(), None, 0);
+        mixer.set_send(id, first, 1.);
+        mixer.set_send(id, second, 1.);
+        assert_eq!(level(&mut mixer), 3.);
+
+        mixer.set_mute(first, true);
+        assert_eq!(level(&mut mixer), 2.);
+
+        // Soloing silences the dry voices and every other bus, and a muted bus stays muted.
+        mixer.set_solo(second, true);
+        assert_eq!(level(&mut mixer), 1.);
+        mixer.set_solo(first, true);
+        assert_eq!(level(&mut mixer), 1.);
+        mixer.set_mute(first, false);
+        assert_eq!(level(&mut mixer), 2.);
+
+        mixer.set_solo(first, false);
+        mixer.set_solo(second, false);
+        assert_eq!(level(&mut mixer), 3.);
+    }
+
+    #[test]
+    fn scheduled_sends() {
+        let mut mixer = Mixer::new();
+        let bus = mixer.add_bus("delay").unwrap();
+        let id = mixer.schedule_voice(2, Gate::new(), None, 0);
+        mixer.schedule(5, id, Action::Send(bus, 1.));
+
+        let mut buffer = [0.; 8];
+        mixer.process_frames(&mut buffer, 1);
+        assert_eq!(buffer, [0., 0., 1., 1., 1., 2., 2., 2.]);
+    }
+
+    #[test]
+    fn scheduled_bus_changes() {
+        let mut mixer = Mixer::new();
+        let bus = mixer.add_bus("reverb").unwrap();
+        mixer.add_insert(Target::Bus(bus), Scale(1.));
+        let id = add_voice(&mut mixer, Gate::new(), None, 0);
+        mixer.set_send(id, bus, 1.);
+        mixer.schedule_mix(4, MixAction::Insert(Target::Bus(bus), 0, 0, 2.));
+        mixer.schedule_mix(8, MixAction::BusGain(bus, 0.5));
+        mixer.schedule_mix(12, MixAction::Solo(bus, true));
+        mixer.schedule_mix(16, MixAction::Mute(bus, true));
+
+        let levels: Vec<_> = (0..5).map(|_| level(&mut mixer)).collect();
+        assert_eq!(levels, [2., 3., 2., 1., 0.]);
+    }
+
+    #[test]
+    fn bus_names_are_unique() {
+        let mut mixer = Mixer::new();
+        let bus = mixer.add_bus("reverb").unwrap();
+        assert!(mixer.add_bus("reverb").is_err());
+        assert_eq!(mixer.bus("reverb"), Some(bus));
+        assert_eq!(mixer.bus("delay"), None);
 
-        f32::max(f32::min(sampled, 1.0), -1.)
+        for i in 1..MAX_BUSES {
+            assert!(mixer.add_bus(&i.to_string()).is_ok());
+        }
+        assert!(mixer.add_bus("one too many").is_err());
     }
 }
@@ -0,0 +1,502 @@
+/**
+ * A modulation matrix for a single voice. Modulators (envelopes, lfos, the velocity and key of the
+ * note, a random value chosen when the note starts) produce control signals which are routed, each with a
+ * depth, to destinations on the source they wrap: its pitch, pulse width, amplitude, pan and any
+ * effect parameter. Modulators can be evaluated every sample or once per block of samples to save
+ * work.
+ */
+use crate::envelope::Envelope;
+use crate::lfo::Lfo;
+use crate::sample::Sample;
+use crate::source::{Parameter, Source};
+use rand::{distributions::uniform::Uniform, rngs::SmallRng, Rng};
+use std::error::Error;
+
+/// A control signal that can be routed to a destination.
+#[derive(Debug)]
+pub enum Modulator {
+    // An envelope, from zero up to its peak level.
+    Envelope(Envelope),
+
+    // A low frequency oscillator, from -1 to 1.
+    Lfo(Lfo),
+
+    // The velocity the note was played with, from 0 to 1.
+    Velocity(f32),
+
+    // The midi note number of the note. This is measured in octaves from middle c so a depth of one
+    // on the frequency tracks the keyboard.
+    Key(u8),
+
+    // A value between -1 and 1 chosen when the note started.
+    Random(f32),
+}
+
+impl Modulator {
+    /// A random modulator drawing its value from `rng`.
+    pub fn random(rng: &mut SmallRng) -> Self {
+        Modulator::Random(rng.sample(Uniform::new(-1., 1.)))
+    }
+
+    /// Return the value of the modulator and advance it by a number of samples, so the first
+    /// evaluation sees every modulator where it starts.
+    pub fn next(&mut self, samples: usize) -> f32 {
+        match self {
+            Modulator::Envelope(envelope) => {
+                let level = envelope.level();
+                for _ in 0..samples {
+                    envelope.next();
+                }
+                level
+            }
+            Modulator::Lfo(lfo) => lfo.next(samples),
+            Modulator::Velocity(velocity) => *velocity,
+            Modulator::Key(note) => (*note as f32 - 60.) / 12.,
+            Modulator::Random(value) => *value,
+        }
+    }
+
+    /// Restart an lfo for a new note. Every other modulator already starts with its voice.
+    pub fn trigger(&mut self) {
+        if let Modulator::Lfo(lfo) = self {
+            lfo.trigger();
+        }
+    }
+
+    pub fn release(&mut self) {
+        if let Modulator::Envelope(envelope) = self {
+            envelope.release();
+        }
+    }
+}
+
+/// Where a modulator is routed and how the modulation is applied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Destination {
+    // The pitch, in octaves above or below the base frequency.
+    Frequency,
+
+    // The pulse width, added to the base duty cycle.
+    Duty,
+
+    // The gain of the voice, added to a base gain of one.
+    Amplitude,
+
+    // The position of the voice between the left (-1) and right (1) speakers, added to centre.
+    Pan,
+
+    // A parameter of an effect, added to its base value.
+    Effect(usize),
+}
+
+impl Destination {
+    /// The parameter of the source this destination changes, if it is one.
+    fn parameter(&self) -> Option<Parameter> {
+        match self {
+            Destination::Frequency => Some(Parameter::Frequency),
+            Destination::Duty => Some(Parameter::Duty),
+            Destination::Effect(index) => Some(Parameter::Effect(*index)),
+            Destination::Amplitude | Destination::Pan => None,
+        }
+    }
+}
+
+/// A connection from a modulator to a destination.
+#[derive(Debug)]
+struct Route {
+    modulator: usize,
+    destination: Destination,
+    depth: f32,
+}
+
+/// A parameter of the source being modulated along with its unmodulated value.
+#[derive(Debug)]
+struct Target {
+    parameter: Parameter,
+    base: f32,
+    offset: f32,
+}
+
+/// A source whose parameters are driven by a set of modulators.
+#[derive(Debug)]
+pub struct Modulated<S: Source = Sample> {
+    source: S,
+    modulators: Vec<Modulator>,
+    routes: Vec<Route>,
+    targets: Vec<Target>,
+
+    // The value of each modulator at the last evaluation.
+    values: Vec<f32>,
+
+    // The number of samples between each evaluation of the modulators and the count of samples
+    // until the next one.
+    interval: usize,
+    countdown: usize,
+
+    // The gain and pan set by the last evaluation.
+    gain: f32,
+    pan: f32,
+
+    // Set once the modulators have been triggered by the first evaluation.
+    started: bool,
+}
+
+impl<S: Source> Modulated<S> {
+    pub fn new(source: S) -> Self {
+        Modulated {
+            source,
+            modulators: Vec::new(),
+            routes: Vec::new(),
+            targets: Vec::new(),
+            values: Vec::new(),
+            interval: 1,
+            countdown: 0,
+            gain: 1.,
+            pan: 0.,
+            started: false,
+        }
+    }
+
+    /// Add a modulator. Modulators are numbered from zero in the order they are added.
+    pub fn with_modulator(mut self, modulator: Modulator) -> Self {
+        self.modulators.push(modulator);
+        self.values.push(0.);
+        self
+    }
+
+    /// Route a modulator to a destination, scaling its value by `depth`.
+    pub fn with_route(
+        mut self,
+        modulator: usize,
+        destination: Destination,
+        depth: f32,
+    ) -> Result<Self, Box<dyn Error>> {
+        if modulator >= self.modulators.len() {
+            return Err("routes must start from one of the modulators".into());
+        }
+
+        if let Some(parameter) = destination.parameter() {
+            if !self.targets.iter().any(|x| x.parameter == parameter) {
+                let base = self
+                    .source
+                    .parameter(parameter)
+                    .ok_or("the source does not have the parameter being modulated")?;
+                self.targets.push(Target {
+                    parameter,
+                    base,
+                    offset: 0.,
+                });
+            }
+        }
+
+        self.routes.push(Route {
+            modulator,
+            destination,
+            depth,
+        });
+        Ok(self)
+    }
+
+    /// Evaluate the modulators once every `samples` samples rather than every sample.
+    pub fn with_interval(self, samples: usize) -> Self {
+        Modulated {
+            interval: samples.max(1),
+            ..self
+        }
+    }
+
+    /// Advance the modulators and apply their values to the destinations. The first update
+    /// triggers the modulators, as the voice starts playing.
+    fn update(&mut self) {
+        if !self.started {
+            for modulator in self.modulators.iter_mut() {
+                modulator.trigger();
+            }
+            self.started = true;
+        }
+
+        for (modulator, value) in self.modulators.iter_mut().zip(self.values.iter_mut()) {
+            *value = modulator.next(self.interval);
+        }
+
+        for target in self.targets.iter_mut() {
+            target.offset = 0.;
+        }
+        let mut gain = 1.;
+        let mut pan = 0.;
+
+        for route in self.routes.iter() {
+            let amount = self.values[route.modulator] * route.depth;
+            match route.destination {
+                Destination::Amplitude => gain += amount,
+                Destination::Pan => pan += amount,
+                destination => {
+                    let parameter = destination.parameter();
+                    for target in self.targets.iter_mut() {
+                        if Some(target.parameter) == parameter {
+                            target.offset += amount;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.gain = gain.max(0.);
+        self.pan = pan.clamp(-1., 1.);
+
+        for target in self.targets.iter() {
+            let value = match target.parameter {
+                Parameter::Frequency => target.base * 2f32.powf(target.offset),
+                Parameter::Duty | Parameter::Effect(_) => target.base + target.offset,
+            };
+            self.source.set_parameter(target.parameter, value);
+        }
+    }
+
+    pub fn next(&mut self) -> f32 {
+        if self.countdown == 0 {
+            self.update();
+            self.countdown = self.interval;
+        }
+        self.countdown -= 1;
+
+        self.source.next() * self.gain
+    }
+
+    /// Fill a buffer a control interval at a time, updating the modulation between intervals.
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        let mut start = 0;
+        while start < buffer.len() {
+            if self.countdown == 0 {
+                self.update();
+                self.countdown = self.interval;
+            }
+
+            let end = buffer.len().min(start + self.countdown);
+            let block = &mut buffer[start..end];
+            self.source.process(block);
+            for value in block.iter_mut() {
+                *value *= self.gain;
+            }
+
+            self.countdown -= end - start;
+            start = end;
+        }
+    }
+}
+
+impl<S: Source> Source for Modulated<S> {
+    fn next(&mut self) -> f32 {
+        Modulated::next(self)
+    }
+
+    fn finished(&self) -> bool {
+        self.source.finished()
+    }
+
+    fn process(&mut self, buffer: &mut [f32]) {
+        Modulated::process(self, buffer)
+    }
+
+    fn release(&mut self) {
+        for modulator in self.modulators.iter_mut() {
+            modulator.release();
+        }
+        self.source.release();
+    }
+
+    fn parameter(&self, parameter: Parameter) -> Option<f32> {
+        self.source.parameter(parameter)
+    }
+
+    /// Setting a modulated parameter moves the value the modulation is applied around.
+    fn set_parameter(&mut self, parameter: Parameter, value: f32) {
+        match self.targets.iter_mut().find(|x| x.parameter == parameter) {
+            Some(target) => target.base = value,
+            None => self.source.set_parameter(parameter, value),
+        }
+    }
+
+    /// The pan set by the modulators on top of any pan of the source.
+    fn pan(&self) -> f32 {
+        (self.pan + self.source.pan()).clamp(-1., 1.)
+    }
+}
+
+#[cfg(test)]
+mod modulation_tests {
+    use super::{Destination, Modulated, Modulator};
+    use crate::envelope::Envelope;
+    use crate::lfo::{Lfo, Rate, Shape};
+    use crate::sample::Sample;
+    use crate::source::{Parameter, Source};
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    const RATE: f32 = 1000.;
+
+    #[test]
+    fn key_tracks_frequency_by_octaves() {
+        let mut voice = Modulated::new(Sample::middle_c(RATE))
+            .with_modulator(Modulator::Key(72))
+            .with_route(0, Destination::Frequency, 1.)
+            .unwrap();
+
+        let base = Sample::middle_c(RATE).frequency();
+        voice.next();
+        let frequency = voice.parameter(Parameter::Frequency).unwrap();
+        assert!((frequency - 2. * base).abs() < 1e-3);
+    }
+
+    #[test]
+    fn envelope_sweeps_pulse_width() {
+        let square = Sample::Square {
+            duty: 0.5,
+            rate: RATE,
+            frequency: 10.,
+            phase: 0.,
+        };
+        let mut voice = Modulated::new(square)
+            .with_modulator(Modulator::Envelope(Envelope::gated(
+                RATE, 0.1, 1., 0., 1., 0.1,
+            )))
+            .with_route(0, Destination::Duty, 0.4)
+            .unwrap();
+
+        let mut widths = Vec::new();
+        for _ in 0..100 {
+            voice.next();
+            widths.push(voice.parameter(Parameter::Duty).unwrap());
+        }
+
+        // The first sample is played with the envelope where it starts, at zero.
+        assert_eq!(widths[0], 0.5);
+        assert!((widths[50] - 0.7).abs() < 1e-4);
+        assert!(widths.windows(2).all(|x| x[1] >= x[0]));
+    }
+
+    #[test]
+    fn velocity_scales_amplitude() {
+        let mut plain = Sample::middle_a(RATE);
+        let mut voice = Modulated::new(Sample::middle_a(RATE))
+            .with_modulator(Modulator::Velocity(0.25))
+            .with_route(0, Destination::Amplitude, -2.)
+            .unwrap();
+
+        for _ in 0..100 {
+            assert!((voice.next() - 0.5 * plain.next()).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn routes_add_up_and_pan_is_clamped() {
+        let mut voice = Modulated::new(Sample::middle_a(RATE))
+            .with_modulator(Modulator::Random(0.5))
+            .with_modulator(Modulator::Velocity(1.))
+            .with_route(0, Destination::Pan, 1.)
+            .unwrap()
+            .with_route(1, Destination::Pan, 0.25)
+            .unwrap();
+        voice.next();
+        assert_eq!(voice.pan(), 0.75);
+
+        let mut voice = Modulated::new(Sample::middle_a(RATE))
+            .with_modulator(Modulator::Velocity(1.))
+            .with_route(0, Destination::Pan, -3.)
+            .unwrap();
+        voice.next();
+        assert_eq!(voice.pan(), -1.);
+    }
+
+    #[test]
+    fn block_rate_holds_between_updates() {
+        let mut voice = Modulated::new(Sample::middle_a(RATE))
+            .with_modulator(Modulator::Envelope(Envelope::gated(
+                RATE, 1., 1., 0., 1., 0.1,
+            )))
+            .with_route(0, Destination::Frequency, 1.)
+            .unwrap()
+            .with_interval(10);
+
+        let frequencies: Vec<f32> = (0..30)
+            .map(|_| {
+                voice.next();
+                voice.parameter(Parameter::Frequency).unwrap()
+            })
+            .collect();
+
+        assert!(frequencies[..10].iter().all(|x| *x == frequencies[0]));
+        assert!(frequencies[10..20].iter().all(|x| *x == frequencies[10]));
+        assert!(frequencies[10] > frequencies[0]);
+
+        // The envelope starts from zero and is advanced by a whole block at each update.
+        assert_eq!(frequencies[0], 440.);
+        let expected = 440. * 2f32.powf(0.01);
+        assert!((frequencies[10] - expected).abs() < 1e-2);
+    }
+
+    #[test]
+    fn lfos_restart_when_the_voice_starts() {
+        let mut lfo = Lfo::new(
+            &mut SmallRng::seed_from_u64(1),
+            RATE,
+            Shape::Sawtooth,
+            Rate::Hertz(1.),
+        );
+        lfo.next(300);
+
+        // At the start of its cycle the sawtooth is at its lowest, an octave down.
+        let mut voice = Modulated::new(Sample::middle_a(RATE))
+            .with_modulator(Modulator::Lfo(lfo))
+            .with_route(0, Destination::Frequency, 1.)
+            .unwrap();
+        voice.next();
+        assert!((voice.parameter(Parameter::Frequency).unwrap() - 220.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn retuning_moves_the_base() {
+        let mut voice = Modulated::new(Sample::middle_a(RATE))
+            .with_modulator(Modulator::Velocity(1.))
+            .with_route(0, Destination::Frequency, 1.)
+            .unwrap();
+        voice.set_parameter(Parameter::Frequency, 100.);
+        voice.next();
+        assert!((voice.parameter(Parameter::Frequency).unwrap() - 200.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn invalid_routes() {
+        assert!(Modulated::new(Sample::middle_a(RATE))
+            .with_route(0, Destination::Frequency, 1.)
+            .is_err());
+        assert!(Modulated::new(Sample::middle_a(RATE))
+            .with_modulator(Modulator::Velocity(1.))
+            .with_route(0, Destination::Duty, 1.)
+            .is_err());
+    }
+
+    #[test]
+    fn block_processing_matches_per_sample() {
+        let voice = || {
+            Modulated::new(Sample::middle_a(RATE))
+                .with_modulator(Modulator::Envelope(Envelope::gated(
+                    RATE, 0.1, 1., 0., 1., 0.1,
+                )))
+                .with_route(0, Destination::Frequency, 1.)
+                .unwrap()
+                .with_route(0, Destination::Amplitude, -0.5)
+                .unwrap()
+                .with_interval(7)
+        };
+
+        let mut per_sample = voice();
+        let mut block = voice();
+        let mut buffer = [0.; 50];
+        for _ in 0..4 {
+            block.process(&mut buffer);
+            for value in buffer.iter() {
+                assert_eq!(per_sample.next(), *value);
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+/**
+ * Noise generators for percussion and test signals. Every generator owns its own random number
+ * generator, seeded from a parent SmallRng, so a render is reproducible from the same seed.
+ */
+use rand::{distributions::uniform::Uniform, rngs::SmallRng, Rng, SeedableRng};
+
+/// Split a new generator off from a parent so noise sources do not share random state.
+fn child_rng(rng: &mut SmallRng) -> SmallRng {
+    SmallRng::from_rng(rng).expect("SmallRng seeding from another SmallRng cannot fail")
+}
+
+/// A uniformly distributed random value in [-1, 1).
+fn white(rng: &mut SmallRng) -> f32 {
+    rng.sample(Uniform::new(-1., 1.))
+}
+
+// The RMS level every colour of noise is scaled to. Pink and brown noise have much higher peaks
+// for their level than white noise does, so this leaves them room to stay within [-1, 1] almost
+// all of the time.
+const NOISE_RMS: f32 = 0.3;
+
+// The RMS level of a uniformly distributed value in [-1, 1), which is one over the root of three.
+const WHITE_RMS: f32 = 0.57735;
+
+/// Uncorrelated noise with a flat spectrum.
+#[derive(Debug)]
+pub struct WhiteNoise {
+    rng: SmallRng,
+}
+
+impl WhiteNoise {
+    pub fn new(rng: &mut SmallRng) -> Self {
+        WhiteNoise {
+            rng: child_rng(rng),
+        }
+    }
+
+    pub fn next(&mut self) -> f32 {
+        white(&mut self.rng) * NOISE_RMS / WHITE_RMS
+    }
+}
+
+// The number of white noise rows summed by the Voss-McCartney algorithm. Each row updates at half
+// the rate of the one before it so the spectrum falls at roughly 3db per octave.
+const PINK_ROWS: usize = 16;
+
+/// Noise with equal energy per octave, generated with the Voss-McCartney algorithm.
+#[derive(Debug)]
+pub struct PinkNoise {
+    rng: SmallRng,
+    rows: [f32; PINK_ROWS],
+    running_sum: f32,
+    counter: u32,
+}
+
+impl PinkNoise {
+    pub fn new(rng: &mut SmallRng) -> Self {
+        let mut rng = child_rng(rng);
+        let mut rows = [0.; PINK_ROWS];
+        for row in rows.iter_mut() {
+            *row = white(&mut rng);
+        }
+
+        PinkNoise {
+            rng,
+            running_sum: rows.iter().sum(),
+            rows,
+            counter: 0,
+        }
+    }
+
+    pub fn next(&mut self) -> f32 {
+        self.counter = self.counter.wrapping_add(1);
+
+        // The number of trailing zeros picks the row to update so row n changes every 2^n samples.
+        let row = self.counter.trailing_zeros() as usize;
+        if row < PINK_ROWS {
+            let value = white(&mut self.rng);
+            self.running_sum += value - self.rows[row];
+            self.rows[row] = value;
+        }
+
+        // The rows and the fresh white value are independent, so the RMS level of their sum grows
+        // with the root of their number. The rare peaks that still go over are clipped.
+        let rms = WHITE_RMS * ((PINK_ROWS + 1) as f32).sqrt();
+        let value = (self.running_sum + white(&mut self.rng)) * NOISE_RMS / rms;
+        value.clamp(-1., 1.)
+    }
+}
+
+// How far each white noise value moves brown noise before the leak is applied.
+const BROWN_STEP: f32 = 0.02;
+
+/// Noise with a spectrum falling at 6db per octave, made by integrating white noise with a small
+/// leak so it does not wander away from zero.
+#[derive(Debug)]
+pub struct BrownNoise {
+    rng: SmallRng,
+    level: f32,
+}
+
+impl BrownNoise {
+    pub fn new(rng: &mut SmallRng) -> Self {
+        BrownNoise {
+            rng: child_rng(rng),
+            level: 0.,
+        }
+    }
+
+    pub fn next(&mut self) -> f32 {
+        // The leak balances the largest step once the level reaches one, so the level itself
+        // never leaves the usual range.
+        self.level = (self.level + BROWN_STEP * white(&mut self.rng)) / (1. + BROWN_STEP);
+
+        // The level settles where the leak removes as much power as each step adds. The rare peaks
+        // that go over once it is scaled up to the same RMS as the other colours are clipped.
+        let rms = WHITE_RMS * BROWN_STEP / ((1. + BROWN_STEP) * (1. + BROWN_STEP) - 1.).sqrt();
+        (self.level * NOISE_RMS / rms).clamp(-1., 1.)
+    }
+}
+
+/// The 15 bit linear feedback shift register noise channel found in 8-bit consoles. The register is
+/// clocked at `frequency` and outputs a pseudo-random square wave. In short mode the feedback tap
+/// moves so the sequence repeats every 93 steps, producing a metallic, pitched tone.
+#[derive(Debug)]
+pub struct LfsrNoise {
+    register: u16,
+    short_mode: bool,
+    rate: f32,
+    frequency: f32,
+    phase: f32,
+}
+
+impl LfsrNoise {
+    pub fn new(rng: &mut SmallRng, rate: f32, frequency: f32, short_mode: bool) -> Self {
+        // The register locks up if it is ever all zeros.
+        let register = rng.sample(Uniform::new(1u16, 1 << 15));
+        LfsrNoise {
+            register,
+            short_mode,
+            rate,
+            frequency,
+            phase: 0.,
+        }
+    }
+
+    pub fn frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    pub fn set_frequency(&mut self, frequency: f32) {
+        self.frequency = frequency;
+    }
+
+    fn clock(&mut self) {
+        let tap = if self.short_mode { 6 } else { 1 };
+        let feedback = (self.register ^ (self.register >> tap)) & 1;
+        self.register = (self.register >> 1) | (feedback << 14);
+    }
+
+    pub fn next(&mut self) -> f32 {
+        let value = if self.register & 1 == 0 {
+            NOISE_RMS
+        } else {
+            -NOISE_RMS
+        };
+
+        self.phase += self.frequency / self.rate;
+        while self.phase >= 1. {
+            self.phase -= 1.;
+            self.clock();
+        }
+
+        value
+    }
+}
+
+#[cfg(test)]
+mod noise_tests {
+    use super::{BrownNoise, LfsrNoise, PinkNoise, WhiteNoise};
+    use crate::sample::Sample;
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    /// The RMS level of a second of noise, in decibels.
+    fn level(mut next: impl FnMut() -> f32) -> f32 {
+        let energy: f32 = (0..44100).map(|_| next().powi(2)).sum();
+        10. * (energy / 44100.).log10()
+    }
+
+    /// The mean squared difference between neighbouring samples, which is large for noise with
+    /// a lot of high frequency energy and small for noise dominated by low frequencies.
+    fn roughness(mut next: impl FnMut() -> f32) -> f32 {
+        let mut last = next();
+        let mut total = 0.;
+        let mut energy = 0.;
+        for _ in 0..44100 {
+            let value = next();
+            total += (value - last) * (value - last);
+            energy += value * value;
+            last = value;
+        }
+        total / energy
+    }
+
+    #[test]
+    fn seeded_noise_is_reproducible() {
+        let mut first = WhiteNoise::new(&mut SmallRng::seed_from_u64(7));
+        let mut second = WhiteNoise::new(&mut SmallRng::seed_from_u64(7));
+        let mut other = WhiteNoise::new(&mut SmallRng::seed_from_u64(8));
+
+        let first: Vec<f32> = (0..100).map(|_| first.next()).collect();
+        let second: Vec<f32> = (0..100).map(|_| second.next()).collect();
+        let other: Vec<f32> = (0..100).map(|_| other.next()).collect();
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn noise_stays_in_range() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let mut white = WhiteNoise::new(&mut rng);
+        let mut pink = PinkNoise::new(&mut rng);
+        let mut brown = BrownNoise::new(&mut rng);
+        for _ in 0..44100 {
+            assert!(white.next().abs() <= 1.);
+            assert!(pink.next().abs() <= 1.);
+            assert!(brown.next().abs() <= 1.);
+        }
+    }
+
+    #[test]
+    fn samples_play_noise() {
+        let mut rng = SmallRng::seed_from_u64(2);
+        let mut samples = [
+            Sample::white_noise(&mut rng),
+            Sample::pink_noise(&mut rng),
+            Sample::brown_noise(&mut rng),
+            Sample::lfsr_noise(&mut rng, 44100., 4000., false),
+        ];
+        for sample in samples.iter_mut() {
+            let values: Vec<f32> = (0..1000).map(|_| sample.next()).collect();
+            assert!(values.iter().all(|x| x.abs() <= 1.));
+            assert!(values.iter().any(|x| *x != values[0]));
+        }
+
+        // Only the shift register has a pitch.
+        assert_eq!(samples[1].frequency(), 0.);
+        assert_eq!(samples[3].frequency(), 4000.);
+    }
+
+    #[test]
+    fn colours_are_equally_loud() {
+        let mut rng = SmallRng::seed_from_u64(4);
+        let mut white = WhiteNoise::new(&mut rng);
+        let mut pink = PinkNoise::new(&mut rng);
+        let mut brown = BrownNoise::new(&mut rng);
+        let mut lfsr = LfsrNoise::new(&mut rng, 44100., 4000., false);
+
+        let white = level(|| white.next());
+        for (colour, level) in [
+            ("pink", level(|| pink.next())),
+            ("brown", level(|| brown.next())),
+            ("lfsr", level(|| lfsr.next())),
+        ] {
+            assert!(
+                (level - white).abs() < 2.,
+                "white {}db {} {}db",
+                white,
+                colour,
+                level
+            );
+        }
+    }
+
+    #[test]
+    fn spectra_tilt_downwards() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        let mut white = WhiteNoise::new(&mut rng);
+        let mut pink = PinkNoise::new(&mut rng);
+        let mut brown = BrownNoise::new(&mut rng);
+
+        let white = roughness(|| white.next());
+        let pink = roughness(|| pink.next());
+        let brown = roughness(|| brown.next());
+
+        assert!(white > pink, "white {} pink {}", white, pink);
+        assert!(pink > brown, "pink {} brown {}", pink, brown);
+    }
+
+    /// Clock the register once per sample and find how many steps it takes to repeat.
+    fn period(short_mode: bool) -> usize {
+        let mut lfsr = LfsrNoise::new(&mut SmallRng::seed_from_u64(5), 1., 1., short_mode);
+
+        // Step past any lead in before the register settles into its cycle.
+        for _ in 0..40000 {
+            lfsr.next();
+        }
+
+        let start = lfsr.register;
+        let mut steps = 0;
+        loop {
+            lfsr.next();
+            steps += 1;
+            if lfsr.register == start {
+                return steps;
+            }
+        }
+    }
+
+    #[test]
+    fn lfsr_periods() {
+        assert_eq!(period(false), 32767);
+        assert_eq!(period(true), 93);
+    }
+}
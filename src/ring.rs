@@ -0,0 +1,200 @@
+/**
+ * A bounded lock-free queue between exactly one producer and one consumer, for passing values to
+ * and from the audio thread. The slots are allocated when the queue is made, so pushing and popping
+ * never allocate, lock or block: a push to a full queue hands the value back and a pop from an
+ * empty queue returns nothing.
+ */
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The storage shared by both ends of a queue. The positions only ever count up (wrapping), and a
+/// slot is found by masking a position with the capacity, which is a power of two.
+struct Shared<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    mask: usize,
+
+    // The position of the next value to pop, only written by the consumer.
+    head: AtomicUsize,
+
+    // The position the next value is pushed to, only written by the producer.
+    tail: AtomicUsize,
+}
+
+// The producer only writes slots the consumer has finished with and the consumer only reads slots
+// the producer has finished with, so the values are only ever touched from one thread at a time.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let tail = *self.tail.get_mut();
+        let mut head = *self.head.get_mut();
+        while head != tail {
+            unsafe { self.slots[head & self.mask].get_mut().assume_init_drop() };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+/// The end of a queue that values are pushed into.
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The end of a queue that values are popped from.
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+unsafe impl<T: Send> Send for Producer<T> {}
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+/// Make a queue that holds at least `capacity` values, rounded up to a power of two.
+pub fn ring<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let capacity = capacity.max(1).next_power_of_two();
+    let shared = Arc::new(Shared {
+        slots: (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        mask: capacity - 1,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+impl<T> Producer<T> {
+    /// Add a value to the back of the queue, or give it back if the queue is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let shared = &*self.shared;
+        let tail = shared.tail.load(Ordering::Relaxed);
+        let head = shared.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) > shared.mask {
+            return Err(value);
+        }
+
+        unsafe { (*shared.slots[tail & shared.mask].get()).write(value) };
+        shared.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// The most values the queue holds at once.
+    pub fn capacity(&self) -> usize {
+        self.shared.mask + 1
+    }
+}
+
+impl<T> Consumer<T> {
+    /// Take the value at the front of the queue, if there is one.
+    pub fn pop(&mut self) -> Option<T> {
+        let shared = &*self.shared;
+        let head = shared.head.load(Ordering::Relaxed);
+        let tail = shared.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        let value = unsafe { (*shared.slots[head & shared.mask].get()).assume_init_read() };
+        shared.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+/// Iterating a consumer pops values until the queue is empty.
+impl<T> Iterator for Consumer<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.pop()
+    }
+}
+
+#[cfg(test)]
+mod ring_tests {
+    use super::ring;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn values_come_out_in_order() {
+        let (mut producer, mut consumer) = ring(4);
+        for i in 0..3 {
+            producer.push(i).unwrap();
+        }
+        assert_eq!(consumer.pop(), Some(0));
+        producer.push(3).unwrap();
+        producer.push(4).unwrap();
+        assert_eq!(consumer.by_ref().collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn full_queue_returns_value() {
+        let (mut producer, mut consumer) = ring(3);
+        assert_eq!(producer.capacity(), 4);
+        for i in 0..4 {
+            producer.push(i).unwrap();
+        }
+        assert_eq!(producer.push(4), Err(4));
+        assert_eq!(consumer.pop(), Some(0));
+        assert_eq!(producer.push(4), Ok(()));
+    }
+
+    #[test]
+    fn unread_values_are_dropped() {
+        struct Counted(Arc<AtomicUsize>);
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let (mut producer, mut consumer) = ring(8);
+        for _ in 0..5 {
+            assert!(producer.push(Counted(drops.clone())).is_ok());
+        }
+        drop(consumer.pop());
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+
+        drop(producer);
+        drop(consumer);
+        assert_eq!(drops.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn passes_values_between_threads() {
+        const COUNT: usize = 100000;
+        let (mut producer, mut consumer) = ring(64);
+
+        let sender = thread::spawn(move || {
+            for i in 0..COUNT {
+                let mut value = i;
+                while let Err(rejected) = producer.push(value) {
+                    value = rejected;
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut expected = 0;
+        while expected < COUNT {
+            match consumer.pop() {
+                Some(value) => {
+                    assert_eq!(value, expected);
+                    expected += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        sender.join().unwrap();
+    }
+}
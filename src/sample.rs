@@ -1,62 +1,513 @@
+use crate::additive::Additive;
+use crate::fm::FmVoice;
+use crate::karplus::KarplusStrong;
+use crate::noise::{BrownNoise, LfsrNoise, PinkNoise, WhiteNoise};
+use crate::sampler::Sampler;
+use crate::source::{Parameter, Source};
+use crate::wavetable::Wavetable;
 use rand::{distributions::uniform::Uniform, rngs::SmallRng, Rng};
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
 use variant_count::VariantCount;
 
+/// A periodic waveform generator. Each sample carries its own phase accumulator which is advanced
+/// by `frequency / rate` every time it is sampled, so the pitch stays stable over long sessions and
+/// the frequency can be changed mid-note (for glides or vibrato) without a discontinuity.
 #[derive(Debug, VariantCount)]
 pub enum Sample {
     Sin {
         rate: f32,
         frequency: f32,
+        phase: f32,
     },
     Sawtooth {
         frequency: f32,
         rate: f32,
+        phase: f32,
     },
     Square {
         duty: f32,
         rate: f32,
         frequency: f32,
+        phase: f32,
     },
     Triangle {
         rate: f32,
         frequency: f32,
+        phase: f32,
     },
+    /// A sawtooth with PolyBLEP corrections applied around the discontinuity to suppress aliasing.
+    BandLimitedSawtooth {
+        frequency: f32,
+        rate: f32,
+        phase: f32,
+    },
+    /// A square wave with PolyBLEP corrections applied to both edges to suppress aliasing.
+    BandLimitedSquare {
+        duty: f32,
+        rate: f32,
+        frequency: f32,
+        phase: f32,
+    },
+    /// A triangle wave with PolyBLAMP corrections applied around the corners to suppress aliasing.
+    BandLimitedTriangle {
+        rate: f32,
+        frequency: f32,
+        phase: f32,
+    },
+    /// Plays a set of band-limited single-cycle tables. `position` morphs between adjacent tables,
+    /// from 0 (the first table) to the index of the last table.
+    Wavetable {
+        tables: Arc<Vec<Wavetable>>,
+        position: f32,
+        rate: f32,
+        frequency: f32,
+        phase: f32,
+    },
+    WhiteNoise(WhiteNoise),
+    PinkNoise(PinkNoise),
+    BrownNoise(BrownNoise),
+    LfsrNoise(LfsrNoise),
+    /// A frequency modulation voice built from sine operators.
+    Fm(FmVoice),
+    /// A sum of sine partials rendered a block at a time through an inverse fft.
+    Additive(Additive),
+    /// Playback of a recording pitched relative to its root note.
+    Pcm(Sampler),
+    /// A physically modelled plucked string that finishes once it has decayed.
+    Pluck(KarplusStrong),
+}
+
+/// The shape of the wave a tone is played with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Sine,
+    Sawtooth,
+    Square,
+    Triangle,
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "sine" => Ok(Waveform::Sine),
+            "sawtooth" => Ok(Waveform::Sawtooth),
+            "square" => Ok(Waveform::Square),
+            "triangle" => Ok(Waveform::Triangle),
+            _ => Err(format!(
+                "unknown waveform {}, expected sine, sawtooth, square or triangle",
+                name
+            )),
+        }
+    }
+}
+
+/// The two sample polynomial band-limited step residual. `phase` is the position within the
+/// current cycle in [0, 1) and `increment` is the phase advanced per sample. The result is the
+/// correction for a discontinuity of +2 at phase zero.
+fn poly_blep(phase: f32, increment: f32) -> f32 {
+    if phase < increment {
+        let x = phase / increment;
+        x + x - x * x - 1.
+    } else if phase > 1. - increment {
+        let x = (phase - 1.) / increment;
+        x * x + x + x + 1.
+    } else {
+        0.
+    }
+}
+
+/// The integrated form of `poly_blep`, used to round off a change in slope of one unit (per unit
+/// of phase) at phase zero. The caller scales the result by the size of the slope change.
+fn poly_blamp(phase: f32, increment: f32) -> f32 {
+    if phase < increment {
+        let x = 1. - phase / increment;
+        increment * x * x * x / 6.
+    } else if phase > 1. - increment {
+        let x = 1. + (phase - 1.) / increment;
+        increment * x * x * x / 6.
+    } else {
+        0.
+    }
+}
+
+/// Advance a phase accumulator by one sample of a waveform at `frequency`, keeping it within
+/// [0, 1). Returns the phase before it was advanced.
+pub(crate) fn advance(phase: &mut f32, frequency: f32, rate: f32) -> f32 {
+    let current = *phase;
+    *phase += frequency / rate;
+    *phase -= phase.floor();
+    current
+}
+
+/// A sine wave evaluated at a position within its cycle.
+pub(crate) fn sine(phase: f32) -> f32 {
+    (2.0 * std::f32::consts::PI * phase).sin()
+}
+
+/// A rising sawtooth evaluated at a position within its cycle.
+pub(crate) fn sawtooth(phase: f32) -> f32 {
+    phase * 2. - 1.
+}
+
+/// A square wave that is low for the first `duty` of the cycle and high for the remainder.
+pub(crate) fn square(phase: f32, duty: f32) -> f32 {
+    if phase > duty {
+        1.
+    } else {
+        -1.
+    }
+}
+
+/// A triangle wave that rises from its trough at the start of the cycle to its peak half way.
+pub(crate) fn triangle(phase: f32) -> f32 {
+    if phase < 0.5 {
+        -1. + phase * 4.
+    } else {
+        3. - phase * 4.
+    }
 }
 
 impl Sample {
-    pub fn next(&self, clock: f32) -> f32 {
+    /// Return the value of the waveform at the current phase and advance the phase by one sample.
+    pub fn next(&mut self) -> f32 {
         match self {
-            Sample::Sin { rate, frequency } => {
-                (2.0 * std::f32::consts::PI * frequency * (clock * (1. / rate))).sin()
+            Sample::Sin {
+                rate,
+                frequency,
+                phase,
+            } => sine(advance(phase, *frequency, *rate)),
+            Sample::Sawtooth {
+                rate,
+                frequency,
+                phase,
+            } => sawtooth(advance(phase, *frequency, *rate)),
+            Sample::Square {
+                duty,
+                rate,
+                frequency,
+                phase,
+            } => square(advance(phase, *frequency, *rate), *duty),
+            Sample::Triangle {
+                rate,
+                frequency,
+                phase,
+            } => triangle(advance(phase, *frequency, *rate)),
+            Sample::BandLimitedSawtooth {
+                rate,
+                frequency,
+                phase,
+            } => {
+                let increment = *frequency / *rate;
+                let phase = advance(phase, *frequency, *rate);
+                sawtooth(phase) - poly_blep(phase, increment)
             }
-            Sample::Sawtooth { rate, frequency } => {
-                -1. + ((((clock * frequency) / rate) % 1.) * 2.)
+            Sample::BandLimitedSquare {
+                duty,
+                rate,
+                frequency,
+                phase,
+            } => {
+                let increment = *frequency / *rate;
+                let phase = advance(phase, *frequency, *rate);
+
+                // The square falls at the start of the cycle and rises once the duty has elapsed.
+                square(phase, *duty) - poly_blep(phase, increment)
+                    + poly_blep((phase - *duty + 1.) % 1., increment)
+            }
+            Sample::BandLimitedTriangle {
+                rate,
+                frequency,
+                phase,
+            } => {
+                let increment = *frequency / *rate;
+                let phase = advance(phase, *frequency, *rate);
+
+                // The slope changes by +8 at the trough (phase zero) and by -8 at the peak.
+                triangle(phase)
+                    + 8. * (poly_blamp(phase, increment)
+                        - poly_blamp((phase + 0.5) % 1., increment))
+            }
+            Sample::Wavetable {
+                tables,
+                position,
+                rate,
+                frequency,
+                phase,
+            } => {
+                let phase = advance(phase, *frequency, *rate);
+                let position = position.clamp(0., (tables.len() - 1) as f32);
+                let index = position as usize;
+                let fraction = position - index as f32;
+
+                let current = tables[index].sample(phase, *frequency, *rate);
+                if fraction > 0. {
+                    let next = tables[index + 1].sample(phase, *frequency, *rate);
+                    current + (next - current) * fraction
+                } else {
+                    current
+                }
+            }
+            Sample::WhiteNoise(noise) => noise.next(),
+            Sample::PinkNoise(noise) => noise.next(),
+            Sample::BrownNoise(noise) => noise.next(),
+            Sample::LfsrNoise(noise) => noise.next(),
+            Sample::Fm(voice) => voice.next(),
+            Sample::Additive(voice) => voice.next(),
+            Sample::Pcm(voice) => voice.next(),
+            Sample::Pluck(voice) => voice.next(),
+        }
+    }
+
+    /// Fill a buffer with the next samples. The match on the kind of waveform is made once per
+    /// buffer rather than once per sample, so the inner loops are tight enough to vectorise. The
+    /// samples are identical to calling `next` repeatedly.
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        match self {
+            Sample::Sin {
+                rate,
+                frequency,
+                phase,
+            } => {
+                for value in buffer.iter_mut() {
+                    *value = sine(advance(phase, *frequency, *rate));
+                }
+            }
+            Sample::Sawtooth {
+                rate,
+                frequency,
+                phase,
+            } => {
+                for value in buffer.iter_mut() {
+                    *value = sawtooth(advance(phase, *frequency, *rate));
+                }
             }
             Sample::Square {
                 duty,
                 rate,
                 frequency,
+                phase,
             } => {
-                if (clock * frequency / rate) % 1. > *duty {
-                    1.
-                } else {
-                    -1.
+                for value in buffer.iter_mut() {
+                    *value = square(advance(phase, *frequency, *rate), *duty);
                 }
             }
-            Sample::Triangle { rate, frequency } => {
-                let stage = ((clock * frequency) / rate) % 4.;
-                if stage <= 2. {
-                    -1. + (stage * 4. % 2.)
-                } else {
-                    1. - (stage * 4. % 2.)
+            Sample::Triangle {
+                rate,
+                frequency,
+                phase,
+            } => {
+                for value in buffer.iter_mut() {
+                    *value = triangle(advance(phase, *frequency, *rate));
+                }
+            }
+            Sample::BandLimitedSawtooth {
+                rate,
+                frequency,
+                phase,
+            } => {
+                let increment = *frequency / *rate;
+                for value in buffer.iter_mut() {
+                    let phase = advance(phase, *frequency, *rate);
+                    *value = sawtooth(phase) - poly_blep(phase, increment);
+                }
+            }
+            _ => {
+                for value in buffer.iter_mut() {
+                    *value = self.next();
                 }
             }
         }
     }
 
+    /// Signal a note off to samples with envelopes of their own, and let looping recordings play
+    /// out past their loop.
+    pub fn release(&mut self) {
+        match self {
+            Sample::Fm(voice) => voice.release(),
+            Sample::Additive(voice) => voice.release(),
+            Sample::Pcm(voice) => voice.release(),
+            _ => {}
+        }
+    }
+
+    /// Returns true once the sample has nothing left to play, such as a one-shot recording that has
+    /// reached its end. Periodic waveforms and noise never finish on their own.
+    pub fn finished(&self) -> bool {
+        match self {
+            Sample::Fm(voice) => voice.finished(),
+            Sample::Additive(voice) => voice.finished(),
+            Sample::Pcm(voice) => voice.finished(),
+            Sample::Pluck(voice) => voice.finished(),
+            _ => false,
+        }
+    }
+
+    /// The frequency (in hz) this sample is currently generating. Unpitched noise reports zero.
+    pub fn frequency(&self) -> f32 {
+        match self {
+            Sample::Sin { frequency, .. }
+            | Sample::Sawtooth { frequency, .. }
+            | Sample::Square { frequency, .. }
+            | Sample::Triangle { frequency, .. }
+            | Sample::BandLimitedSawtooth { frequency, .. }
+            | Sample::BandLimitedSquare { frequency, .. }
+            | Sample::BandLimitedTriangle { frequency, .. }
+            | Sample::Wavetable { frequency, .. } => *frequency,
+            Sample::LfsrNoise(noise) => noise.frequency(),
+            Sample::Fm(voice) => voice.frequency(),
+            Sample::Additive(voice) => voice.frequency(),
+            Sample::Pcm(voice) => voice.frequency(),
+            Sample::Pluck(voice) => voice.frequency(),
+            Sample::WhiteNoise(_) | Sample::PinkNoise(_) | Sample::BrownNoise(_) => 0.,
+        }
+    }
+
+    /// Change the frequency of the sample. The phase is left untouched so the waveform continues
+    /// smoothly from where it is at the new pitch. Unpitched noise ignores the change.
+    pub fn set_frequency(&mut self, new_frequency: f32) {
+        match self {
+            Sample::Sin { frequency, .. }
+            | Sample::Sawtooth { frequency, .. }
+            | Sample::Square { frequency, .. }
+            | Sample::Triangle { frequency, .. }
+            | Sample::BandLimitedSawtooth { frequency, .. }
+            | Sample::BandLimitedSquare { frequency, .. }
+            | Sample::BandLimitedTriangle { frequency, .. }
+            | Sample::Wavetable { frequency, .. } => *frequency = new_frequency,
+            Sample::LfsrNoise(noise) => noise.set_frequency(new_frequency),
+            Sample::Fm(voice) => voice.set_frequency(new_frequency),
+            Sample::Additive(voice) => voice.set_frequency(new_frequency),
+            Sample::Pcm(voice) => voice.set_frequency(new_frequency),
+            Sample::Pluck(voice) => voice.set_frequency(new_frequency),
+            Sample::WhiteNoise(_) | Sample::PinkNoise(_) | Sample::BrownNoise(_) => {}
+        }
+    }
+
+    /// The duty cycle of pulse waves.
+    pub fn duty(&self) -> Option<f32> {
+        match self {
+            Sample::Square { duty, .. } | Sample::BandLimitedSquare { duty, .. } => Some(*duty),
+            _ => None,
+        }
+    }
+
+    /// Change the duty cycle of a pulse wave, keeping it short of silence at either end. Other
+    /// samples ignore the change.
+    pub fn set_duty(&mut self, new_duty: f32) {
+        match self {
+            Sample::Square { duty, .. } | Sample::BandLimitedSquare { duty, .. } => {
+                *duty = new_duty.clamp(0.01, 0.99)
+            }
+            _ => {}
+        }
+    }
+
+    /// Play a set of wavetables from the first table. The set must contain at least one table.
+    pub fn wavetable(
+        tables: Arc<Vec<Wavetable>>,
+        sample_rate: f32,
+        frequency: f32,
+    ) -> Result<Self, Box<dyn Error>> {
+        if tables.is_empty() {
+            return Err("a wavetable sample needs at least one table".into());
+        }
+
+        Ok(Sample::Wavetable {
+            tables,
+            position: 0.,
+            rate: sample_rate,
+            frequency,
+            phase: 0.,
+        })
+    }
+
+    /// Play a sine at the same pitch and phase with another waveform. The sawtooth, square and
+    /// triangle are band limited. Samples other than sines are returned unchanged.
+    pub fn with_waveform(self, waveform: Waveform) -> Self {
+        match self {
+            Sample::Sin {
+                rate,
+                frequency,
+                phase,
+            } => match waveform {
+                Waveform::Sine => Sample::Sin {
+                    rate,
+                    frequency,
+                    phase,
+                },
+                Waveform::Sawtooth => Sample::BandLimitedSawtooth {
+                    rate,
+                    frequency,
+                    phase,
+                },
+                Waveform::Square => Sample::BandLimitedSquare {
+                    duty: 0.5,
+                    rate,
+                    frequency,
+                    phase,
+                },
+                Waveform::Triangle => Sample::BandLimitedTriangle {
+                    rate,
+                    frequency,
+                    phase,
+                },
+            },
+            other => other,
+        }
+    }
+
+    /// White noise seeded from `rng`.
+    pub fn white_noise(rng: &mut SmallRng) -> Self {
+        Sample::WhiteNoise(WhiteNoise::new(rng))
+    }
+
+    /// Pink noise seeded from `rng`.
+    pub fn pink_noise(rng: &mut SmallRng) -> Self {
+        Sample::PinkNoise(PinkNoise::new(rng))
+    }
+
+    /// Brown noise seeded from `rng`.
+    pub fn brown_noise(rng: &mut SmallRng) -> Self {
+        Sample::BrownNoise(BrownNoise::new(rng))
+    }
+
+    /// Shift register noise clocked at `frequency`, seeded from `rng`.
+    pub fn lfsr_noise(
+        rng: &mut SmallRng,
+        sample_rate: f32,
+        frequency: f32,
+        short_mode: bool,
+    ) -> Self {
+        Sample::LfsrNoise(LfsrNoise::new(rng, sample_rate, frequency, short_mode))
+    }
+
+    /// A plucked string seeded with a burst of noise from `rng`. The frequency must be between
+    /// zero and half the sample rate.
+    pub fn pluck(
+        rng: &mut SmallRng,
+        sample_rate: f32,
+        frequency: f32,
+        damping: f32,
+        brightness: f32,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(Sample::Pluck(KarplusStrong::new(
+            rng,
+            sample_rate,
+            frequency,
+            damping,
+            brightness,
+        )?))
+    }
+
     pub fn middle_a(sample_rate: f32) -> Self {
         Sample::Sin {
             rate: sample_rate,
             frequency: 440.,
+            phase: 0.,
         }
     }
 
@@ -64,6 +515,7 @@ impl Sample {
         Sample::Sin {
             rate: sample_rate,
             frequency: 493.883,
+            phase: 0.,
         }
     }
 
@@ -71,6 +523,7 @@ impl Sample {
         Sample::Sin {
             rate: sample_rate,
             frequency: 261.63,
+            phase: 0.,
         }
     }
 
@@ -78,6 +531,7 @@ impl Sample {
         Sample::Sin {
             rate: sample_rate,
             frequency: 293.665,
+            phase: 0.,
         }
     }
 
@@ -85,6 +539,7 @@ impl Sample {
         Sample::Sin {
             rate: sample_rate,
             frequency: 1046.50,
+            phase: 0.,
         }
     }
 
@@ -92,6 +547,7 @@ impl Sample {
         Sample::Sin {
             rate: sample_rate,
             frequency: 4186.01,
+            phase: 0.,
         }
     }
 
@@ -99,22 +555,26 @@ impl Sample {
         let random_sine = Sample::Sin {
             rate: sample_rate,
             frequency: rng.sample(Uniform::new(200., 801.)),
+            phase: 0.,
         };
 
         let random_sawtooth = Sample::Sawtooth {
             rate: sample_rate,
             frequency: rng.sample(Uniform::new(150., 600.)),
+            phase: 0.,
         };
 
         let random_square = Sample::Square {
             rate: sample_rate,
             duty: rng.sample(Uniform::new(0.3, 0.8)),
             frequency: rng.sample(Uniform::new(250., 600.)),
+            phase: 0.,
         };
 
         let random_triangle = Sample::Triangle {
             rate: sample_rate,
             frequency: rng.sample(Uniform::new(250., 500.)),
+            phase: 0.,
         };
 
         match rng.sample(Uniform::new(0, 4)) {
@@ -126,3 +586,250 @@ impl Sample {
         }
     }
 }
+
+impl Source for Sample {
+    fn next(&mut self) -> f32 {
+        Sample::next(self)
+    }
+
+    fn finished(&self) -> bool {
+        Sample::finished(self)
+    }
+
+    fn release(&mut self) {
+        Sample::release(self)
+    }
+
+    fn process(&mut self, buffer: &mut [f32]) {
+        Sample::process(self, buffer)
+    }
+
+    fn parameter(&self, parameter: Parameter) -> Option<f32> {
+        match (parameter, self) {
+            (Parameter::Frequency, Sample::WhiteNoise(_))
+            | (Parameter::Frequency, Sample::PinkNoise(_))
+            | (Parameter::Frequency, Sample::BrownNoise(_)) => None,
+            (Parameter::Frequency, _) => Some(self.frequency()),
+            (Parameter::Duty, _) => self.duty(),
+            (Parameter::Effect(_), _) => None,
+        }
+    }
+
+    fn set_parameter(&mut self, parameter: Parameter, value: f32) {
+        match parameter {
+            Parameter::Frequency => self.set_frequency(value),
+            Parameter::Duty => self.set_duty(value),
+            Parameter::Effect(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod band_limited_tests {
+    use super::{Sample, Waveform};
+    use crate::fft::RealFft;
+
+    const RATE: f32 = 44100.;
+    const FREQUENCY: f32 = 5000.;
+
+    /// Render a sample through a hann window and return the fraction of the spectral energy that
+    /// lies away from the harmonics of the fundamental (and so must have been folded back from
+    /// above nyquist).
+    fn aliased_energy(mut sample: Sample) -> f64 {
+        let len = 4096;
+        let data: Vec<f64> = (0..len)
+            .map(|i| {
+                let window = 0.5 - 0.5 * (2. * std::f64::consts::PI * i as f64 / len as f64).cos();
+                sample.next() as f64 * window
+            })
+            .collect();
+
+        let mut fft = RealFft::new(8192, RATE as f64).unwrap();
+        let spectrum = fft.run(&data).unwrap();
+
+        let mut harmonic = 0.;
+        let mut aliased = 0.;
+
+        for (frequency, amplitude) in spectrum {
+            let nearest = (frequency / FREQUENCY as f64).round() * FREQUENCY as f64;
+            if (frequency - nearest).abs() < 250. {
+                harmonic += amplitude * amplitude;
+            } else {
+                aliased += amplitude * amplitude;
+            }
+        }
+
+        aliased / (harmonic + aliased)
+    }
+
+    #[test]
+    fn sawtooth_alias_suppressed() {
+        let naive = aliased_energy(Sample::Sawtooth {
+            rate: RATE,
+            frequency: FREQUENCY,
+            phase: 0.,
+        });
+        let band_limited = aliased_energy(Sample::BandLimitedSawtooth {
+            rate: RATE,
+            frequency: FREQUENCY,
+            phase: 0.,
+        });
+        assert!(band_limited < 0.01);
+        assert!(band_limited < naive / 10.);
+    }
+
+    #[test]
+    fn square_alias_suppressed() {
+        let naive = aliased_energy(Sample::Square {
+            duty: 0.3,
+            rate: RATE,
+            frequency: FREQUENCY,
+            phase: 0.,
+        });
+        let band_limited = aliased_energy(Sample::BandLimitedSquare {
+            duty: 0.3,
+            rate: RATE,
+            frequency: FREQUENCY,
+            phase: 0.,
+        });
+        assert!(band_limited < 0.01);
+        assert!(band_limited < naive / 10.);
+    }
+
+    #[test]
+    fn triangle_alias_suppressed() {
+        let naive = aliased_energy(Sample::Triangle {
+            rate: RATE,
+            frequency: FREQUENCY,
+            phase: 0.,
+        });
+        let band_limited = aliased_energy(Sample::BandLimitedTriangle {
+            rate: RATE,
+            frequency: FREQUENCY,
+            phase: 0.,
+        });
+        assert!(band_limited < 0.001);
+        assert!(band_limited < naive / 10.);
+    }
+
+    #[test]
+    fn band_limited_stays_in_range() {
+        let mut sample = Sample::BandLimitedSquare {
+            duty: 0.5,
+            rate: RATE,
+            frequency: 440.,
+            phase: 0.,
+        };
+        for _ in 0..44100 {
+            assert!(sample.next().abs() <= 1.01);
+        }
+    }
+
+    #[test]
+    fn waveforms_are_band_limited() {
+        for waveform in ["sawtooth", "square", "triangle"] {
+            let waveform: Waveform = waveform.parse().unwrap();
+            let sample = Sample::Sin {
+                rate: RATE,
+                frequency: FREQUENCY,
+                phase: 0.,
+            }
+            .with_waveform(waveform);
+            assert!(aliased_energy(sample) < 0.01, "{:?}", waveform);
+        }
+        assert!("saw".parse::<Waveform>().is_err());
+    }
+}
+
+#[cfg(test)]
+mod phase_accumulator_tests {
+    use super::Sample;
+
+    const RATE: f32 = 44100.;
+
+    /// Count the rising zero crossings over one second of output.
+    fn cycles_in_one_second(sample: &mut Sample) -> usize {
+        let mut last = sample.next();
+        let mut crossings = 0;
+        for _ in 1..RATE as usize {
+            let next = sample.next();
+            if last < 0. && next >= 0. {
+                crossings += 1;
+            }
+            last = next;
+        }
+        crossings
+    }
+
+    #[test]
+    fn pitch_is_stable_after_long_sessions() {
+        let mut sample = Sample::middle_a(RATE);
+
+        // Well past the point where an f32 sample counter can no longer represent every sample.
+        for _ in 0..(1 << 25) {
+            sample.next();
+        }
+
+        let cycles = cycles_in_one_second(&mut sample);
+        assert!((439..=441).contains(&cycles), "{} cycles", cycles);
+    }
+
+    #[test]
+    fn frequency_change_is_continuous() {
+        let mut sample = Sample::Sin {
+            rate: RATE,
+            frequency: 440.,
+            phase: 0.,
+        };
+
+        let mut last = 0.;
+        for i in 0..RATE as usize {
+            // Sweep upward an octave over the second as a glide would.
+            sample.set_frequency(440. + 440. * (i as f32 / RATE));
+            let next = sample.next();
+
+            // The largest step a sine at 880hz can take in one sample.
+            assert!((next - last).abs() <= 2. * std::f32::consts::PI * 880. / RATE + 1e-4);
+            last = next;
+        }
+
+        assert!((sample.frequency() - 880.).abs() < 0.1);
+    }
+}
+
+#[cfg(test)]
+mod wavetable_sample_tests {
+    use super::Sample;
+    use crate::wavetable::Wavetable;
+    use std::sync::Arc;
+
+    #[test]
+    fn morphs_between_tables() {
+        let tables = Arc::new(vec![
+            Wavetable::from_harmonics(&[1.]).unwrap(),
+            Wavetable::from_harmonics(&[0., 1.]).unwrap(),
+        ]);
+
+        let mut first = Sample::wavetable(tables.clone(), 44100., 440.).unwrap();
+        let mut second = Sample::wavetable(tables.clone(), 44100., 440.).unwrap();
+        let mut halfway = Sample::wavetable(tables, 44100., 440.).unwrap();
+
+        if let Sample::Wavetable { position, .. } = &mut second {
+            *position = 1.;
+        }
+
+        if let Sample::Wavetable { position, .. } = &mut halfway {
+            *position = 0.5;
+        }
+
+        for _ in 0..1000 {
+            let expected = (first.next() + second.next()) / 2.;
+            assert!((halfway.next() - expected).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn rejects_an_empty_set() {
+        assert!(Sample::wavetable(Arc::new(Vec::new()), 44100., 440.).is_err());
+    }
+}
@@ -0,0 +1,458 @@
+/**
+ * Playback of recorded audio. A recording is decoded once into a `Recording` which is shared
+ * between every voice playing it. Each `Sampler` voice reads through the recording at a rate set
+ * by the ratio of the note it plays to the root note of the recording, interpolating between
+ * frames, and can loop a region forwards or back and forth while the note is held.
+ */
+use crate::dsp::hermite;
+use crate::wav::Wav;
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// How a voice behaves when it reaches the end of the loop region.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopMode {
+    // Play through the recording once and stop.
+    Off,
+
+    // Jump back to the loop start each time the loop end is reached.
+    Forward,
+
+    // Reverse direction at each end of the loop region.
+    PingPong,
+}
+
+impl FromStr for LoopMode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "off" => Ok(LoopMode::Off),
+            "forward" => Ok(LoopMode::Forward),
+            "ping-pong" => Ok(LoopMode::PingPong),
+            _ => Err(format!(
+                "unknown loop mode {}, expected off, forward or ping-pong",
+                name
+            )),
+        }
+    }
+}
+
+/// The frequency in hz of a midi note number.
+pub fn note_frequency(note: u8) -> f32 {
+    440. * 2f32.powf((note as f32 - 69.) / 12.)
+}
+
+/// Decoded mono audio along with how it should be pitched and looped.
+#[derive(Debug)]
+pub struct Recording {
+    frames: Vec<f32>,
+
+    // The sample rate the audio was recorded at.
+    sample_rate: f32,
+
+    // The frequency that plays the recording back at its original speed.
+    root_frequency: f32,
+
+    loop_mode: LoopMode,
+    loop_start: usize,
+    loop_end: usize,
+}
+
+impl Recording {
+    /// Wrap decoded frames. The recording plays once, unlooped, at its original pitch when asked
+    /// for middle c.
+    pub fn new(frames: Vec<f32>, sample_rate: f32) -> Result<Self, Box<dyn Error>> {
+        if frames.is_empty() {
+            return Err("a recording needs at least one frame".into());
+        }
+
+        Ok(Recording {
+            loop_end: frames.len(),
+            frames,
+            sample_rate,
+            root_frequency: note_frequency(60),
+            loop_mode: LoopMode::Off,
+            loop_start: 0,
+        })
+    }
+
+    /// Load a recording from a wav file, mixing multiple channels down to mono.
+    pub fn from_wav<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let wav = Wav::load(path)?;
+        Self::new(wav.mono(), wav.sample_rate as f32)
+    }
+
+    /// Set the midi note the recording was made at.
+    pub fn with_root_note(self, note: u8) -> Self {
+        Recording {
+            root_frequency: note_frequency(note),
+            ..self
+        }
+    }
+
+    /// Loop the frames between `start` (inclusive) and `end` (exclusive).
+    pub fn with_loop(
+        self,
+        mode: LoopMode,
+        start: usize,
+        end: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        if start + 1 >= end || end > self.frames.len() {
+            return Err("the loop region must span at least two frames of the recording".into());
+        }
+
+        Ok(Recording {
+            loop_mode: mode,
+            loop_start: start,
+            loop_end: end,
+            ..self
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The frame at an index, treating everything outside the recording as silence.
+    fn frame(&self, index: isize) -> f32 {
+        if index < 0 {
+            0.
+        } else {
+            *self.frames.get(index as usize).unwrap_or(&0.)
+        }
+    }
+
+    /// Read between frames with four point cubic hermite interpolation. `tap` maps the index of
+    /// each frame that is read to the index of the frame that really neighbours the others, which
+    /// is somewhere else across the seam of a loop.
+    fn interpolate(&self, position: f64, tap: impl Fn(isize) -> isize) -> f32 {
+        let index = position.floor() as isize;
+        let t = (position - position.floor()) as f32;
+
+        hermite(
+            self.frame(tap(index - 1)),
+            self.frame(tap(index)),
+            self.frame(tap(index + 1)),
+            self.frame(tap(index + 2)),
+            t,
+        )
+    }
+}
+
+/// A voice playing a shared recording at a given pitch.
+#[derive(Debug)]
+pub struct Sampler {
+    recording: Arc<Recording>,
+
+    // The read position in frames of the recording.
+    position: f64,
+
+    // +1 when reading forwards and -1 when reading backwards in a ping-pong loop.
+    direction: f64,
+
+    rate: f32,
+    frequency: f32,
+    finished: bool,
+
+    // Whether the voice has been round the loop, so the frames before the loop start are no
+    // longer the ones that led up to it.
+    looped: bool,
+
+    // Released voices leave the loop and play out to the end of the recording.
+    released: bool,
+}
+
+impl Sampler {
+    pub fn new(recording: Arc<Recording>, rate: f32, frequency: f32) -> Self {
+        Sampler {
+            recording,
+            position: 0.,
+            direction: 1.,
+            rate,
+            frequency,
+            finished: false,
+            looped: false,
+            released: false,
+        }
+    }
+
+    /// Start playback part way into the recording.
+    pub fn with_offset(self, frames: usize) -> Self {
+        Sampler {
+            position: frames as f64,
+            ..self
+        }
+    }
+
+    pub fn frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    pub fn set_frequency(&mut self, frequency: f32) {
+        self.frequency = frequency;
+    }
+
+    /// The number of recording frames to move per output sample.
+    fn step(&self) -> f64 {
+        (self.frequency / self.recording.root_frequency) as f64
+            * (self.recording.sample_rate / self.rate) as f64
+    }
+
+    /// Stop looping. The voice plays on from where it is to the end of the recording.
+    pub fn release(&mut self) {
+        self.released = true;
+        self.direction = 1.;
+    }
+
+    /// How the loop is played, which is not at all once the voice has been released.
+    fn loop_mode(&self) -> LoopMode {
+        if self.released {
+            LoopMode::Off
+        } else {
+            self.recording.loop_mode
+        }
+    }
+
+    /// The index of the frame that is read in place of a frame the interpolation asks for. Inside
+    /// a loop the frames past one end are the ones at the other end, or for a ping-pong loop the
+    /// ones travelled back over.
+    fn tap(&self, index: isize) -> isize {
+        let start = self.recording.loop_start as isize;
+        let end = self.recording.loop_end as isize;
+        let last = end - 1;
+
+        match self.loop_mode() {
+            LoopMode::Off => index,
+            LoopMode::Forward if index >= end => index - (end - start),
+            LoopMode::Forward if self.looped && index < start => index + (end - start),
+            LoopMode::PingPong if index > last => 2 * last - index,
+            LoopMode::PingPong if self.looped && index < start => 2 * start - index,
+            _ => index,
+        }
+    }
+
+    pub fn next(&mut self) -> f32 {
+        if self.finished {
+            return 0.;
+        }
+
+        let value = self
+            .recording
+            .interpolate(self.position, |index| self.tap(index));
+        self.position += self.step() * self.direction;
+
+        let start = self.recording.loop_start as f64;
+        let end = self.recording.loop_end as f64;
+
+        match self.loop_mode() {
+            LoopMode::Off => {
+                if self.position >= self.recording.len() as f64 {
+                    self.finished = true;
+                }
+            }
+            LoopMode::Forward => {
+                if self.position >= end {
+                    self.position = start + (self.position - end) % (end - start);
+                    self.looped = true;
+                }
+            }
+            LoopMode::PingPong => {
+                // Bounce off the last frame of the loop and off the loop start, reflecting any
+                // overshoot. The loop start only applies once the voice is travelling backwards
+                // so a start offset before the loop plays through into it.
+                let last = end - 1.;
+                if self.direction > 0. && self.position > last {
+                    self.position = last - (self.position - last) % (last - start);
+                    self.direction = -1.;
+                    self.looped = true;
+                } else if self.direction < 0. && self.position < start {
+                    self.position = start + (start - self.position) % (last - start);
+                    self.direction = 1.;
+                }
+            }
+        }
+
+        value
+    }
+
+    /// An unlooped voice finishes once it has read past the end of the recording.
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+}
+
+#[cfg(test)]
+mod sampler_tests {
+    use super::{note_frequency, LoopMode, Recording, Sampler};
+    use crate::sample::Sample;
+    use std::sync::Arc;
+
+    fn ramp(len: usize) -> Vec<f32> {
+        (0..len).map(|x| x as f32).collect()
+    }
+
+    #[test]
+    fn root_note_plays_unchanged() {
+        let recording = Arc::new(Recording::new(ramp(64), 44100.).unwrap().with_root_note(69));
+        let mut sampler = Sampler::new(recording, 44100., 440.);
+        for i in 0..64 {
+            assert!((sampler.next() - i as f32).abs() < 1e-4);
+        }
+        assert!(sampler.finished());
+    }
+
+    #[test]
+    fn octave_up_plays_twice_as_fast() {
+        let recording = Arc::new(Recording::new(ramp(64), 44100.).unwrap());
+        let mut sampler = Sampler::new(recording, 44100., note_frequency(72));
+        for i in 0..32 {
+            assert!(!sampler.finished());
+            assert!((sampler.next() - (i * 2) as f32).abs() < 1e-3);
+        }
+        assert!(sampler.finished());
+    }
+
+    #[test]
+    fn interpolates_between_frames() {
+        let recording = Arc::new(Recording::new(ramp(64), 44100.).unwrap());
+        let mut sampler = Sampler::new(recording, 44100., note_frequency(48)).with_offset(10);
+        assert!((sampler.next() - 10.).abs() < 1e-3);
+        assert!((sampler.next() - 10.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn forward_loop() {
+        let recording = Recording::new(ramp(64), 44100.)
+            .unwrap()
+            .with_loop(LoopMode::Forward, 16, 32)
+            .unwrap();
+        let mut sampler = Sampler::new(Arc::new(recording), 44100., note_frequency(60));
+
+        for _ in 0..32 {
+            sampler.next();
+        }
+
+        for i in 0..100 {
+            assert_eq!(sampler.next().round(), (16 + i % 16) as f32);
+        }
+        assert!(!sampler.finished());
+    }
+
+    #[test]
+    fn ping_pong_loop() {
+        let recording = Recording::new(ramp(64), 44100.)
+            .unwrap()
+            .with_loop(LoopMode::PingPong, 16, 32)
+            .unwrap();
+        let mut sampler = Sampler::new(Arc::new(recording), 44100., note_frequency(60));
+
+        let played: Vec<f32> = (0..80).map(|_| sampler.next().round()).collect();
+        assert_eq!(played[31], 31.);
+        assert_eq!(played[32], 30.);
+        assert_eq!(played[46], 16.);
+        assert_eq!(played[47], 17.);
+    }
+
+    #[test]
+    fn interpolates_across_the_loop_seam() {
+        // One cycle of a sine is looped, with silence either side of it that the interpolation must
+        // not reach into.
+        let frames = (0..64)
+            .map(|i| match i {
+                16..=31 => (2. * std::f32::consts::PI * i as f32 / 16.).sin(),
+                _ => 0.,
+            })
+            .collect();
+        let recording = Recording::new(frames, 44100.)
+            .unwrap()
+            .with_loop(LoopMode::Forward, 16, 32)
+            .unwrap();
+
+        // An octave down reads half way between frames, so every other value is interpolated.
+        let mut sampler =
+            Sampler::new(Arc::new(recording), 44100., note_frequency(48)).with_offset(16);
+        for _ in 0..32 {
+            sampler.next();
+        }
+        for i in 0..64 {
+            let expected = (2. * std::f32::consts::PI * i as f32 / 32.).sin();
+            assert!((sampler.next() - expected).abs() < 0.02, "{}", i);
+        }
+    }
+
+    #[test]
+    fn release_leaves_the_loop() {
+        let recording = Recording::new(ramp(64), 44100.)
+            .unwrap()
+            .with_loop(LoopMode::PingPong, 16, 32)
+            .unwrap();
+        let mut sampler = Sampler::new(Arc::new(recording), 44100., note_frequency(60));
+
+        for _ in 0..40 {
+            sampler.next();
+        }
+        sampler.release();
+
+        // Having turned back at the loop end it heads forwards again and out of the recording.
+        let rest: Vec<f32> = (0..50).map(|_| sampler.next().round()).collect();
+        assert_eq!(rest[..3], [22., 23., 24.]);
+        assert_eq!(rest[41], 63.);
+        assert!(sampler.finished());
+    }
+
+    #[test]
+    fn loop_modes_by_name() {
+        assert_eq!("ping-pong".parse::<LoopMode>(), Ok(LoopMode::PingPong));
+        assert!("backwards".parse::<LoopMode>().is_err());
+    }
+
+    #[test]
+    fn invalid_recordings() {
+        assert!(Recording::new(vec![], 44100.).is_err());
+        let recording = Recording::new(ramp(8), 44100.).unwrap();
+        assert!(recording.with_loop(LoopMode::Forward, 4, 9).is_err());
+    }
+
+    #[test]
+    fn plays_as_a_sample() {
+        let recording = Arc::new(Recording::new(ramp(64), 44100.).unwrap());
+        let mut sample = Sample::Pcm(Sampler::new(recording, 44100., note_frequency(72)));
+        assert_eq!(sample.frequency(), note_frequency(72));
+
+        // Dropping back to the root note halfway through slows playback to one frame a sample.
+        for i in 0..8 {
+            assert_eq!(sample.next().round(), (i * 2) as f32);
+        }
+        sample.set_frequency(note_frequency(60));
+        for i in 16..64 {
+            assert!((sample.next() - i as f32).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn note_off_plays_out_the_loop() {
+        let recording = Recording::new(ramp(64), 44100.)
+            .unwrap()
+            .with_loop(LoopMode::Forward, 16, 32)
+            .unwrap();
+        let mut sample = Sample::Pcm(Sampler::new(
+            Arc::new(recording),
+            44100.,
+            note_frequency(60),
+        ));
+
+        for _ in 0..100 {
+            sample.next();
+        }
+        assert!(!sample.finished());
+
+        sample.release();
+        for _ in 0..64 {
+            sample.next();
+        }
+        assert!(sample.finished());
+    }
+}
@@ -0,0 +1,50 @@
+//! The common interface of everything that generates audio. Samples, envelopes and mixers all
+//! implement `Source` so they can be nested in any combination, and user defined generators can be
+//! plugged in anywhere one of them is accepted.
+
+/// A value of a source that can be changed while it plays, so that it can be modulated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parameter {
+    // The pitch in hz.
+    Frequency,
+
+    // The fraction of each cycle a pulse wave spends low, from 0 to 1.
+    Duty,
+
+    // A parameter of an effect, such as a filter cutoff, by index.
+    Effect(usize),
+}
+
+pub trait Source {
+    /// Produce the next sample.
+    fn next(&mut self) -> f32;
+
+    /// Returns true once the source has nothing left to play. Mixers drop sources once they finish.
+    fn finished(&self) -> bool {
+        false
+    }
+
+    /// Signal a note off. Enveloped sources move to their release stage, other sources ignore it.
+    fn release(&mut self) {}
+
+    /// The current value of a parameter, or None when the source does not have it.
+    fn parameter(&self, _parameter: Parameter) -> Option<f32> {
+        None
+    }
+
+    /// Change a parameter. Sources ignore parameters they do not have.
+    fn set_parameter(&mut self, _parameter: Parameter, _value: f32) {}
+
+    /// Where the source places itself between the left (-1) and right (1) speakers.
+    fn pan(&self) -> f32 {
+        0.
+    }
+
+    /// Fill a buffer with the next samples. Sources that can render a block more efficiently than
+    /// one sample at a time should override this.
+    fn process(&mut self, buffer: &mut [f32]) {
+        for value in buffer.iter_mut() {
+            *value = self.next();
+        }
+    }
+}
@@ -0,0 +1,248 @@
+/**
+ * A state variable filter, which gives a low, band and high pass of the same input at once. It is
+ * built from trapezoidal integrators with the feedback solved for each sample (the topology
+ * preserving transform), so it stays stable and in tune however fast its cutoff and resonance are
+ * modulated.
+ *
+ * The damping falls to zero as the resonance reaches one. Past that the filter rings on its own,
+ * and damping that grows with the level of the band pass holds the ringing at a steady level.
+ */
+use crate::envelope::Envelope;
+use crate::filter::{Cutoff, Filter, MAX_RESONANCE};
+use std::error::Error;
+
+/// How much the damping grows with the square of the band pass state.
+const SATURATION: f32 = 1.;
+
+/// Which output of the filter is played when it is used as a `Filter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Low,
+    Band,
+    High,
+}
+
+/// One sample of every output of the filter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Outputs {
+    pub low: f32,
+    pub band: f32,
+    pub high: f32,
+}
+
+/// A resonant filter falling off 12db per octave from its low and high passes.
+///
+/// Parameters: 0 is the cutoff in hz before key tracking and the envelope and 1 the resonance from
+/// 0 to `MAX_RESONANCE`.
+#[derive(Debug)]
+pub struct Svf {
+    cutoff: Cutoff,
+    resonance: f32,
+    output: Output,
+
+    // The states of the band and low pass integrators.
+    state: [f32; 2],
+}
+
+impl Svf {
+    /// A filter playing its low pass. A resonance of zero has no peak at the cutoff.
+    pub fn new(sample_rate: f32, frequency: f32, resonance: f32) -> Result<Self, Box<dyn Error>> {
+        if !(frequency > 0. && frequency < sample_rate / 2.) {
+            return Err("a filter cutoff must be between zero and half the sample rate".into());
+        }
+        if !(0. ..=MAX_RESONANCE).contains(&resonance) {
+            return Err(format!("a resonance must be between 0 and {}", MAX_RESONANCE).into());
+        }
+
+        Ok(Svf {
+            cutoff: Cutoff::new(sample_rate, frequency),
+            resonance,
+            output: Output::Low,
+            state: [0.; 2],
+        })
+    }
+
+    /// Play a different output.
+    pub fn with_output(self, output: Output) -> Self {
+        Svf { output, ..self }
+    }
+
+    /// Follow the key of a midi note by a fraction of its distance from middle c.
+    pub fn with_key_tracking(self, note: u8, amount: f32) -> Self {
+        Svf {
+            cutoff: self.cutoff.with_key_tracking(note, amount),
+            ..self
+        }
+    }
+
+    /// Open the cutoff by an envelope, by `octaves` at a level of one.
+    pub fn with_envelope(self, envelope: Envelope, octaves: f32) -> Self {
+        Svf {
+            cutoff: self.cutoff.with_envelope(envelope, octaves),
+            ..self
+        }
+    }
+
+    /// Filter the next sample, returning every output.
+    pub fn tick(&mut self, input: f32) -> Outputs {
+        let g = self.cutoff.next_gain();
+        let [band_state, low_state] = self.state;
+        let damping = 2. * (1. - self.resonance) + SATURATION * band_state * band_state;
+
+        let high = (input - (damping + g) * band_state - low_state) / (1. + g * (damping + g));
+        let band = g * high + band_state;
+        let low = g * band + low_state;
+        self.state = [band + g * high, low + g * band];
+
+        Outputs { low, band, high }
+    }
+}
+
+impl Filter for Svf {
+    fn next(&mut self, input: f32) -> f32 {
+        let outputs = self.tick(input);
+        match self.output {
+            Output::Low => outputs.low,
+            Output::Band => outputs.band,
+            Output::High => outputs.high,
+        }
+    }
+
+    fn parameter(&self, index: usize) -> Option<f32> {
+        match index {
+            0 => Some(self.cutoff.frequency()),
+            1 => Some(self.resonance),
+            _ => None,
+        }
+    }
+
+    fn set_parameter(&mut self, index: usize, value: f32) {
+        match index {
+            0 => self.cutoff.set_frequency(value),
+            1 => self.resonance = value.clamp(0., MAX_RESONANCE),
+            _ => {}
+        }
+    }
+
+    fn release(&mut self) {
+        self.cutoff.release();
+    }
+}
+
+#[cfg(test)]
+mod svf_tests {
+    use super::{Output, Svf};
+    use crate::envelope::Envelope;
+    use crate::filter::Filter;
+    use std::f32::consts::PI;
+
+    const RATE: f32 = 44100.;
+
+    /// The gain of a filter at a frequency in decibels, from the levels of a quiet sine wave going
+    /// in and coming out once the filter has settled.
+    fn measured(mut filter: Svf, frequency: f32) -> f32 {
+        let sine =
+            |i: usize| 0.01 * (2. * PI * frequency * (i % RATE as usize) as f32 / RATE).sin();
+        for i in 0..RATE as usize / 10 {
+            filter.next(sine(i));
+        }
+        let (mut dry, mut wet) = (0., 0.);
+        for i in RATE as usize / 10..RATE as usize / 5 {
+            dry += sine(i).powi(2);
+            wet += filter.next(sine(i)).powi(2);
+        }
+        10. * (wet / dry).log10()
+    }
+
+    /// The gain of one output of a filter at 1khz at a frequency in decibels.
+    fn gain(output: Output, resonance: f32, frequency: f32) -> f32 {
+        let filter = Svf::new(RATE, 1000., resonance)
+            .unwrap()
+            .with_output(output);
+        measured(filter, frequency)
+    }
+
+    #[test]
+    fn outputs_split_the_input() {
+        assert!(gain(Output::Low, 0.3, 50.).abs() < 0.1);
+        assert!(gain(Output::Low, 0.3, 16000.) < -30.);
+        assert!(gain(Output::High, 0.3, 16000.).abs() < 0.1);
+        assert!(gain(Output::High, 0.3, 50.) < -40.);
+        assert!(gain(Output::Band, 0.3, 1000.) > gain(Output::Band, 0.3, 100.) + 15.);
+
+        // The outputs add back up to the input.
+        let mut filter = Svf::new(RATE, 1000., 0.).unwrap();
+        for i in 0..1000 {
+            let input = 0.01 * (i as f32 * 0.37).sin();
+            let outputs = filter.tick(input);
+            let sum = outputs.low + 2. * outputs.band + outputs.high;
+            assert!((sum - input).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn resonance_sharpens_the_peak() {
+        let mild = gain(Output::Low, 0., 1000.);
+        let sharp = gain(Output::Low, 0.9, 1000.);
+        assert!((mild + 6.02).abs() < 0.1);
+        assert!(sharp > 10., "{}", sharp);
+    }
+
+    #[test]
+    fn self_oscillates_at_the_cutoff() {
+        let mut filter = Svf::new(RATE, 1000., 1.1)
+            .unwrap()
+            .with_output(Output::Band);
+        filter.next(0.5);
+        let tail: Vec<f32> = (0..RATE as usize).map(|_| filter.next(0.)).collect();
+        let tail = &tail[RATE as usize / 2..];
+
+        let peak = tail.iter().fold(0f32, |peak, x| peak.max(x.abs()));
+        assert!(peak > 0.1 && peak < 1., "{}", peak);
+        let crossings = tail.windows(2).filter(|x| x[0] < 0. && x[1] >= 0.).count();
+        assert!((crossings as f32 - 500.).abs() < 10., "{}", crossings);
+
+        // Below one the ringing dies away.
+        let mut filter = Svf::new(RATE, 1000., 0.95).unwrap();
+        filter.next(0.5);
+        for _ in 0..RATE as usize / 2 {
+            filter.next(0.);
+        }
+        assert!(filter.next(0.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn cutoff_follows_key_and_envelope() {
+        // An octave over middle c moves the cutoff up an octave, where the low pass is 6db down.
+        let tracked = Svf::new(RATE, 1000., 0.).unwrap().with_key_tracking(72, 1.);
+        assert!((measured(tracked, 2000.) + 6.02).abs() < 0.1);
+
+        // An envelope holding at half its level opens the cutoff of a lower note back up.
+        let envelope = Envelope::gated(RATE, 0.01, 1., 0.01, 0.5, 0.01);
+        let opened = Svf::new(RATE, 1000., 0.)
+            .unwrap()
+            .with_key_tracking(48, 1.)
+            .with_envelope(envelope, 2.);
+        assert!((measured(opened, 1000.) + 6.02).abs() < 0.1);
+    }
+
+    #[test]
+    fn stable_under_audio_rate_modulation() {
+        let mut filter = Svf::new(RATE, 1000., 1.2).unwrap();
+        for i in 0..RATE as usize {
+            let phase = 2. * PI * (i as f32 / RATE);
+            filter.set_parameter(0, 7000. + 6900. * (500. * phase).sin());
+            filter.set_parameter(1, 0.6 + 0.6 * (300. * phase).sin());
+            let outputs = filter.tick((55. * phase).sin().signum());
+            for output in [outputs.low, outputs.band, outputs.high] {
+                assert!(output.is_finite() && output.abs() < 10., "{}", output);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(Svf::new(RATE, 0., 0.5).is_err());
+        assert!(Svf::new(RATE, 1000., 1.5).is_err());
+    }
+}
@@ -1,7 +1,12 @@
+use crate::engine::Levels;
 use crate::fft::RealFft;
+use crate::meter::{decibels, Reading};
+use crate::mixer::{BusId, MixAction, Target};
 use std::error::Error;
 use std::io::{stdout, Bytes, Read, Stdout, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
 use termion::{
     async_stdin,
     raw::{IntoRawMode, RawTerminal},
@@ -9,14 +14,18 @@ use termion::{
 };
 use tui::{
     backend::{Backend, TermionBackend},
-    layout::{Alignment, Constraint, Layout, Rect},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     symbols,
     text::Span,
-    widgets::{Axis, Block, Borders, Chart, Dataset, GraphType, Paragraph, Widget, Wrap},
+    widgets::{
+        Axis, Block, Borders, Chart, Dataset, GraphType, LineGauge, Paragraph, Widget, Wrap,
+    },
     Frame, Terminal,
 };
+use variant_count::VariantCount;
 
+#[derive(Debug, Clone, Copy, VariantCount)]
 pub enum Note {
     A,
     B,
@@ -24,8 +33,46 @@ pub enum Note {
     D,
 }
 
+/// The lowest level shown on the meters, in decibels.
+const METER_FLOOR: f32 = -60.;
+
+/// The velocity of notes played on lower case keys. Upper case keys play at full velocity.
+const SOFT_VELOCITY: f32 = 0.5;
+
+/// How much each press changes the level of the held notes.
+const LEVEL_STEP: f32 = 0.1;
+
+/// The furthest the held notes can be bent either way, in semitones.
+const MAX_BEND: f32 = 12.;
+
+/// The most feedback the echo can be turned up to before it would ring on for too long.
+const MAX_FEEDBACK: f32 = 0.9;
+
+/// The buses and inserts the interface controls: the reverb bus held notes are sent to, and the
+/// slot of the echo on the master along with the feedback it starts at.
+pub struct Mix {
+    pub reverb: BusId,
+    pub echo: usize,
+    pub feedback: f32,
+}
+
 pub enum Command {
-    Start(Note),
+    // Start a note with a velocity from 0 to 1.
+    Start(Note, f32),
+    // Release the voice started by the last Start for this note.
+    Stop(Note),
+    Wavetable,
+    Noise,
+    Additive,
+    Pulse,
+    Pluck(f32),
+    Fm,
+    // Set the gain of the held notes, from 0 to 1.
+    Level(f32),
+    // Bend the held notes by a number of semitones.
+    Bend(f32),
+    // Change a bus or an insert.
+    Mix(MixAction),
 }
 
 pub enum LoopState {
@@ -42,6 +89,27 @@ pub struct Ui {
     stdin: Bytes<AsyncReader>,
     fft_buffer: RealFft<f64>,
     commander: Sender<Command>,
+
+    // The number of samples the master stage has had to clip.
+    clips: Arc<AtomicU64>,
+
+    // Which notes are currently held. The terminal does not report key releases so each note key
+    // toggles its note on and off.
+    held: [bool; Note::VARIANT_COUNT],
+
+    // The gain of the held notes and how far they are bent, in semitones.
+    level: f32,
+    bend: f32,
+
+    // The latest meter readings from the audio thread.
+    levels: Option<Levels>,
+
+    // The buses and inserts the keys control, the level of the reverb bus and whether it is
+    // muted or soloed.
+    mix: Mix,
+    reverb: f32,
+    muted: bool,
+    soloed: bool,
 }
 
 impl Ui {
@@ -50,6 +118,8 @@ impl Ui {
         seconds_to_record: usize,
         sample_rate: usize,
         commander: Sender<Command>,
+        clips: Arc<AtomicU64>,
+        mix: Mix,
     ) -> Result<Self, Box<dyn Error>> {
         let mut stdout = stdout().into_raw_mode()?;
         write!(stdout, "{}", termion::clear::All).unwrap();
@@ -68,9 +138,80 @@ impl Ui {
             stdin,
             fft_buffer: RealFft::new(65536, sample_rate as f64)?,
             commander,
+            clips,
+            held: [false; Note::VARIANT_COUNT],
+            level: 1.,
+            bend: 0.,
+            levels: None,
+            mix,
+            reverb: 1.,
+            muted: false,
+            soloed: false,
         })
     }
 
+    /// Start a note if it is not held, otherwise release it.
+    fn toggle(&mut self, note: Note, velocity: f32) -> Result<(), Box<dyn Error>> {
+        let held = &mut self.held[note as usize];
+        *held = !*held;
+        if *held {
+            self.commander.send(Command::Start(note, velocity))?;
+        } else {
+            self.commander.send(Command::Stop(note))?;
+        }
+        Ok(())
+    }
+
+    /// Change the level of the held notes, keeping it between silence and full level.
+    fn change_level(&mut self, change: f32) -> Result<(), Box<dyn Error>> {
+        self.level = (self.level + change).clamp(0., 1.);
+        self.commander.send(Command::Level(self.level))?;
+        Ok(())
+    }
+
+    /// Bend the held notes further, up to an octave either way.
+    fn change_bend(&mut self, change: f32) -> Result<(), Box<dyn Error>> {
+        self.bend = (self.bend + change).clamp(-MAX_BEND, MAX_BEND);
+        self.commander.send(Command::Bend(self.bend))?;
+        Ok(())
+    }
+
+    /// Change the level the reverb bus is mixed in at, keeping it between silence and full level.
+    fn change_reverb(&mut self, change: f32) -> Result<(), Box<dyn Error>> {
+        self.reverb = (self.reverb + change).clamp(0., 1.);
+        let action = MixAction::BusGain(self.mix.reverb, self.reverb);
+        self.commander.send(Command::Mix(action))?;
+        Ok(())
+    }
+
+    /// Mute the reverb bus if it is playing, otherwise unmute it.
+    fn toggle_mute(&mut self) -> Result<(), Box<dyn Error>> {
+        self.muted = !self.muted;
+        let action = MixAction::Mute(self.mix.reverb, self.muted);
+        self.commander.send(Command::Mix(action))?;
+        Ok(())
+    }
+
+    /// Solo the reverb bus, silencing the dry voices, or go back to hearing everything.
+    fn toggle_solo(&mut self) -> Result<(), Box<dyn Error>> {
+        self.soloed = !self.soloed;
+        let action = MixAction::Solo(self.mix.reverb, self.soloed);
+        self.commander.send(Command::Mix(action))?;
+        Ok(())
+    }
+
+    /// Change the feedback of the echo on the master, which is its second parameter.
+    fn change_feedback(&mut self, change: f32) -> Result<(), Box<dyn Error>> {
+        self.mix.feedback = (self.mix.feedback + change).clamp(0., MAX_FEEDBACK);
+        let action = MixAction::Insert(Target::Master, self.mix.echo, 1, self.mix.feedback);
+        self.commander.send(Command::Mix(action))?;
+        Ok(())
+    }
+
+    pub fn set_levels(&mut self, levels: Levels) {
+        self.levels = Some(levels);
+    }
+
     pub fn add_sample(&mut self, sample: f32) {
         let capacity = self.samples.capacity();
         self.samples[self.total_samples % capacity] = (
@@ -132,16 +273,79 @@ impl Ui {
                     }
                 }
                 Ok(b'a') => {
-                    self.commander.send(Command::Start(Note::A))?;
+                    self.toggle(Note::A, SOFT_VELOCITY)?;
+                }
+                Ok(b'A') => {
+                    self.toggle(Note::A, 1.)?;
                 }
                 Ok(b'b') => {
-                    self.commander.send(Command::Start(Note::B))?;
+                    self.toggle(Note::B, SOFT_VELOCITY)?;
+                }
+                Ok(b'B') => {
+                    self.toggle(Note::B, 1.)?;
                 }
                 Ok(b'c') => {
-                    self.commander.send(Command::Start(Note::C))?;
+                    self.toggle(Note::C, SOFT_VELOCITY)?;
+                }
+                Ok(b'C') => {
+                    self.toggle(Note::C, 1.)?;
                 }
                 Ok(b'd') => {
-                    self.commander.send(Command::Start(Note::D))?;
+                    self.toggle(Note::D, SOFT_VELOCITY)?;
+                }
+                Ok(b'D') => {
+                    self.toggle(Note::D, 1.)?;
+                }
+                Ok(b't') => {
+                    self.commander.send(Command::Wavetable)?;
+                }
+                Ok(b'n') => {
+                    self.commander.send(Command::Noise)?;
+                }
+                Ok(b'h') => {
+                    self.commander.send(Command::Additive)?;
+                }
+                Ok(b'w') => {
+                    self.commander.send(Command::Pulse)?;
+                }
+                Ok(b'p') => {
+                    self.commander.send(Command::Pluck(SOFT_VELOCITY))?;
+                }
+                Ok(b'P') => {
+                    self.commander.send(Command::Pluck(1.))?;
+                }
+                Ok(b'f') => {
+                    self.commander.send(Command::Fm)?;
+                }
+                Ok(b'[') => {
+                    self.change_level(-LEVEL_STEP)?;
+                }
+                Ok(b']') => {
+                    self.change_level(LEVEL_STEP)?;
+                }
+                Ok(b',') => {
+                    self.change_bend(-1.)?;
+                }
+                Ok(b'.') => {
+                    self.change_bend(1.)?;
+                }
+                Ok(b'{') => {
+                    self.change_reverb(-LEVEL_STEP)?;
+                }
+                Ok(b'}') => {
+                    self.change_reverb(LEVEL_STEP)?;
+                }
+                Ok(b'm') => {
+                    self.toggle_mute()?;
+                }
+                Ok(b's') => {
+                    self.toggle_solo()?;
+                }
+                Ok(b'<') => {
+                    self.change_feedback(-LEVEL_STEP)?;
+                }
+                Ok(b'>') => {
+                    self.change_feedback(LEVEL_STEP)?;
                 }
                 Ok(b'q') => return Ok(LoopState::Exit),
                 _ => {}
@@ -213,6 +417,72 @@ impl Ui {
             )
     }
 
+    /// A one line meter of a level in decibels.
+    fn gauge<'a>(label: &str, level: f32) -> LineGauge<'a> {
+        let ratio = ((level - METER_FLOOR) / -METER_FLOOR).clamp(0., 1.);
+        let color = if level > -1. {
+            Color::Red
+        } else if level > -12. {
+            Color::Yellow
+        } else {
+            Color::Green
+        };
+        LineGauge::default()
+            .gauge_style(Style::default().fg(color))
+            .line_set(symbols::line::THICK)
+            .ratio(ratio as f64)
+            .label(format!("{:<9}{:>6.1}", label, level))
+    }
+
+    /// The peak, rms and true peak of the mix and its loudness, followed by the peak of each
+    /// voice as far as there is room.
+    fn draw_meters<T: Backend>(f: &mut Frame<'_, T>, levels: &Levels, area: Rect) {
+        let block = Block::default()
+            .title(Span::styled(
+                "meters (db)",
+                Style::default()
+                    .fg(Color::Cyan)
+                    .add_modifier(Modifier::BOLD),
+            ))
+            .borders(Borders::ALL);
+        let inner = block.inner(area);
+        f.render_widget(block, area);
+
+        let rows = Layout::default()
+            .constraints(vec![Constraint::Length(1); inner.height as usize])
+            .split(inner);
+        let mix = &levels.mix;
+        let gauges = [
+            ("peak", mix.peak),
+            ("rms", mix.rms),
+            ("true peak", mix.true_peak),
+        ]
+        .into_iter()
+        .map(|(label, level)| Self::gauge(label, decibels(level)));
+        let voices = levels.voices[..levels.voice_count]
+            .iter()
+            .enumerate()
+            .map(|(i, voice)| Self::gauge(&format!("voice {}", i + 1), decibels(voice.peak)));
+
+        let loudness = |reading: &Reading| {
+            format!(
+                "M {:.1} S {:.1} I {:.1} LUFS",
+                reading.momentary, reading.short_term, reading.integrated
+            )
+        };
+
+        let mut rows = rows.into_iter();
+        for (gauge, row) in gauges.zip(rows.by_ref()) {
+            f.render_widget(gauge, row);
+        }
+        if let Some(row) = rows.next() {
+            f.render_widget(Paragraph::new(loudness(mix)), row);
+        }
+        for (gauge, row) in voices.zip(rows) {
+            f.render_widget(gauge, row);
+        }
+    }
+
     pub fn draw(&mut self) -> Result<(), Box<dyn Error>> {
         let (first_time, last_time, frame) = self.frame(self.sample_window);
         let (first_freq, last_freq, fft_frame) = self.fft_frame(self.sample_window)?;
@@ -258,17 +528,41 @@ impl Ui {
                 .margin(1)
                 .split(f.size());
 
+            let reverb = match (self.muted, self.soloed) {
+                (true, _) => " muted",
+                (false, true) => " soloed",
+                (false, false) => "",
+            };
             let intro_text = Some(
-                Paragraph::new(format!("{} samples visualized", self.sample_window))
-                    .block(Block::default().borders(Borders::ALL))
-                    .style(Style::default().fg(Color::White).bg(Color::Black))
-                    .alignment(Alignment::Left)
-                    .wrap(Wrap { trim: true }),
+                Paragraph::new(format!(
+                    "{} samples visualized, {} samples clipped, held notes at {:.1} bent {:+} \
+                     semitones, reverb at {:.1}{}, echo feedback {:.1}",
+                    self.sample_window,
+                    self.clips.load(Ordering::Relaxed),
+                    self.level,
+                    self.bend,
+                    self.reverb,
+                    reverb,
+                    self.mix.feedback
+                ))
+                .block(Block::default().borders(Borders::ALL))
+                .style(Style::default().fg(Color::White).bg(Color::Black))
+                .alignment(Alignment::Left)
+                .wrap(Wrap { trim: true }),
             );
 
+            // The meters sit to the right of the waveform.
+            let waveform = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Min(0), Constraint::Length(34)].as_ref())
+                .split(chunks[2]);
+
             Self::draw_widget(f, intro_text, chunks[0]);
             Self::draw_widget(f, fft_widget, chunks[1]);
-            Self::draw_widget(f, freq_widget, chunks[2]);
+            Self::draw_widget(f, freq_widget, waveform[0]);
+            if let Some(levels) = &self.levels {
+                Self::draw_meters(f, levels, waveform[1]);
+            }
         })?;
         Ok(())
     }
@@ -0,0 +1,198 @@
+//! Velocity sensitivity. A note on carries a velocity between 0 (softest) and 1 (hardest) which is
+//! passed through a curve and then scales the level, attack time and brightness of the voice by
+//! configurable amounts, so the same patch can be played quietly and gently or loudly and sharply.
+
+use std::str::FromStr;
+
+/// How a velocity maps onto the response it produces, both running from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VelocityCurve {
+    // The response is the velocity.
+    Linear,
+
+    // Soft playing gives more response than it would on the linear curve.
+    Soft,
+
+    // Notes have to be played harder to reach the same response as the linear curve.
+    Hard,
+
+    // The velocity raised to a power. Below one is softer and above one is harder.
+    Power(f32),
+
+    // Every note gets the same response whatever its velocity.
+    Fixed(f32),
+}
+
+/// Curves are named linear, soft or hard, or `power:<exponent>` and `fixed:<response>` for the
+/// curves that take a value.
+impl FromStr for VelocityCurve {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let value = |value: &str| {
+            value
+                .parse::<f32>()
+                .map_err(|_| format!("velocity curve {} needs a number after the colon", name))
+        };
+        match name.split_once(':') {
+            None if name == "linear" => Ok(VelocityCurve::Linear),
+            None if name == "soft" => Ok(VelocityCurve::Soft),
+            None if name == "hard" => Ok(VelocityCurve::Hard),
+            Some(("power", power)) => Ok(VelocityCurve::Power(value(power)?)),
+            Some(("fixed", response)) => Ok(VelocityCurve::Fixed(value(response)?)),
+            _ => Err(format!(
+                "unknown velocity curve {}, expected linear, soft, hard, power:<exponent> or \
+                 fixed:<response>",
+                name
+            )),
+        }
+    }
+}
+
+impl VelocityCurve {
+    pub fn apply(&self, velocity: f32) -> f32 {
+        let velocity = velocity.clamp(0., 1.);
+        match self {
+            VelocityCurve::Linear => velocity,
+            VelocityCurve::Soft => velocity.sqrt(),
+            VelocityCurve::Hard => velocity * velocity,
+            VelocityCurve::Power(power) => velocity.powf(*power),
+            VelocityCurve::Fixed(response) => *response,
+        }
+    }
+}
+
+/// How much a voice responds to velocity. Each depth runs from 0 (velocity has no effect) to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VelocityResponse {
+    curve: VelocityCurve,
+
+    // The fraction of the level taken away from the softest notes.
+    level: f32,
+
+    // How much longer the attack of the softest notes is, as a multiple of the attack time.
+    attack: f32,
+
+    // The fraction of the brightness taken away from the softest notes, if velocity should change
+    // the tone at all.
+    brightness: Option<f32>,
+}
+
+impl VelocityResponse {
+    /// A response where velocity scales the level alone, all the way down to silence.
+    pub const fn new(curve: VelocityCurve) -> Self {
+        VelocityResponse {
+            curve,
+            level: 1.,
+            attack: 0.,
+            brightness: None,
+        }
+    }
+
+    pub const fn with_curve(self, curve: VelocityCurve) -> Self {
+        VelocityResponse { curve, ..self }
+    }
+
+    pub const fn with_level(self, depth: f32) -> Self {
+        VelocityResponse {
+            level: depth,
+            ..self
+        }
+    }
+
+    pub const fn with_attack(self, depth: f32) -> Self {
+        VelocityResponse {
+            attack: depth,
+            ..self
+        }
+    }
+
+    pub const fn with_brightness(self, depth: f32) -> Self {
+        VelocityResponse {
+            brightness: Some(depth),
+            ..self
+        }
+    }
+
+    /// The gain to apply to the peak and sustain levels of a note.
+    pub fn gain(&self, velocity: f32) -> f32 {
+        1. - self.level * (1. - self.curve.apply(velocity))
+    }
+
+    /// The attack time of a note, stretched for softer notes.
+    pub fn attack(&self, velocity: f32, attack: f32) -> f32 {
+        attack * (1. + self.attack * (1. - self.curve.apply(velocity)))
+    }
+
+    /// The brightness of a note, darkened for softer notes when velocity controls brightness.
+    pub fn brightness(&self, velocity: f32, brightness: f32) -> f32 {
+        match self.brightness {
+            Some(depth) => brightness * (1. - depth * (1. - self.curve.apply(velocity))),
+            None => brightness,
+        }
+    }
+}
+
+#[cfg(test)]
+mod velocity_tests {
+    use super::{VelocityCurve, VelocityResponse};
+
+    #[test]
+    fn curves_by_name() {
+        assert_eq!("hard".parse::<VelocityCurve>(), Ok(VelocityCurve::Hard));
+        assert_eq!(
+            "power:2.5".parse::<VelocityCurve>(),
+            Ok(VelocityCurve::Power(2.5))
+        );
+        assert_eq!(
+            "fixed:0.8".parse::<VelocityCurve>(),
+            Ok(VelocityCurve::Fixed(0.8))
+        );
+        assert!("power".parse::<VelocityCurve>().is_err());
+        assert!("fixed:loud".parse::<VelocityCurve>().is_err());
+        assert!("gentle".parse::<VelocityCurve>().is_err());
+    }
+
+    #[test]
+    fn curves() {
+        assert_eq!(VelocityCurve::Linear.apply(0.25), 0.25);
+        assert_eq!(VelocityCurve::Soft.apply(0.25), 0.5);
+        assert_eq!(VelocityCurve::Hard.apply(0.5), 0.25);
+        assert_eq!(VelocityCurve::Power(3.).apply(0.5), 0.125);
+        assert_eq!(VelocityCurve::Fixed(0.8).apply(0.1), 0.8);
+
+        for curve in [VelocityCurve::Soft, VelocityCurve::Hard] {
+            assert_eq!(curve.apply(0.), 0.);
+            assert_eq!(curve.apply(1.), 1.);
+            assert_eq!(curve.apply(2.), 1.);
+        }
+    }
+
+    #[test]
+    fn level_depth() {
+        let full = VelocityResponse::new(VelocityCurve::Linear);
+        assert_eq!(full.gain(0.), 0.);
+        assert_eq!(full.gain(0.5), 0.5);
+
+        let partial = full.with_level(0.5);
+        assert_eq!(partial.gain(0.), 0.5);
+        assert_eq!(partial.gain(1.), 1.);
+    }
+
+    #[test]
+    fn soft_notes_attack_slower_and_darker() {
+        let response = VelocityResponse::new(VelocityCurve::Linear)
+            .with_attack(1.)
+            .with_brightness(0.5);
+
+        assert_eq!(response.attack(1., 0.1), 0.1);
+        assert_eq!(response.attack(0., 0.1), 0.2);
+        assert_eq!(response.brightness(1., 0.8), 0.8);
+        assert_eq!(response.brightness(0., 0.8), 0.4);
+
+        // Brightness is left alone unless asked for.
+        let level_only = VelocityResponse::new(VelocityCurve::Linear);
+        assert_eq!(level_only.brightness(0., 0.8), 0.8);
+        assert_eq!(level_only.attack(0., 0.1), 0.1);
+    }
+}
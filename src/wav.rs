@@ -0,0 +1,196 @@
+/**
+ * A minimal reader for RIFF WAVE files. Only the 'fmt ' and 'data' chunks are understood which is
+ * enough to load single-cycle waveforms and recorded samples. Integer PCM (8, 16, 24 and 32 bit)
+ * and IEEE float (32 and 64 bit) encodings are supported and decoded to f32 in [-1, 1].
+ */
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Decoded audio from a wav file. Samples are stored interleaved by channel.
+#[derive(Debug)]
+pub struct Wav {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub samples: Vec<f32>,
+}
+
+fn read_u16(data: &[u8], at: usize) -> Result<u16, Box<dyn Error>> {
+    let bytes = data.get(at..at + 2).ok_or("wav file is truncated")?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32, Box<dyn Error>> {
+    let bytes = data.get(at..at + 4).ok_or("wav file is truncated")?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Decode a single sample in the given format to an f32 in [-1, 1].
+fn decode(format: u16, bits: u16, bytes: &[u8]) -> Result<f32, Box<dyn Error>> {
+    match (format, bits) {
+        (FORMAT_PCM, 8) => Ok((bytes[0] as f32 - 128.) / 128.),
+        (FORMAT_PCM, 16) => Ok(i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.),
+        (FORMAT_PCM, 24) => {
+            // Place the 24 bits at the top of an i32 so the sign is extended.
+            let value = i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]);
+            Ok(value as f32 / 2147483648.)
+        }
+        (FORMAT_PCM, 32) => {
+            let value = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            Ok(value as f32 / 2147483648.)
+        }
+        (FORMAT_FLOAT, 32) => Ok(f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+        (FORMAT_FLOAT, 64) => {
+            let mut raw = [0; 8];
+            raw.copy_from_slice(&bytes[..8]);
+            Ok(f64::from_le_bytes(raw) as f32)
+        }
+        _ => Err(format!(
+            "unsupported wav encoding (format {}, {} bits)",
+            format, bits
+        )
+        .into()),
+    }
+}
+
+impl Wav {
+    /// Read and decode a wav file from disk.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    /// Decode a wav file that has already been read into memory.
+    pub fn parse(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if data.get(0..4) != Some(b"RIFF") || data.get(8..12) != Some(b"WAVE") {
+            return Err("not a RIFF WAVE file".into());
+        }
+
+        let mut format = None;
+        let mut samples = None;
+        let mut at = 12;
+
+        while at + 8 <= data.len() {
+            let id = &data[at..at + 4];
+            let size = read_u32(data, at + 4)? as usize;
+            let body = data
+                .get(at + 8..at + 8 + size)
+                .ok_or("wav chunk runs past the end of the file")?;
+
+            match id {
+                b"fmt " => {
+                    let mut encoding = read_u16(body, 0)?;
+                    let channels = read_u16(body, 2)?;
+                    let sample_rate = read_u32(body, 4)?;
+                    let bits = read_u16(body, 14)?;
+
+                    // The extensible format stores the real encoding at the start of the sub-format
+                    // guid.
+                    if encoding == FORMAT_EXTENSIBLE {
+                        encoding = read_u16(body, 24)?;
+                    }
+
+                    format = Some((encoding, channels, sample_rate, bits));
+                }
+                b"data" => samples = Some(body),
+                _ => {}
+            }
+
+            // Chunks are padded to an even number of bytes.
+            at += 8 + size + (size & 1);
+        }
+
+        let (encoding, channels, sample_rate, bits) = format.ok_or("wav file has no fmt chunk")?;
+        let samples = samples.ok_or("wav file has no data chunk")?;
+
+        if channels == 0 || bits == 0 || bits % 8 != 0 {
+            return Err("wav file has an invalid fmt chunk".into());
+        }
+
+        let width = bits as usize / 8;
+        let samples = samples
+            .chunks_exact(width)
+            .map(|bytes| decode(encoding, bits, bytes))
+            .collect::<Result<Vec<f32>, Box<dyn Error>>>()?;
+
+        Ok(Wav {
+            sample_rate,
+            channels,
+            samples,
+        })
+    }
+
+    /// Mix all channels down to a single channel by averaging them.
+    pub fn mono(&self) -> Vec<f32> {
+        self.samples
+            .chunks_exact(self.channels as usize)
+            .map(|frame| frame.iter().sum::<f32>() / self.channels as f32)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod wav_tests {
+    use super::Wav;
+
+    /// Build an in-memory wav file with a single fmt and data chunk.
+    fn wav_bytes(format: u16, channels: u16, bits: u16, data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVE");
+        bytes.extend_from_slice(b"fmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&format.to_le_bytes());
+        bytes.extend_from_slice(&channels.to_le_bytes());
+        bytes.extend_from_slice(&44100u32.to_le_bytes());
+        bytes.extend_from_slice(&(44100 * channels as u32 * bits as u32 / 8).to_le_bytes());
+        bytes.extend_from_slice(&(channels * bits / 8).to_le_bytes());
+        bytes.extend_from_slice(&bits.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn pcm16_stereo() {
+        let data: Vec<u8> = [0i16, 16384, -32768, 32767]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect();
+        let wav = Wav::parse(&wav_bytes(1, 2, 16, &data)).unwrap();
+        assert_eq!(wav.sample_rate, 44100);
+        assert_eq!(wav.channels, 2);
+        assert_eq!(wav.samples[0..3], [0., 0.5, -1.]);
+        assert_eq!(wav.mono().len(), 2);
+        assert_eq!(wav.mono()[0], 0.25);
+    }
+
+    #[test]
+    fn pcm24_sign_extends() {
+        let data = [0x00, 0x00, 0xC0, 0xFF, 0xFF, 0x7F];
+        let wav = Wav::parse(&wav_bytes(1, 1, 24, &data)).unwrap();
+        assert_eq!(wav.samples[0], -0.5);
+        assert!((wav.samples[1] - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn float32() {
+        let data: Vec<u8> = [0.25f32, -0.75]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect();
+        let wav = Wav::parse(&wav_bytes(3, 1, 32, &data)).unwrap();
+        assert_eq!(wav.samples, vec![0.25, -0.75]);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(Wav::parse(b"not a wav file at all").is_err());
+        assert!(Wav::parse(&wav_bytes(2, 1, 16, &[0, 0])).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+/**
+ * Band-limited single-cycle wavetables. Each table keeps one copy of its cycle per octave with the
+ * harmonics that would fold back above nyquist at that octave removed, built by transforming the
+ * cycle with do_fft, zeroing the high bins and transforming back.
+ */
+use crate::complex::Complex;
+use crate::fft::do_fft;
+use crate::wav::Wav;
+use std::error::Error;
+use std::path::Path;
+
+/// The number of points in every stored cycle. Must be a power of two for do_fft.
+pub const TABLE_SIZE: usize = 2048;
+
+#[derive(Debug)]
+pub struct Wavetable {
+    // levels[k] holds the cycle with every harmonic above (TABLE_SIZE / 2) >> k removed.
+    levels: Vec<Vec<f32>>,
+}
+
+/// Linearly resample a cycle of any length to TABLE_SIZE points, treating it as periodic.
+fn resample_cycle(cycle: &[f32]) -> Vec<f32> {
+    (0..TABLE_SIZE)
+        .map(|i| {
+            let position = i as f32 * cycle.len() as f32 / TABLE_SIZE as f32;
+            let index = position as usize;
+            let fraction = position - index as f32;
+            let current = cycle[index % cycle.len()];
+            let next = cycle[(index + 1) % cycle.len()];
+            current + (next - current) * fraction
+        })
+        .collect()
+}
+
+impl Wavetable {
+    /// Build a wavetable from one cycle of a waveform. The cycle can be any length and will be
+    /// resampled to TABLE_SIZE points.
+    pub fn from_cycle(cycle: &[f32]) -> Result<Self, Box<dyn Error>> {
+        if cycle.is_empty() {
+            return Err("a wavetable cycle needs at least one point".into());
+        }
+
+        let mut spectrum: Vec<Complex<f64>> = resample_cycle(cycle)
+            .iter()
+            .map(|x| Complex::real(*x as f64))
+            .collect();
+        do_fft(&mut spectrum, false)?;
+
+        let mut levels = Vec::new();
+        let mut limit = TABLE_SIZE / 2;
+
+        while limit >= 1 {
+            let mut level: Vec<Complex<f64>> = spectrum
+                .iter()
+                .enumerate()
+                .map(|(bin, value)| {
+                    // Bins above the half way point hold the negative frequencies.
+                    let harmonic = usize::min(bin, TABLE_SIZE - bin);
+                    if harmonic > limit {
+                        Complex::real(0.)
+                    } else {
+                        *value
+                    }
+                })
+                .collect();
+            do_fft(&mut level, true)?;
+            levels.push(level.iter().map(|x| x.real as f32).collect());
+            limit /= 2;
+        }
+
+        Ok(Wavetable { levels })
+    }
+
+    /// Build a wavetable from the amplitudes of a series of sine harmonics, starting with the
+    /// fundamental.
+    pub fn from_harmonics(amplitudes: &[f32]) -> Result<Self, Box<dyn Error>> {
+        let cycle: Vec<f32> = (0..TABLE_SIZE)
+            .map(|i| {
+                let phase = i as f32 / TABLE_SIZE as f32;
+                amplitudes
+                    .iter()
+                    .enumerate()
+                    .map(|(h, amplitude)| {
+                        amplitude * (2. * std::f32::consts::PI * (h + 1) as f32 * phase).sin()
+                    })
+                    .sum()
+            })
+            .collect();
+        Self::from_cycle(&cycle)
+    }
+
+    /// Load a wavetable from a wav file containing exactly one cycle. Multi-channel files are
+    /// mixed down to mono.
+    pub fn from_wav<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::from_cycle(&Wav::load(path)?.mono())
+    }
+
+    /// Select the copy of the cycle with as many harmonics as possible that all stay below nyquist
+    /// when played at this frequency.
+    pub fn level(&self, frequency: f32, rate: f32) -> &[f32] {
+        let max_harmonic = rate / (2. * frequency);
+        let mut limit = TABLE_SIZE / 2;
+
+        for level in &self.levels {
+            if limit as f32 <= max_harmonic {
+                return level;
+            }
+            limit /= 2;
+        }
+
+        self.levels.last().unwrap()
+    }
+
+    /// Sample the band-limited cycle at a position in [0, 1) with linear interpolation.
+    pub fn sample(&self, phase: f32, frequency: f32, rate: f32) -> f32 {
+        let level = self.level(frequency, rate);
+        let position = phase * TABLE_SIZE as f32;
+        let index = position as usize % TABLE_SIZE;
+        let fraction = position - position.floor();
+        let current = level[index];
+        let next = level[(index + 1) % TABLE_SIZE];
+        current + (next - current) * fraction
+    }
+}
+
+#[cfg(test)]
+mod wavetable_tests {
+    use super::{Wavetable, TABLE_SIZE};
+    use crate::complex::Complex;
+    use crate::fft::do_fft;
+
+    fn sawtooth_cycle() -> Vec<f32> {
+        (0..TABLE_SIZE)
+            .map(|i| i as f32 * 2. / TABLE_SIZE as f32 - 1.)
+            .collect()
+    }
+
+    #[test]
+    fn harmonics_build_a_sine() {
+        let table = Wavetable::from_harmonics(&[1.]).unwrap();
+        for i in 0..TABLE_SIZE {
+            let phase = i as f32 / TABLE_SIZE as f32;
+            let expected = (2. * std::f32::consts::PI * phase).sin();
+            assert!((table.sample(phase, 440., 44100.) - expected).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn levels_remove_high_harmonics() {
+        let table = Wavetable::from_cycle(&sawtooth_cycle()).unwrap();
+
+        // At 5khz only four harmonics of the sawtooth fit below nyquist.
+        let level = table.level(5000., 44100.);
+        let mut spectrum: Vec<Complex<f64>> =
+            level.iter().map(|x| Complex::real(*x as f64)).collect();
+        do_fft(&mut spectrum, false).unwrap();
+
+        for (bin, value) in spectrum.iter().enumerate().take(TABLE_SIZE / 2).skip(1) {
+            let magnitude = value.magnitude() / TABLE_SIZE as f64;
+            if bin as f32 * 5000. > 22050. {
+                assert!(magnitude < 1e-4, "harmonic {} is {}", bin, magnitude);
+            }
+        }
+
+        // Low notes keep the full bandwidth.
+        assert_eq!(table.level(10., 44100.).as_ptr(), table.levels[0].as_ptr());
+    }
+
+    #[test]
+    fn resamples_short_cycles() {
+        let table = Wavetable::from_cycle(&[-1., 1.]).unwrap();
+        assert_eq!(table.levels[0].len(), TABLE_SIZE);
+    }
+
+    #[test]
+    fn empty_cycle_is_an_error() {
+        assert!(Wavetable::from_cycle(&[]).is_err());
+    }
+}